@@ -54,7 +54,7 @@ where
 {
     use std::io;
 
-    let failed_db = Err(io::Error::new(io::ErrorKind::Other, ""));
+    let failed_db = Err(io::Error::other(""));
     failed_db?; // returns a 500 automatically.
 
     resp_wtr.send().await
@@ -68,7 +68,7 @@ where
 
     // Manually create a 400
     // This will work even without anyhow integration.
-    failed_db.ok_or_else(|| Glitch::bad_request())?;
+    failed_db.ok_or_else(Glitch::bad_request)?;
 
     resp_wtr.send().await
 }
@@ -19,8 +19,9 @@ fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
     let opts = ServerOpts {
-        timeout: Some(std::time::Duration::from_secs(60)),
+        header_read_timeout: Some(std::time::Duration::from_secs(10)),
         verbose_glitch: true,
+        ..ServerOpts::default()
     };
 
     let router = Router::build()
@@ -68,7 +69,7 @@ where
     use std::io;
 
     let failed_db: std::result::Result<(), _> =
-        Err(io::Error::new(io::ErrorKind::Other, "The database crashed"));
+        Err(io::Error::other("The database crashed"));
     failed_db?;
 
     resp_wtr.send().await
@@ -84,7 +85,7 @@ where
     use std::io;
 
     let failed_db: std::result::Result<(), _> =
-        Err(io::Error::new(io::ErrorKind::Other, "The database crashed"));
+        Err(io::Error::other("The database crashed"));
     failed_db.glitch_ctx(S_500, "looking for user")?;
 
     resp_wtr.send().await
@@ -98,7 +99,7 @@ where
 
     // Manually create a 400
     // This will work even without anyhow integration.
-    failed_db.ok_or_else(|| Glitch::bad_request())?;
+    failed_db.ok_or_else(Glitch::bad_request)?;
 
     resp_wtr.send().await
 }
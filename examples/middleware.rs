@@ -73,7 +73,7 @@ where
 {
     //smol::Timer::after(std::time::Duration::from_secs(5)).await;
 
-    let mut resp_body = format!("Hello, ");
+    let mut resp_body = "Hello, ".to_string();
 
     // add params to body string
     if let Some(params) = req.params() {
@@ -0,0 +1,75 @@
+use async_dup::Arc;
+use futures_lite::StreamExt;
+use http::Method;
+use smol::Async;
+use std::io;
+use std::net::TcpListener;
+use std::path::Path;
+use tophat::server::{
+    accept,
+    fs::{BoxFuture, DirEntry, FileSystem, Metadata, ServeDir},
+    router::Router,
+};
+
+fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+
+    // serves this repo's files, e.g. http://localhost:9999/files/src/lib.rs
+    let router = Router::build()
+        .at(Method::GET, "/files/*path", ServeDir::new(".", SmolFs).list_directories(true))
+        .at(Method::HEAD, "/files/*path", ServeDir::new(".", SmolFs))
+        .finish();
+
+    let listener = Async::<TcpListener>::bind(([127,0,0,1],9999))?;
+
+    smol::block_on(async {
+        loop {
+            let router = router.clone();
+
+            let (stream, _) = listener.accept().await?;
+            let stream = Arc::new(stream);
+
+            let task = smol::spawn(async move {
+                let serve = accept(stream, |req, resp_wtr| async {
+                    let res = router.route(req, resp_wtr).await;
+                    res
+                })
+                .await;
+
+                if let Err(err) = serve {
+                    eprintln!("Error: {}", err);
+                }
+            });
+
+            task.detach();
+        }
+    })
+}
+
+struct SmolFs;
+
+impl FileSystem for SmolFs {
+    type File = smol::fs::File;
+
+    fn open<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Self::File>> {
+        Box::pin(smol::fs::File::open(path))
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Metadata>> {
+        Box::pin(async move { smol::fs::metadata(path).await.map(Metadata::from) })
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            let mut entries = Vec::new();
+            let mut dir = smol::fs::read_dir(path).await?;
+            while let Some(entry) = dir.try_next().await? {
+                entries.push(DirEntry {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    is_dir: entry.file_type().await?.is_dir(),
+                });
+            }
+            Ok(entries)
+        })
+    }
+}
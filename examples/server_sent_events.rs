@@ -1,26 +1,25 @@
-use async_dup::{Arc, Mutex};
-use futures::Stream;
+use async_dup::Arc;
 use smol::Async;
 use std::net::TcpListener;
-use std::pin::Pin;
-use std::task::{Context, Poll};
 use std::time::Duration;
-use tophat::server::accept;
+use tophat::server::{
+    accept,
+    broadcast::Hub,
+    sse::{self, Event},
+};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
 
-    let ping_machine = Arc::new(Mutex::new(PingMachine {
-        broadcasters: Vec::new(),
-    }));
+    let hub = Hub::new();
 
     let listener = Async::<TcpListener>::bind(([127,0,0,1],9999))?;
 
     let ping_task = smol::spawn({
-        let ping_machine = ping_machine.clone();
+        let hub = hub.clone();
         async move {
             loop {
-                ping_machine.lock().ping().await;
+                hub.publish("pings", Event::new("ping")).await;
                 smol::Timer::after(Duration::from_secs(1)).await;
             }
         }
@@ -32,14 +31,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let (stream, _) = listener.accept().await?;
             let stream = Arc::new(stream);
 
-            let ping_machine = ping_machine.clone();
+            let hub = hub.clone();
 
             let task = smol::spawn(async move {
-                let serve = accept(stream, |_req, mut resp_wtr| async {
-                    let client = ping_machine.lock().add_client();
-                    resp_wtr.set_sse(client);
+                let serve = accept(stream, |req, mut resp_wtr| {
+                    // replays the pings missed since `Last-Event-ID` on reconnect
+                    resp_wtr.set_sse(sse::subscribe(&hub, "pings", &req));
 
-                    resp_wtr.send().await
+                    async move { resp_wtr.send().await }
                 })
                 .await;
 
@@ -52,37 +51,3 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     })
 }
-
-struct PingMachine {
-    broadcasters: Vec<async_channel::Sender<String>>,
-}
-
-impl PingMachine {
-    async fn ping(&self) {
-        for tx in &self.broadcasters {
-            let _ = tx.send("data: ping\n\n".to_owned()).await;
-        }
-    }
-
-    fn add_client(&mut self) -> Client {
-        let (tx, rx) = async_channel::bounded(10);
-
-        self.broadcasters.push(tx);
-
-        Client(rx)
-    }
-}
-
-struct Client(async_channel::Receiver<String>);
-
-impl Stream for Client {
-    type Item = Result<String, std::io::Error>;
-
-    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match Pin::new(&mut self.0).poll_next(cx) {
-            Poll::Ready(Some(v)) => Poll::Ready(Some(Ok(v))),
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Pending => Poll::Pending,
-        }
-    }
-}
@@ -1,10 +1,14 @@
 use async_dup::{Arc, Mutex};
 use std::io;
+use std::path::Path;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::net::{self, TcpStream};
-use tokio_util::compat::{Compat, TokioAsyncWriteCompatExt};
-use tophat::server::accept;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tophat::server::{
+    accept,
+    fs::{BoxFuture, DirEntry, FileSystem, Metadata, ServeDir},
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -12,16 +16,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let listener = net::TcpListener::bind("127.0.0.1:9999").await?;
 
+    // serves files from the current directory under /files/
+    let serve_dir = std::sync::Arc::new(ServeDir::new(".", TokioFs).list_directories(true));
+
     loop {
         let (stream, _) = listener.accept().await?;
         let stream = WrapStream::new(stream);
+        let serve_dir = serve_dir.clone();
 
         tokio::spawn(async move {
-            let serve = accept(stream, |_req, mut resp_wtr| async {
-                let resp_body = "Hello, World!";
-                resp_wtr.set_body(resp_body.into());
+            let serve = accept(stream, |req, mut resp_wtr| {
+                let serve_dir = serve_dir.clone();
+                async move {
+                    if let Some(path) = req.uri().path().strip_prefix("/files/") {
+                        let path = path.to_owned();
+                        return serve_dir.serve(&path, &req, resp_wtr).await;
+                    }
+
+                    let resp_body = "Hello, World!";
+                    resp_wtr.set_body(resp_body.into());
 
-                resp_wtr.send().await
+                    resp_wtr.send().await
+                }
             })
             .await;
 
@@ -32,6 +48,34 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 }
 
+struct TokioFs;
+
+impl FileSystem for TokioFs {
+    type File = Compat<tokio::fs::File>;
+
+    fn open<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Self::File>> {
+        Box::pin(async move { Ok(tokio::fs::File::open(path).await?.compat()) })
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Metadata>> {
+        Box::pin(async move { tokio::fs::metadata(path).await.map(Metadata::from) })
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            let mut entries = Vec::new();
+            let mut dir = tokio::fs::read_dir(path).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                entries.push(DirEntry {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    is_dir: entry.file_type().await?.is_dir(),
+                });
+            }
+            Ok(entries)
+        })
+    }
+}
+
 // TODO I'm not sure this is the best way to do this. Suggestions for simplifying definitely
 // welcome. When AsyncRead and AsyncWrite standardized, this shouldn't be necessary.
 #[derive(Clone)]
@@ -0,0 +1,66 @@
+use async_dup::Arc;
+use futures_util::io::{AsyncRead, AsyncWrite};
+use http::Method;
+use smol::Async;
+use std::net::TcpListener;
+use tophat::{
+    server::{
+        accept,
+        glitch::Result,
+        router::Router,
+        websocket::{self, Message},
+        ResponseWriter, ResponseWritten,
+    },
+    Request,
+};
+
+fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt::init();
+
+    let router = Router::build()
+        .at(Method::GET, "/echo", echo)
+        .finish();
+
+    let listener = Async::<TcpListener>::bind(([127,0,0,1],9999))?;
+
+    smol::block_on(async {
+        loop {
+            let router = router.clone();
+
+            let (stream, _) = listener.accept().await?;
+            let stream = Arc::new(stream);
+
+            let task = smol::spawn(async move {
+                let serve = accept(stream, |req, resp_wtr| async {
+                    let res = router.route(req, resp_wtr).await;
+                    res
+                })
+                .await;
+
+                if let Err(err) = serve {
+                    eprintln!("Error: {}", err);
+                }
+            });
+
+            task.detach();
+        }
+    })
+}
+
+async fn echo<W>(req: Request, resp_wtr: ResponseWriter<W>) -> Result<ResponseWritten>
+where
+    W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    let mut ws = websocket::upgrade(&req, resp_wtr).await?;
+
+    while let Some(msg) = ws.recv().await {
+        match msg? {
+            Message::Text(text) => ws.send(Message::Text(text)).await?,
+            Message::Binary(bytes) => ws.send(Message::Binary(bytes)).await?,
+            // pings and closes are answered automatically
+            _ => (),
+        }
+    }
+
+    Ok(ws.response_written())
+}
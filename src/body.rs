@@ -1,9 +1,10 @@
-use futures_lite::{AsyncBufRead, AsyncRead, AsyncReadExt};
+use futures_lite::{AsyncBufRead, AsyncRead, AsyncReadExt, StreamExt};
+use http::HeaderName;
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use crate::trailers::{Trailers, TrailersSender};
+use crate::trailers::{ReceivedTrailers, Trailers, TrailersSender};
 use crate::util::{empty, Cursor};
 use self::error::BodyError;
 
@@ -17,6 +18,7 @@ pin_project_lite::pin_project! {
         pub(crate) length: Option<usize>,
         trailer_sender: Option<async_channel::Sender<Result<Trailers, BodyError>>>,
         trailer_receiver: async_channel::Receiver<Result<Trailers, BodyError>>,
+        pub(crate) trailer_names: Vec<HeaderName>,
     }
 }
 
@@ -30,6 +32,7 @@ impl Body {
             length: Some(0),
             trailer_sender: Some(sender),
             trailer_receiver: receiver,
+            trailer_names: Vec::new(),
         }
     }
 
@@ -48,6 +51,7 @@ impl Body {
             length: len,
             trailer_sender: Some(sender),
             trailer_receiver: receiver,
+            trailer_names: Vec::new(),
         }
     }
 
@@ -60,52 +64,50 @@ impl Body {
             reader: Box::new(Cursor::new(bytes)),
             trailer_sender: Some(sender),
             trailer_receiver: receiver,
+            trailer_names: Vec::new(),
         }
     }
 
     /// Read a Body into bytes. Consumes Body.
     pub async fn into_bytes(mut self) -> Result<Vec<u8>, BodyError> {
         let mut buf = Vec::with_capacity(1024);
-        self.read_to_end(&mut buf)
-            .await
-            .map_err(BodyError::Conversion)?;
+        self.read_to_end(&mut buf).await?;
         Ok(buf)
     }
 
     /// Read a Body into a String. Consumes Body.
     pub async fn into_string(mut self) -> Result<String, BodyError> {
         let mut buf = String::with_capacity(self.length.unwrap_or(0));
-        self.read_to_string(&mut buf)
-            .await
-            .map_err(BodyError::Conversion)?;
+        self.read_to_string(&mut buf).await?;
         Ok(buf)
     }
 
-    /// sending trailers not yet supported
+    /// Read a Body into bytes, and then receive its trailers, if any were sent. Consumes Body.
     pub async fn into_bytes_with_trailer(
         mut self,
     ) -> Result<(Vec<u8>, Option<Result<Trailers, BodyError>>), BodyError> {
         let mut buf = Vec::with_capacity(1024);
-        self.read_to_end(&mut buf)
-            .await
-            .map_err(BodyError::Conversion)?;
+        self.read_to_end(&mut buf).await?;
         let trailer = self.recv_trailers().await;
         Ok((buf, trailer))
     }
 
-    /// sending trailers not yet supported
+    /// Read a Body into a String, and then receive its trailers, if any were sent. Consumes Body.
     pub async fn into_string_with_trailer(
         mut self,
     ) -> Result<(String, Option<Result<Trailers, BodyError>>), BodyError> {
         let mut buf = String::with_capacity(self.length.unwrap_or(0));
-        self.read_to_string(&mut buf)
-            .await
-            .map_err(BodyError::Conversion)?;
+        self.read_to_string(&mut buf).await?;
         let trailer = self.recv_trailers().await;
         Ok((buf, trailer))
     }
 
-    /// sending trailers not yet supported
+    /// Get the sender for this body's trailers.
+    ///
+    /// For a response with a chunked body, the server waits for the trailers after the last
+    /// chunk (up to `ServerOpts::trailers_timeout`), and sends them. See the `trailers` module.
+    ///
+    /// Panics if called more than once.
     pub fn send_trailers(&mut self) -> TrailersSender {
         let sender = self
             .trailer_sender
@@ -114,12 +116,60 @@ impl Body {
         TrailersSender::new(sender)
     }
 
+    /// Names of the trailers which will be sent, for the server to declare in a `Trailer` header
+    /// (unless one is already set on the response).
+    pub fn declare_trailers(&mut self, names: impl IntoIterator<Item = HeaderName>) -> &mut Self {
+        self.trailer_names.extend(names);
+        self
+    }
+
+    /// The trailers to send after this body, if a `TrailersSender` was taken. `None` if there
+    /// aren't any, or if the sender was dropped without sending.
+    pub(crate) fn poll_trailers(&mut self, cx: &mut Context<'_>) -> Poll<Option<Trailers>> {
+        if self.trailer_sender.is_some() {
+            return Poll::Ready(None);
+        }
+        match self.trailer_receiver.poll_next(cx) {
+            Poll::Ready(Some(Ok(trailers))) => Poll::Ready(Some(trailers)),
+            Poll::Ready(_) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
     /// Don't use this directly if you also want to read the body.
-    /// In that case, prefer `into_{bytes, string}_with_trailer()
+    /// In that case, prefer `into_{bytes, string}_with_trailer()` or `trailers()`.
+    ///
+    /// `None` if no trailers can be sent, e.g. the body isn't chunked.
     pub async fn recv_trailers(&self) -> Option<Result<Trailers, BodyError>> {
+        if self.trailer_sender.is_some() {
+            return None;
+        }
         self.trailer_receiver.recv().await.ok()
     }
 
+    /// The trailers sent after the body, once the body has been read.
+    ///
+    /// Read the body first (e.g. as an `AsyncRead`), then call this. Any part of the body which
+    /// wasn't read is skipped.
+    pub async fn trailers(&mut self) -> Result<ReceivedTrailers, BodyError> {
+        if self.trailer_sender.is_some() {
+            return Ok(ReceivedTrailers::NotChunked);
+        }
+
+        // trailers only arrive at the end of the body
+        futures_lite::io::copy(&mut *self, futures_lite::io::sink()).await?;
+
+        match self.trailer_receiver.recv().await {
+            Ok(Ok(trailers)) if trailers.is_empty() => Ok(ReceivedTrailers::NoneSent),
+            Ok(Ok(trailers)) => Ok(ReceivedTrailers::Trailers(trailers)),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(BodyError::Trailer(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "body ended without trailers",
+            ))),
+        }
+    }
+
     pub(crate) fn set_inner(
         &mut self,
         rdr: impl AsyncBufRead + Unpin + Send + Sync + 'static,
@@ -139,6 +189,7 @@ impl From<String> for Body {
             reader: Box::new(Cursor::new(s.into_bytes())),
             trailer_sender: Some(sender),
             trailer_receiver: receiver,
+            trailer_names: Vec::new(),
         }
     }
 }
@@ -152,6 +203,7 @@ impl<'a> From<&'a str> for Body {
             reader: Box::new(Cursor::new(s.to_owned().into_bytes())),
             trailer_sender: Some(sender),
             trailer_receiver: receiver,
+            trailer_names: Vec::new(),
         }
     }
 }
@@ -177,6 +229,74 @@ impl AsyncBufRead for Body {
     }
 }
 
+pin_project_lite::pin_project! {
+    /// Fails reads once more than `limit` bytes have been read from the inner reader.
+    pub(crate) struct LimitedReader<R> {
+        #[pin]
+        inner: R,
+        limit: usize,
+        bytes_read: usize,
+    }
+}
+
+impl<R> LimitedReader<R> {
+    pub(crate) fn new(inner: R, limit: usize) -> Self {
+        Self {
+            inner,
+            limit,
+            bytes_read: 0,
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncBufRead> AsyncRead for LimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let src = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(src)) => src,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let n = std::cmp::min(src.len(), buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<R: AsyncBufRead> AsyncBufRead for LimitedReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&'_ [u8]>> {
+        let this = self.project();
+        let remaining = *this.limit - *this.bytes_read;
+        let limit = *this.limit;
+        match this.inner.poll_fill_buf(cx) {
+            // Only fail once there's data past the limit, so that a body of exactly `limit`
+            // bytes can still be read.
+            Poll::Ready(Ok(src)) if !src.is_empty() && remaining == 0 => Poll::Ready(Err(
+                io::Error::new(io::ErrorKind::InvalidData, BodyError::TooLarge(limit)),
+            )),
+            Poll::Ready(Ok(src)) => {
+                let n = std::cmp::min(src.len(), remaining);
+                Poll::Ready(Ok(&src[..n]))
+            }
+            other => other,
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.project();
+        *this.bytes_read += amt;
+        this.inner.consume(amt)
+    }
+}
+
 pub mod error {
     use std::fmt;
 
@@ -187,6 +307,9 @@ pub mod error {
         Conversion(std::io::Error),
         /// Error for sending or receiving trailer
         Trailer(std::io::Error),
+        /// The body went over the size limit (in bytes) set in `ServerOpts::max_body_size`.
+        /// Respond with a 413 Payload Too Large.
+        TooLarge(usize),
     }
 
     impl std::error::Error for BodyError {
@@ -195,6 +318,7 @@ pub mod error {
             match self {
                 Conversion(err) => Some(err),
                 Trailer(err) => Some(err),
+                TooLarge(_) => None,
             }
         }
     }
@@ -205,6 +329,20 @@ pub mod error {
             match self {
                 Conversion(err) => write!(f, "Error converting body: {}", err),
                 Trailer(err) => write!(f, "Error in body trailer: {}", err),
+                TooLarge(limit) => write!(f, "Body larger than limit of {} bytes", limit),
+            }
+        }
+    }
+
+    /// When reading a `Body` directly as `AsyncRead`, use this conversion to recover a `BodyError`
+    /// (e.g. `TooLarge`) from the `io::Error`.
+    impl From<std::io::Error> for BodyError {
+        fn from(err: std::io::Error) -> Self {
+            if err.get_ref().map(|inner| inner.is::<BodyError>()).unwrap_or(false) {
+                let inner = err.into_inner().expect("checked inner error exists");
+                *inner.downcast::<BodyError>().expect("checked inner error is BodyError")
+            } else {
+                BodyError::Conversion(err)
             }
         }
     }
@@ -25,7 +25,7 @@ lazy_static::lazy_static! {
 
 /// Decodes a chunked body according to
 /// https://tools.ietf.org/html/rfc7230#section-4.1
-pub(crate) struct ChunkedDecoder<R: AsyncRead> {
+pub(crate) struct ChunkedDecoder<R> {
     /// The underlying stream
     inner: R,
     /// Buffer for the already read, but not yet parsed data.
@@ -52,6 +52,16 @@ impl<R: AsyncRead> ChunkedDecoder<R> {
             trailer_sender: Some(trailer_sender),
         }
     }
+
+    /// The underlying reader, and any bytes read from it past the end of the chunked body.
+    ///
+    /// `None` until the end of the body has been decoded.
+    pub(crate) fn into_parts(self) -> Option<(R, Vec<u8>)> {
+        match self.state {
+            State::Done => Some((self.inner, self.buffer[self.current.clone()].to_vec())),
+            _ => None,
+        }
+    }
 }
 
 impl<R: AsyncRead + Unpin> ChunkedDecoder<R> {
@@ -162,7 +172,7 @@ impl<R: AsyncRead + Unpin> ChunkedDecoder<R> {
                 let sender =
                     sender.expect("invalid chunked state, tried sending multiple trailers");
 
-                let fut = Box::pin(sender.send(Ok(headers)));
+                let fut = Box::pin(sender.send_result(Ok(headers)));
                 Ok(DecodeResult::Some {
                     read: 0,
                     new_state: Some(State::TrailerSending(fut)),
@@ -272,10 +282,7 @@ impl<R: AsyncRead + Unpin> AsyncRead for ChunkedDecoder<R> {
                 } else {
                     this.buffer = buffer;
                     this.current = n;
-                    return Poll::Ready(Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "incoming data too large",
-                    )));
+                    return Poll::Ready(Err(io::Error::other("incoming data too large")));
                 }
             }
 
@@ -298,6 +305,8 @@ impl<R: AsyncRead + Unpin> AsyncRead for ChunkedDecoder<R> {
                         // Unexpected end
                         // TODO: do something?
                         this.state = State::Done;
+                        // No trailers are coming, let anyone waiting for them know.
+                        this.trailer_sender = None;
                     }
                     _ => {}
                 }
@@ -454,7 +463,7 @@ fn decode_init(buffer: Block<'static>, pos: &Range<usize>) -> io::Result<DecodeR
             })
         }
         Ok(Status::Partial) => Ok(DecodeResult::None(buffer)),
-        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+        Err(err) => Err(io::Error::other(err.to_string())),
     }
 }
 
@@ -493,9 +502,9 @@ fn decode_trailer(buffer: Block<'static>, pos: &Range<usize>) -> io::Result<Deco
                 trailers.headers.append(
                     // TODO should this be insert?
                     HeaderName::from_bytes(header.name.as_bytes())
-                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?,
+                        .map_err(|err| io::Error::other(err.to_string()))?,
                     HeaderValue::from_bytes(header.value)
-                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?,
+                        .map_err(|err| io::Error::other(err.to_string()))?,
                 );
             }
 
@@ -511,7 +520,7 @@ fn decode_trailer(buffer: Block<'static>, pos: &Range<usize>) -> io::Result<Deco
             })
         }
         Ok(Status::Partial) => Ok(DecodeResult::None(buffer)),
-        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+        Err(err) => Err(io::Error::other(err.to_string())),
     }
 }
 
@@ -606,10 +615,10 @@ mod tests {
                 trailer.headers.iter().collect::<Vec<_>>(),
                 vec![(
                     &HeaderName::from_bytes(b"Expires")
-                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+                        .map_err(|err| io::Error::other(err.to_string()))
                         .unwrap(),
                     &HeaderValue::from_bytes(b"Wed, 21 Oct 2015 07:28:00 GMT")
-                        .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))
+                        .map_err(|err| io::Error::other(err.to_string()))
                         .unwrap(),
                 )]
             );
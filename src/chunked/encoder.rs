@@ -2,11 +2,14 @@
 #![allow(clippy::len_zero)]
 #![allow(clippy::manual_saturating_arithmetic)]
 
-use futures_lite::AsyncBufRead;
+use futures_lite::{AsyncBufRead, Future};
+use futures_timer::Delay;
+use http::{header, HeaderMap};
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tracing::trace;
+use std::time::Duration;
+use tracing::{debug, trace};
 
 use crate::body::Body;
 
@@ -40,14 +43,25 @@ pub(crate) struct ChunkedEncoder {
     bytes_written: usize,
     /// The internal encoder state.
     state: State,
+    /// Encoded trailers, and how much of them has been written.
+    trailer_buf: Vec<u8>,
+    trailer_pos: usize,
+    /// How long to wait for trailers after the last chunk, and the wait so far.
+    trailers_timeout: Option<Duration>,
+    trailers_delay: Option<Delay>,
 }
 
 impl ChunkedEncoder {
-    /// Create a new instance.
-    pub(crate) fn new() -> Self {
+    /// Create a new instance. After `trailers_timeout` waiting for trailers, the stream ends
+    /// without them.
+    pub(crate) fn new(trailers_timeout: Option<Duration>) -> Self {
         Self {
             state: State::Start,
             bytes_written: 0,
+            trailer_buf: Vec::new(),
+            trailer_pos: 0,
+            trailers_timeout,
+            trailers_delay: None,
         }
     }
 
@@ -81,7 +95,7 @@ impl ChunkedEncoder {
             State::Start => self.init(body, cx, buf),
             State::EncodeChunks => self.encode_chunks(body, cx, buf),
             State::EndOfChunks => self.encode_chunks_eos(body, cx, buf),
-            State::ReceiveTrailers => self.encode_trailers(body, cx, buf),
+            State::ReceiveTrailers => self.receive_trailers(body, cx, buf),
             State::EncodeTrailers => self.encode_trailers(body, cx, buf),
             State::EndOfStream => self.encode_eos(cx, buf),
             State::End => Poll::Ready(Ok(0)),
@@ -196,7 +210,7 @@ impl ChunkedEncoder {
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
         // Request a new buf if the current buf is too small to write into.
-        if buf.len() < 3 {
+        if buf.len() - self.bytes_written < 1 + CRLF_LEN {
             cx.waker().wake_by_ref();
             return Poll::Ready(Ok(self.bytes_written));
         }
@@ -220,9 +234,31 @@ impl ChunkedEncoder {
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        // TODO: actually wait for trailers to be received.
-        self.set_state(State::EncodeTrailers);
-        self.encode_trailers(body, cx, buf)
+        match body.poll_trailers(cx) {
+            Poll::Ready(Some(trailers)) => {
+                encode_trailer_fields(&trailers, &mut self.trailer_buf);
+                self.set_state(State::EncodeTrailers);
+                self.encode_trailers(body, cx, buf)
+            }
+            Poll::Ready(None) => {
+                self.set_state(State::EndOfStream);
+                self.encode_eos(cx, buf)
+            }
+            Poll::Pending => {
+                if let Some(timeout) = self.trailers_timeout {
+                    let delay = self.trailers_delay.get_or_insert_with(|| Delay::new(timeout));
+                    if Pin::new(delay).poll(cx).is_ready() {
+                        debug!("Response trailers timeout, ending body without them");
+                        self.set_state(State::EndOfStream);
+                        return self.encode_eos(cx, buf);
+                    }
+                }
+                match self.bytes_written {
+                    0 => Poll::Pending,
+                    n => Poll::Ready(Ok(n)),
+                }
+            }
+        }
     }
 
     /// Send trailers to the buffer.
@@ -232,13 +268,33 @@ impl ChunkedEncoder {
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        // TODO: actually encode trailers here.
+        let len = std::cmp::min(
+            self.trailer_buf.len() - self.trailer_pos,
+            buf.len() - self.bytes_written,
+        );
+        let lower = self.bytes_written;
+        buf[lower..lower + len]
+            .copy_from_slice(&self.trailer_buf[self.trailer_pos..self.trailer_pos + len]);
+        self.trailer_pos += len;
+        self.bytes_written += len;
+
+        if self.trailer_pos < self.trailer_buf.len() {
+            cx.waker().wake_by_ref();
+            return Poll::Ready(Ok(self.bytes_written));
+        }
+
         self.set_state(State::EndOfStream);
         self.encode_eos(cx, buf)
     }
 
     /// Encode the end of the stream.
-    fn encode_eos(&mut self, _cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+    fn encode_eos(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        // Request a new buf if the current buf is too small to write into.
+        if buf.len() - self.bytes_written < CRLF_LEN {
+            cx.waker().wake_by_ref();
+            return Poll::Ready(Ok(self.bytes_written));
+        }
+
         let idx = self.bytes_written;
         // Write the final CRLF
         buf[idx] = CR;
@@ -249,3 +305,16 @@ impl ChunkedEncoder {
         Poll::Ready(Ok(self.bytes_written))
     }
 }
+
+/// Trailer fields, each followed by a CRLF. The final CRLF of the message is not included.
+///
+/// Fields which would change the framing of the message are left out.
+pub(crate) fn encode_trailer_fields(trailers: &HeaderMap, out: &mut Vec<u8>) {
+    let framing = [header::CONTENT_LENGTH, header::TRANSFER_ENCODING, header::TRAILER];
+    for (name, value) in trailers.iter().filter(|(name, _)| !framing.contains(name)) {
+        out.extend_from_slice(name.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(value.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+}
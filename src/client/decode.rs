@@ -95,7 +95,7 @@ where
     // must be either transfer encoding or content length/ TODO compile time
     let mut res = Response::new(Body::empty());
 
-    if let Some(encoding) = headers.get(TRANSFER_ENCODING).iter().last() {
+    if let Some(encoding) = headers.get(TRANSFER_ENCODING).iter().next_back() {
         if *encoding == "chunked" {
             let mut body = Body::empty();
             let trailers_sender = body.send_trailers();
@@ -109,7 +109,7 @@ where
     }
 
     // Check for Content-Length.
-    if let Some(len) = headers.get(CONTENT_LENGTH).iter().last() {
+    if let Some(len) = headers.get(CONTENT_LENGTH).iter().next_back() {
         let len = len
             .to_str()
             .map_err(error::decode_err)?
@@ -149,7 +149,7 @@ impl AsyncRead for Encoder {
                     if bytes_read == 0 {
                         return Poll::Pending;
                     } else {
-                        return Poll::Ready(Ok(bytes_read as usize));
+                        return Poll::Ready(Ok(bytes_read));
                     }
                 }
             };
@@ -160,6 +160,6 @@ impl AsyncRead for Encoder {
             }
         }
 
-        Poll::Ready(Ok(bytes_read as usize))
+        Poll::Ready(Ok(bytes_read))
     }
 }
@@ -1,5 +1,7 @@
 #![deny(unsafe_code)]
 #![warn(missing_docs)]
+// `Glitch` is a response, so it is large by design
+#![allow(clippy::result_large_err)]
 
 //! # tophat
 //!
@@ -23,6 +25,7 @@ mod util;
 /// Re-export http crate for convenience
 pub use http;
 
+pub use body::error::BodyError;
 pub use body::Body;
 pub use request::Request;
 pub use response::Response;
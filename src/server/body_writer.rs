@@ -0,0 +1,181 @@
+// Writing a response body directly to the connection, after its head has been sent.
+
+use futures_lite::{AsyncWrite, AsyncWriteExt};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::chunked::encode_trailer_fields;
+use crate::timeout::TimeoutWriter;
+use crate::trailers::Trailers;
+
+use super::conn::BodySlot;
+use super::encode::BodyFraming;
+use super::glitch::Glitch;
+use super::response_writer::ResponseWritten;
+
+// Larger writes are split, so that at most one chunk is buffered at a time.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes a response body as it's produced, from `ResponseWriter::start_stream`.
+///
+/// The head of the response has already been sent. Use `AsyncWriteExt::write_all` and `flush` to
+/// send the body; each write is sent as a chunk (`transfer-encoding: chunked`), and a write only
+/// completes once the previous chunk has been written to the connection, so a slow client slows
+/// down the handler instead of filling up memory.
+///
+/// The body must be ended with `finish` (or `finish_with_trailers`), which returns the
+/// `ResponseWritten` for the endpoint. If the endpoint returns without finishing, even with a
+/// `Glitch`, the connection is closed, since a response was already partly sent.
+///
+/// For HTTP/1.0, the body is not chunked and the connection closes after it. In response to HEAD,
+/// or with a 204 or 304 status, writes are discarded.
+pub struct BodyWriter<W> {
+    writer: TimeoutWriter<W>,
+    framing: BodyFraming,
+    // an encoded chunk which is not yet fully written to the connection
+    pending: Vec<u8>,
+    pending_pos: usize,
+    bytes_written: u64,
+    keep_alive: bool,
+    body_slot: BodySlot<W>,
+}
+
+impl<W> BodyWriter<W>
+where
+    W: AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    pub(crate) fn new(
+        writer: TimeoutWriter<W>,
+        framing: BodyFraming,
+        head_bytes_written: u64,
+        keep_alive: bool,
+        body_slot: BodySlot<W>,
+    ) -> Self {
+        body_slot.set_streaming(true);
+
+        Self {
+            writer,
+            framing,
+            pending: Vec::new(),
+            pending_pos: 0,
+            bytes_written: head_bytes_written,
+            keep_alive,
+            body_slot,
+        }
+    }
+
+    /// End the body, and flush it to the connection.
+    pub async fn finish(self) -> Result<ResponseWritten, Glitch> {
+        self.finish_with_trailers(Trailers::new()).await
+    }
+
+    /// End the body with trailers (headers sent after the body), and flush it to the connection.
+    ///
+    /// Trailers are only sent with a chunked body. Since the head has already been sent, declare
+    /// them beforehand with a `Trailer` header.
+    pub async fn finish_with_trailers(
+        mut self,
+        trailers: Trailers,
+    ) -> Result<ResponseWritten, Glitch> {
+        self.flush().await?;
+
+        if self.framing == BodyFraming::Chunked {
+            let mut end = b"0\r\n".to_vec();
+            encode_trailer_fields(&trailers, &mut end);
+            end.extend_from_slice(b"\r\n");
+            self.pending = end;
+            self.pending_pos = 0;
+            self.flush().await?;
+        }
+
+        self.body_slot.set_streaming(false);
+
+        Ok(ResponseWritten {
+            bytes_written: self.bytes_written,
+            keep_alive: self.keep_alive,
+        })
+    }
+
+    /// Bytes written to the connection so far, including the head and chunk framing.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Write out the pending chunk.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let buf = &self.pending[self.pending_pos..];
+            match Pin::new(&mut self.writer).poll_write(cx, buf) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => {
+                    self.pending_pos += n;
+                    self.bytes_written += n as u64;
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> AsyncWrite for BodyWriter<W>
+where
+    W: AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        match this.framing {
+            BodyFraming::None => Poll::Ready(Ok(buf.len())),
+            BodyFraming::CloseDelimited => {
+                let res = Pin::new(&mut this.writer).poll_write(cx, buf);
+                if let Poll::Ready(Ok(n)) = res {
+                    this.bytes_written += n as u64;
+                }
+                res
+            }
+            BodyFraming::Chunked => {
+                let buf = &buf[..std::cmp::min(buf.len(), MAX_CHUNK_SIZE)];
+                this.pending = format!("{:X}\r\n", buf.len()).into_bytes();
+                this.pending.extend_from_slice(buf);
+                this.pending.extend_from_slice(b"\r\n");
+
+                // The chunk is accepted now, the next write or flush waits for it to be written.
+                if let Poll::Ready(Err(err)) = this.poll_pending(cx) {
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Ready(Ok(buf.len()))
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.writer).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    /// Only flushes; the body is ended by `finish`, and the connection stays open.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
@@ -0,0 +1,605 @@
+//! Broadcast module
+//!
+//! A `Hub` sends messages published to a topic to every subscriber of that topic, e.g. to fan
+//! out Server Sent Events (see `sse::subscribe`) or WebSocket messages to many clients:
+//!
+//! ```rust
+//! # smol::block_on(async {
+//! use futures_lite::StreamExt;
+//! use tophat::server::broadcast::Hub;
+//!
+//! let hub = Hub::new();
+//! let mut subscriber = hub.subscribe("news");
+//! assert_eq!(hub.subscriber_count("news"), 1);
+//!
+//! hub.publish("news", "hello".to_owned()).await;
+//! assert_eq!(subscriber.next().await, Some((1, "hello".to_owned())));
+//!
+//! // a dropped subscriber is removed
+//! drop(subscriber);
+//! assert_eq!(hub.subscriber_count("news"), 0);
+//! # });
+//! ```
+//!
+//! Each message gets an id, counting up from 1 in its topic. The last `HubConfig::replay`
+//! messages of each topic are kept, so that a client reconnecting can get what it missed with
+//! `Hub::subscribe_after` (for SSE, from `Last-Event-ID`).
+//!
+//! Each subscriber has a queue of `HubConfig::capacity` messages. When a subscriber doesn't keep
+//! up and its queue is full, `HubConfig::policy` decides what happens, see `SlowPolicy`.
+//!
+//! A subscriber is removed when it's dropped (e.g. when its client disconnects and the response
+//! is dropped), or disconnected by `SlowPolicy::Disconnect`.
+
+use futures_lite::future::poll_fn;
+use futures_lite::Stream;
+use futures_util::lock::Mutex as AsyncMutex;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+
+/// What to do when a subscriber's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowPolicy {
+    /// Drop the oldest message in the subscriber's queue to make room. The subscriber misses
+    /// messages, but publishing is never held up.
+    DropOldest,
+    /// Disconnect the subscriber: its stream ends, and it's removed from the hub. A client can
+    /// then reconnect, and catch up from the replay buffer.
+    Disconnect,
+    /// Wait for room in the subscriber's queue. No messages are missed, but `publish` waits on
+    /// the slowest subscriber.
+    Block,
+}
+
+/// Settings for a `Hub`.
+#[derive(Debug, Clone)]
+pub struct HubConfig {
+    /// Most messages waiting in a subscriber's queue.
+    ///
+    /// Default 64.
+    pub capacity: usize,
+    /// What to do when a subscriber's queue is full.
+    ///
+    /// Default `SlowPolicy::DropOldest`.
+    pub policy: SlowPolicy,
+    /// How many of the latest messages of each topic are kept to replay, for
+    /// `Hub::subscribe_after`. 0 for none. A topic with messages to replay is kept until
+    /// `Hub::remove_topic`.
+    ///
+    /// Default 64.
+    pub replay: usize,
+}
+
+impl Default for HubConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            policy: SlowPolicy::DropOldest,
+            replay: 64,
+        }
+    }
+}
+
+/// Publishes messages to the subscribers of a topic. Cloning gives another handle to the same
+/// hub.
+pub struct Hub<T> {
+    inner: Arc<Mutex<HubInner<T>>>,
+    config: HubConfig,
+}
+
+struct HubInner<T> {
+    topics: HashMap<String, Topic<T>>,
+    next_subscriber: usize,
+}
+
+impl<T: Clone> HubInner<T> {
+    /// The topic to publish to. `None` if it has no subscribers and there's no replay, as then
+    /// there's nothing to keep.
+    fn publish_topic(&mut self, topic: &str, replay: usize) -> Option<&mut Topic<T>> {
+        if !self.topics.contains_key(topic) {
+            if replay == 0 {
+                return None;
+            }
+            self.topics.insert(topic.to_owned(), Topic::new());
+        }
+        self.topics.get_mut(topic)
+    }
+}
+
+struct Topic<T> {
+    subscribers: Vec<(usize, Arc<Queue<T>>)>,
+    replay: VecDeque<(u64, T)>,
+    last_id: u64,
+    // Held while publishing with `SlowPolicy::Block`
+    publish_lock: Arc<AsyncMutex<()>>,
+}
+
+impl<T: Clone> Topic<T> {
+    fn new() -> Self {
+        Self {
+            subscribers: Vec::new(),
+            replay: VecDeque::new(),
+            last_id: 0,
+            publish_lock: Arc::new(AsyncMutex::new(())),
+        }
+    }
+
+    /// Give `message` the next id, and keep it to replay.
+    fn record(&mut self, message: &T, replay: usize) -> u64 {
+        self.last_id += 1;
+        if replay > 0 {
+            if self.replay.len() >= replay {
+                self.replay.pop_front();
+            }
+            self.replay.push_back((self.last_id, message.clone()));
+        }
+        self.last_id
+    }
+}
+
+impl<T: Clone + Send + 'static> Hub<T> {
+    /// A hub with the default settings.
+    pub fn new() -> Self {
+        Self::with_config(HubConfig::default())
+    }
+
+    /// A hub with custom settings.
+    pub fn with_config(config: HubConfig) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HubInner {
+                topics: HashMap::new(),
+                next_subscriber: 0,
+            })),
+            config,
+        }
+    }
+
+    /// Subscribe to new messages of `topic`.
+    pub fn subscribe(&self, topic: &str) -> Subscriber<T> {
+        self.subscribe_after(topic, None)
+    }
+
+    /// Subscribe to `topic`, starting with the messages in the replay buffer after `last_id`.
+    /// With `None`, the same as `subscribe`.
+    ///
+    /// If messages after `last_id` are no longer in the replay buffer, they are missed; the
+    /// replay starts from the oldest message kept.
+    pub fn subscribe_after(&self, topic: &str, last_id: Option<u64>) -> Subscriber<T> {
+        let mut inner = self.lock();
+        let id = inner.next_subscriber;
+        inner.next_subscriber += 1;
+
+        let topic_entry = inner
+            .topics
+            .entry(topic.to_owned())
+            .or_insert_with(Topic::new);
+
+        let mut buf = VecDeque::new();
+        if let Some(last_id) = last_id {
+            buf.extend(
+                topic_entry
+                    .replay
+                    .iter()
+                    .filter(|(id, _)| *id > last_id)
+                    .cloned(),
+            );
+        }
+        let queue = Arc::new(Queue {
+            // the replay can be longer than the capacity, so that none of it is dropped
+            capacity: self.config.capacity.max(buf.len()).max(1),
+            state: Mutex::new(QueueState {
+                buf,
+                closed: false,
+                recv_waker: None,
+                send_wakers: Vec::new(),
+            }),
+        });
+        topic_entry.subscribers.push((id, queue.clone()));
+
+        Subscriber {
+            id,
+            topic: topic.to_owned(),
+            queue,
+            hub: Arc::downgrade(&self.inner),
+        }
+    }
+
+    /// Publish `message` to the subscribers of `topic`, and return its id. If the topic has no
+    /// subscribers and there's no replay buffer, the message is dropped and the id is 0.
+    ///
+    /// With `SlowPolicy::Block`, waits until every subscriber of the topic has room for it.
+    /// Publishing to other topics isn't held up.
+    ///
+    /// A topic with messages to replay is kept after its last subscriber leaves, so that clients
+    /// can still catch up. For topics made on the fly (e.g. one per user), call `remove_topic`
+    /// when done with one, or it's kept until the hub is dropped.
+    pub async fn publish(&self, topic: &str, message: T) -> u64 {
+        let replay = self.config.replay;
+        let on_full = match self.config.policy {
+            SlowPolicy::DropOldest => Some(OnFull::DropOldest),
+            SlowPolicy::Disconnect => Some(OnFull::Disconnect),
+            SlowPolicy::Block => None,
+        };
+
+        if let Some(on_full) = on_full {
+            // Doesn't wait, so push while locked and drop the subscribers which are gone.
+            let mut inner = self.lock();
+            let topic_entry = match inner.publish_topic(topic, replay) {
+                Some(topic_entry) => topic_entry,
+                None => return 0,
+            };
+            let id = topic_entry.record(&message, replay);
+            topic_entry
+                .subscribers
+                .retain(|(_, queue)| queue.try_push(id, message.clone(), on_full));
+            return id;
+        }
+
+        // Publishes to a topic one at a time, so that its subscribers get messages in id order
+        // even while `publish` waits.
+        let publish_lock = match self.lock().publish_topic(topic, replay) {
+            Some(topic_entry) => topic_entry.publish_lock.clone(),
+            None => return 0,
+        };
+        let _publishing = publish_lock.lock().await;
+
+        let (id, queues) = {
+            let mut inner = self.lock();
+            let topic_entry = match inner.publish_topic(topic, replay) {
+                Some(topic_entry) => topic_entry,
+                None => return 0,
+            };
+            let id = topic_entry.record(&message, replay);
+            let queues: Vec<_> = topic_entry
+                .subscribers
+                .iter()
+                .map(|(_, queue)| queue.clone())
+                .collect();
+            (id, queues)
+        };
+
+        for queue in queues {
+            queue.push_blocking(id, message.clone()).await;
+        }
+        id
+    }
+
+    /// The number of subscribers to `topic`.
+    pub fn subscriber_count(&self, topic: &str) -> usize {
+        self.lock()
+            .topics
+            .get(topic)
+            .map(|topic| topic.subscribers.len())
+            .unwrap_or(0)
+    }
+
+    /// The topics with subscribers, or with messages kept to replay.
+    pub fn topics(&self) -> Vec<String> {
+        self.lock().topics.keys().cloned().collect()
+    }
+
+    /// Remove `topic` and its replay buffer. Its subscribers are disconnected.
+    pub fn remove_topic(&self, topic: &str) {
+        if let Some(topic) = self.lock().topics.remove(topic) {
+            for (_, queue) in topic.subscribers {
+                queue.close();
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HubInner<T>> {
+        self.inner.lock().expect("hub lock poisoned")
+    }
+}
+
+impl<T: Clone + Send + 'static> Default for Hub<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Clone for Hub<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Hub<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hub").field("config", &self.config).finish()
+    }
+}
+
+/// A subscription to a topic of a `Hub`: a `Stream` of each message with its id.
+///
+/// Ends if the subscriber is disconnected, by `SlowPolicy::Disconnect` or `Hub::remove_topic`.
+/// Dropping it unsubscribes.
+pub struct Subscriber<T> {
+    id: usize,
+    topic: String,
+    queue: Arc<Queue<T>>,
+    hub: Weak<Mutex<HubInner<T>>>,
+}
+
+impl<T> Subscriber<T> {
+    /// The topic subscribed to
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl<T> Stream for Subscriber<T> {
+    type Item = (u64, T);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.queue.lock();
+        match state.buf.pop_front() {
+            Some(message) => {
+                for waker in state.send_wakers.drain(..) {
+                    waker.wake();
+                }
+                Poll::Ready(Some(message))
+            }
+            None if state.closed => Poll::Ready(None),
+            None => {
+                state.recv_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Subscriber<T> {
+    fn drop(&mut self) {
+        self.queue.close();
+
+        if let Some(hub) = self.hub.upgrade() {
+            let mut hub = hub.lock().expect("hub lock poisoned");
+            if let Some(topic) = hub.topics.get_mut(&self.topic) {
+                topic.subscribers.retain(|(id, _)| *id != self.id);
+                if topic.subscribers.is_empty() && topic.replay.is_empty() {
+                    hub.topics.remove(&self.topic);
+                }
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for Subscriber<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscriber")
+            .field("topic", &self.topic)
+            .finish()
+    }
+}
+
+/// A subscriber's queue, shared with the hub.
+struct Queue<T> {
+    capacity: usize,
+    state: Mutex<QueueState<T>>,
+}
+
+struct QueueState<T> {
+    buf: VecDeque<(u64, T)>,
+    // The subscriber is gone, or disconnected
+    closed: bool,
+    recv_waker: Option<Waker>,
+    // Publishers waiting for room
+    send_wakers: Vec<Waker>,
+}
+
+impl<T> Queue<T> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, QueueState<T>> {
+        self.state.lock().expect("subscriber lock poisoned")
+    }
+
+    /// Push without waiting. `false` if the subscriber is gone (or just disconnected).
+    fn try_push(&self, id: u64, message: T, on_full: OnFull) -> bool {
+        let mut state = self.lock();
+        if state.closed {
+            return false;
+        }
+
+        if state.buf.len() >= self.capacity {
+            match on_full {
+                OnFull::DropOldest => {
+                    state.buf.pop_front();
+                }
+                OnFull::Disconnect => {
+                    state.buf.clear();
+                    state.closed = true;
+                    wake_receiver(&mut state);
+                    return false;
+                }
+            }
+        }
+
+        state.buf.push_back((id, message));
+        wake_receiver(&mut state);
+        true
+    }
+
+    /// Push, waiting for room. Nothing is pushed if the subscriber is gone.
+    async fn push_blocking(&self, id: u64, message: T) {
+        let mut message = Some(message);
+        poll_fn(|cx| {
+            let mut state = self.lock();
+            if state.closed {
+                return Poll::Ready(());
+            }
+            if state.buf.len() >= self.capacity {
+                state.send_wakers.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+            if let Some(message) = message.take() {
+                state.buf.push_back((id, message));
+            }
+            wake_receiver(&mut state);
+            Poll::Ready(())
+        })
+        .await
+    }
+
+    fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        for waker in state.send_wakers.drain(..) {
+            waker.wake();
+        }
+        wake_receiver(&mut state);
+    }
+}
+
+// The `SlowPolicy`s which don't wait, for `Queue::try_push`. `Block` uses `push_blocking`.
+#[derive(Debug, Clone, Copy)]
+enum OnFull {
+    DropOldest,
+    Disconnect,
+}
+
+fn wake_receiver<T>(state: &mut QueueState<T>) {
+    if let Some(waker) = state.recv_waker.take() {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_lite::{future, StreamExt};
+
+    fn test_hub(capacity: usize, policy: SlowPolicy, replay: usize) -> Hub<u32> {
+        Hub::with_config(HubConfig {
+            capacity,
+            policy,
+            replay,
+        })
+    }
+
+    // The messages ready now, and whether the stream ended
+    fn drain(subscriber: &mut Subscriber<u32>) -> (Vec<u32>, bool) {
+        let mut messages = Vec::new();
+        loop {
+            match future::block_on(future::poll_once(subscriber.next())) {
+                Some(Some((_, message))) => messages.push(message),
+                Some(None) => return (messages, true),
+                None => return (messages, false),
+            }
+        }
+    }
+
+    #[test]
+    fn test_topics() {
+        future::block_on(async {
+            let hub = test_hub(4, SlowPolicy::DropOldest, 0);
+            let mut a = hub.subscribe("a");
+            let mut a2 = hub.subscribe("a");
+            let mut b = hub.subscribe("b");
+            assert_eq!(hub.subscriber_count("a"), 2);
+
+            assert_eq!(hub.publish("a", 1).await, 1);
+            assert_eq!(hub.publish("a", 2).await, 2);
+            assert_eq!(hub.publish("b", 3).await, 1);
+            // no subscribers, and no replay
+            assert_eq!(hub.publish("c", 4).await, 0);
+
+            assert_eq!(drain(&mut a), (vec![1, 2], false));
+            assert_eq!(drain(&mut a2), (vec![1, 2], false));
+            assert_eq!(drain(&mut b), (vec![3], false));
+
+            drop(a2);
+            assert_eq!(hub.subscriber_count("a"), 1);
+            drop(b);
+            assert_eq!(hub.topics(), vec!["a".to_owned()]);
+
+            hub.remove_topic("a");
+            assert_eq!(drain(&mut a), (vec![], true));
+        });
+    }
+
+    #[test]
+    fn test_slow_policy() {
+        future::block_on(async {
+            let hub = test_hub(2, SlowPolicy::DropOldest, 0);
+            let mut sub = hub.subscribe("t");
+            for i in 1..=4 {
+                hub.publish("t", i).await;
+            }
+            assert_eq!(drain(&mut sub), (vec![3, 4], false));
+
+            let hub = test_hub(2, SlowPolicy::Disconnect, 0);
+            let mut sub = hub.subscribe("t");
+            for i in 1..=3 {
+                hub.publish("t", i).await;
+            }
+            assert_eq!(drain(&mut sub), (vec![], true));
+            assert_eq!(hub.subscriber_count("t"), 0);
+        });
+    }
+
+    #[test]
+    fn test_slow_policy_block() {
+        let hub = test_hub(1, SlowPolicy::Block, 0);
+        let mut sub = hub.subscribe("t");
+
+        future::block_on(hub.publish("t", 1));
+        let mut publish = Box::pin(hub.publish("t", 2));
+        assert!(future::block_on(future::poll_once(&mut publish)).is_none());
+
+        // room again
+        assert_eq!(drain(&mut sub).0, vec![1]);
+        assert_eq!(future::block_on(publish), 2);
+        assert_eq!(drain(&mut sub).0, vec![2]);
+
+        // a blocked publish ends when the subscriber goes away
+        future::block_on(hub.publish("t", 3));
+        let mut publish = Box::pin(hub.publish("t", 4));
+        assert!(future::block_on(future::poll_once(&mut publish)).is_none());
+        drop(sub);
+        assert_eq!(future::block_on(publish), 4);
+    }
+
+    #[test]
+    fn test_slow_policy_block_other_topic() {
+        let hub = test_hub(1, SlowPolicy::Block, 0);
+        let _stalled = hub.subscribe("stalled");
+        let mut sub = hub.subscribe("t");
+
+        future::block_on(hub.publish("stalled", 1));
+        let mut publish = Box::pin(hub.publish("stalled", 2));
+        assert!(future::block_on(future::poll_once(&mut publish)).is_none());
+
+        // another topic isn't held up
+        assert_eq!(future::block_on(hub.publish("t", 3)), 1);
+        assert_eq!(drain(&mut sub).0, vec![3]);
+    }
+
+    #[test]
+    fn test_replay() {
+        future::block_on(async {
+            let hub = test_hub(1, SlowPolicy::DropOldest, 3);
+            for i in 1..=5 {
+                hub.publish("t", i * 10).await;
+            }
+            // kept for replay without subscribers
+            assert_eq!(hub.topics(), vec!["t".to_owned()]);
+
+            let mut sub = hub.subscribe_after("t", Some(3));
+            assert_eq!(drain(&mut sub), (vec![40, 50], false));
+            // older than the buffer, so from its start
+            let mut sub = hub.subscribe_after("t", Some(0));
+            assert_eq!(drain(&mut sub), (vec![30, 40, 50], false));
+            let mut sub = hub.subscribe("t");
+            assert_eq!(drain(&mut sub), (vec![], false));
+
+            hub.publish("t", 60).await;
+            assert_eq!(sub.next().await, Some((6, 60)));
+        });
+    }
+}
@@ -0,0 +1,386 @@
+//! Compression module
+//!
+//! Compresses response bodies with gzip, deflate, or brotli, as negotiated from the request's
+//! `Accept-Encoding` (highest q-value wins; on a tie, the first in `Compression::encodings`).
+//!
+//! Either set `ServerOpts::compression` to compress every response sent with
+//! `ResponseWriter::send`, or use `ResponseWriter::compress` on a single response.
+//!
+//! A compressed body is streamed through the compressor, so its length isn't known up front and
+//! it's sent with `transfer-encoding: chunked`. `Content-Encoding` is set, `Vary: Accept-Encoding`
+//! is added, and a strong `ETag` becomes weak.
+//!
+//! A response is left alone if:
+//! - it already has a `Content-Encoding` (set `Content-Encoding: identity` to opt out of
+//!   compression for one response).
+//! - its content type is already compressed (see `Compression::skip_content_types`), or is
+//!   `text/event-stream`, whose events would be held up in the compressor.
+//! - its body is known to be smaller than `Compression::min_size`.
+//! - it has no body (e.g. 204, 304), is a `206 Partial Content`, or has
+//!   `Cache-Control: no-transform`.
+//!
+//! Bodies written with `ResponseWriter::start_stream` are not compressed.
+//!
+//! Request bodies can be decompressed too, by setting `ServerOpts::decompress_requests`. A request
+//! with a `Content-Encoding` of gzip, deflate, or br (or a list of them) gets a body which is
+//! decoded as it's read, and its `Content-Encoding` and `Content-Length` headers are removed,
+//! since they no longer describe the body. Any other coding is rejected with `415 Unsupported
+//! Media Type`, and the connection is closed.
+//!
+//! `ServerOpts::max_body_size` then limits the decompressed size of the body, so that a small
+//! compressed body can't blow up in memory. Set it when decompressing requests!
+
+use async_compression::futures::bufread::{
+    BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZlibDecoder, ZlibEncoder,
+};
+use async_compression::Level;
+use futures_lite::io::BufReader;
+use http::{
+    header::{self, HeaderMap, HeaderValue},
+    StatusCode,
+};
+
+use crate::body::{Body, LimitedReader};
+use crate::util::{empty, header_has_token};
+
+/// A content coding for compressing a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// `gzip`
+    Gzip,
+    /// `deflate`, which in http is the zlib format.
+    Deflate,
+    /// `br`. Uses a lower quality than brotli's default, which is too slow for compressing on
+    /// the fly.
+    Brotli,
+}
+
+impl Encoding {
+    /// The name of the coding in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    fn from_coding(coding: &str) -> Option<Self> {
+        if coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip") {
+            Some(Encoding::Gzip)
+        } else if coding.eq_ignore_ascii_case("deflate") {
+            Some(Encoding::Deflate)
+        } else if coding.eq_ignore_ascii_case("br") {
+            Some(Encoding::Brotli)
+        } else {
+            None
+        }
+    }
+}
+
+/// Codings listed in a request's `Content-Encoding`, in the order they were applied. `identity`
+/// is skipped.
+///
+/// `None` if any coding isn't supported.
+pub(crate) fn request_codings(headers: &HeaderMap) -> Option<Vec<Encoding>> {
+    let mut codings = Vec::new();
+    for value in headers.get_all(header::CONTENT_ENCODING) {
+        let value = value.to_str().ok()?;
+        for coding in value.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            if coding.eq_ignore_ascii_case("identity") {
+                continue;
+            }
+            codings.push(Encoding::from_coding(coding)?);
+        }
+    }
+    Some(codings)
+}
+
+/// Decode the request body as it's read, undoing `codings` (as returned by `request_codings`) in
+/// reverse. At most `limit` decoded bytes can be read.
+pub(crate) fn decompress(body: &mut Body, codings: &[Encoding], limit: usize) {
+    let mut reader = std::mem::replace(&mut body.reader, Box::new(empty()));
+    for encoding in codings.iter().rev() {
+        reader = match encoding {
+            Encoding::Gzip => {
+                let mut decoder = GzipDecoder::new(reader);
+                // concatenated gzip members make up one body
+                decoder.multiple_members(true);
+                Box::new(BufReader::new(decoder))
+            }
+            Encoding::Deflate => Box::new(BufReader::new(ZlibDecoder::new(reader))),
+            Encoding::Brotli => Box::new(BufReader::new(BrotliDecoder::new(reader))),
+        };
+    }
+    body.set_inner(LimitedReader::new(reader, limit), None);
+}
+
+/// Settings for compressing responses.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// Encodings to offer, in order of preference when the client accepts several equally.
+    ///
+    /// Default: brotli, gzip, deflate.
+    pub encodings: Vec<Encoding>,
+    /// Bodies with a known length under this many bytes aren't compressed; it's not worth it.
+    /// Bodies of unknown length are always compressed.
+    ///
+    /// Default 1024.
+    pub min_size: usize,
+    /// Content types which aren't compressed, because they already are. An entry ending in `/`
+    /// (e.g. `video/`) matches the whole type.
+    ///
+    /// Default: common image, audio, video, font, and archive types.
+    pub skip_content_types: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        let skip_content_types = [
+            "image/png",
+            "image/jpeg",
+            "image/gif",
+            "image/webp",
+            "image/avif",
+            "audio/",
+            "video/",
+            "font/woff",
+            "font/woff2",
+            "application/gzip",
+            "application/x-gzip",
+            "application/zip",
+            "application/x-bzip2",
+            "application/x-7z-compressed",
+            "application/x-rar-compressed",
+            "application/zstd",
+            "application/wasm",
+        ];
+
+        Self {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+            skip_content_types: skip_content_types.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl Compression {
+    /// Compress the response body, if it should be and the client accepts an encoding.
+    pub(crate) fn compress(
+        &self,
+        accept_encoding: Option<&HeaderValue>,
+        status: StatusCode,
+        headers: &mut HeaderMap,
+        body: &mut Body,
+    ) {
+        if !self.should_compress(status, headers, body) {
+            return;
+        }
+
+        // From here, the response depends on `Accept-Encoding`, whatever the client sent.
+        if !header_has_token(headers, header::VARY, "accept-encoding")
+            && !header_has_token(headers, header::VARY, "*")
+        {
+            headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        }
+
+        let encoding = match accept_encoding
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(|accept| negotiate(accept, &self.encodings))
+        {
+            Some(encoding) => encoding,
+            None => return,
+        };
+
+        let reader = std::mem::replace(&mut body.reader, Box::new(empty()));
+        match encoding {
+            Encoding::Gzip => body.set_inner(BufReader::new(GzipEncoder::new(reader)), None),
+            Encoding::Deflate => body.set_inner(BufReader::new(ZlibEncoder::new(reader)), None),
+            Encoding::Brotli => body.set_inner(
+                BufReader::new(BrotliEncoder::with_quality(reader, Level::Precise(4))),
+                None,
+            ),
+        }
+
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.as_str()));
+        headers.remove(header::CONTENT_LENGTH);
+
+        // The compressed body is no longer byte-for-byte the same.
+        if let Some(etag) = headers.get(header::ETAG) {
+            if !etag.as_bytes().starts_with(b"W/") {
+                let mut weak = b"W/".to_vec();
+                weak.extend_from_slice(etag.as_bytes());
+                if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                    headers.insert(header::ETAG, weak);
+                }
+            }
+        }
+    }
+
+    fn should_compress(&self, status: StatusCode, headers: &HeaderMap, body: &Body) -> bool {
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || status == StatusCode::PARTIAL_CONTENT
+        {
+            return false;
+        }
+
+        if headers.contains_key(header::CONTENT_ENCODING)
+            || headers.contains_key(header::CONTENT_RANGE)
+            || header_has_token(headers, header::CACHE_CONTROL, "no-transform")
+        {
+            return false;
+        }
+
+        if matches!(body.length, Some(len) if len < self.min_size) {
+            return false;
+        }
+
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok())
+            .and_then(|ct| ct.split(';').next())
+            .map(|ct| ct.trim().to_ascii_lowercase());
+        if let Some(content_type) = content_type {
+            if content_type == "text/event-stream" {
+                return false;
+            }
+            let skip = self.skip_content_types.iter().any(|skip| {
+                if skip.ends_with('/') {
+                    content_type.starts_with(skip.as_str())
+                } else {
+                    content_type == *skip
+                }
+            });
+            if skip {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// The offered encoding with the highest q-value in `Accept-Encoding`. `*` covers any encoding
+/// not listed; q=0 means not acceptable.
+fn negotiate(accept_encoding: &str, offered: &[Encoding]) -> Option<Encoding> {
+    let accepted: Vec<(&str, u16)> = accept_encoding
+        .split(',')
+        .filter_map(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next()?.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let mut q = 1000;
+            for param in parts {
+                let mut param = param.splitn(2, '=');
+                if param.next()?.trim().eq_ignore_ascii_case("q") {
+                    q = parse_qvalue(param.next()?.trim())?;
+                }
+            }
+            Some((name, q))
+        })
+        .collect();
+
+    let q_value = |encoding: Encoding| {
+        let listed = accepted.iter().find(|(name, _)| {
+            name.eq_ignore_ascii_case(encoding.as_str())
+                || (encoding == Encoding::Gzip && name.eq_ignore_ascii_case("x-gzip"))
+        });
+        listed
+            .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+            .map(|(_, q)| *q)
+    };
+
+    let mut best: Option<(Encoding, u16)> = None;
+    for &encoding in offered {
+        if let Some(q) = q_value(encoding) {
+            if q > 0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+                best = Some((encoding, q));
+            }
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// A q-value in thousandths, following https://tools.ietf.org/html/rfc7231#section-5.3.1:
+/// `0` or `1`, with at most 3 decimals, and no more than 1.
+fn parse_qvalue(value: &str) -> Option<u16> {
+    let (int, frac) = match value.find('.') {
+        Some(dot) => (&value[..dot], &value[dot + 1..]),
+        None => (value, ""),
+    };
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let thousandths = format!("{:0<3}", frac).parse::<u16>().ok()?;
+    match int {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OFFERED: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("gzip", &OFFERED), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip, deflate, br", &OFFERED), Some(Encoding::Brotli));
+        assert_eq!(negotiate("GZIP;q=0.5, deflate;q=0.8", &OFFERED), Some(Encoding::Deflate));
+        assert_eq!(negotiate("br;q=0, *", &OFFERED), Some(Encoding::Gzip));
+        assert_eq!(negotiate("x-gzip", &OFFERED), Some(Encoding::Gzip));
+        assert_eq!(negotiate("gzip;q=0", &OFFERED), None);
+        assert_eq!(negotiate("identity", &OFFERED), None);
+        assert_eq!(negotiate("", &OFFERED), None);
+        // an unparseable q-value ignores that coding
+        assert_eq!(negotiate("br;q=high, gzip", &OFFERED), Some(Encoding::Gzip));
+        for q in &["NaN", "inf", "5", "1.001", "-0", "0.1234", "+1", ".5", "1e0"] {
+            let accept_encoding = format!("br;q={}, gzip;q=0.5", q);
+            assert_eq!(negotiate(&accept_encoding, &OFFERED), Some(Encoding::Gzip), "{}", q);
+        }
+        assert_eq!(negotiate("br;q=0.001, gzip;q=0.0", &OFFERED), Some(Encoding::Brotli));
+        assert_eq!(negotiate("br;q=1., gzip;q=0.999", &OFFERED), Some(Encoding::Brotli));
+        // server preference breaks ties
+        assert_eq!(
+            negotiate("deflate, gzip", &[Encoding::Gzip, Encoding::Deflate]),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn test_parse_qvalue() {
+        assert_eq!(parse_qvalue("1"), Some(1000));
+        assert_eq!(parse_qvalue("1.000"), Some(1000));
+        assert_eq!(parse_qvalue("0.5"), Some(500));
+        assert_eq!(parse_qvalue("0.05"), Some(50));
+        assert_eq!(parse_qvalue("0"), Some(0));
+        assert_eq!(parse_qvalue("0."), Some(0));
+        assert_eq!(parse_qvalue("1.5"), None);
+        assert_eq!(parse_qvalue("NaN"), None);
+        assert_eq!(parse_qvalue(""), None);
+    }
+
+    #[test]
+    fn test_request_codings() {
+        let codings = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_ENCODING, value.parse().unwrap());
+            request_codings(&headers)
+        };
+
+        assert_eq!(request_codings(&HeaderMap::new()), Some(vec![]));
+        assert_eq!(codings("gzip"), Some(vec![Encoding::Gzip]));
+        assert_eq!(codings("identity"), Some(vec![]));
+        assert_eq!(codings("deflate, BR"), Some(vec![Encoding::Deflate, Encoding::Brotli]));
+        assert_eq!(codings("x-gzip"), Some(vec![Encoding::Gzip]));
+        assert_eq!(codings("gzip, compress"), None);
+        assert_eq!(codings("zstd"), None);
+    }
+}
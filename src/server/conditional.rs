@@ -0,0 +1,320 @@
+//! Conditional requests
+//!
+//! When a response to a GET or HEAD request sent with `ResponseWriter::send` has an `ETag` or
+//! `Last-Modified` header, the request's preconditions are checked against them, following
+//! https://tools.ietf.org/html/rfc7232#section-6:
+//! 1. `If-Match` (strong comparison), or else `If-Unmodified-Since`: if it fails, the response is
+//!    `412 Precondition Failed`.
+//! 2. `If-None-Match` (weak comparison), or else `If-Modified-Since`: if it fails, the response
+//!    is `304 Not Modified`.
+//!
+//! So a handler only needs to set the validators (and can skip the expensive part of building
+//! the body if it wants, by checking itself). A 304 keeps the validators and caching headers, but
+//! drops the body and the headers describing it.
+//!
+//! Preconditions are only checked for a 2xx response, and not for `ResponseWriter::start_stream`
+//! or `upgrade`.
+//!
+//! Other methods change the resource, so their preconditions must be checked against its current
+//! state before the change is made, which only the handler can do, with `check`:
+//!
+//! ```rust,no_run
+//! # use futures_util::io::{AsyncRead, AsyncWrite};
+//! # use tophat::{Request, server::{glitch::Result, ResponseWriter, ResponseWritten}};
+//! # fn current_etag() -> http::HeaderValue { unimplemented!() }
+//! use tophat::server::conditional;
+//!
+//! async fn put<W>(req: Request, mut resp_wtr: ResponseWriter<W>) -> Result<ResponseWritten>
+//!     where W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+//! {
+//!     if let Some(status) = conditional::check(&req, true, Some(&current_etag()), None) {
+//!         resp_wtr.set_status(status);
+//!         return resp_wtr.send().await;
+//!     }
+//!     // apply the change
+//! #   unimplemented!()
+//! }
+//! ```
+//!
+//! For a body already in memory, `ResponseWriter::set_body_with_etag` (or `etag`) makes a strong
+//! `ETag` from its bytes.
+
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use std::time::SystemTime;
+
+use crate::body::Body;
+use crate::request::Request;
+
+/// The request's precondition headers.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Preconditions {
+    if_match: Option<HeaderValue>,
+    if_none_match: Option<HeaderValue>,
+    if_modified_since: Option<HeaderValue>,
+    if_unmodified_since: Option<HeaderValue>,
+}
+
+/// A strong `ETag` for `bytes`, from their length and a hash.
+///
+/// It doesn't change between runs, so it stays valid across restarts.
+pub fn etag(bytes: &[u8]) -> HeaderValue {
+    // FNV-1a, which is stable (unlike std's `DefaultHasher`).
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("\"{:x}-{:016x}\"", bytes.len(), hash)
+        .parse()
+        .expect("valid header value")
+}
+
+/// Check the request's preconditions against the current `etag` and `last_modified` of the
+/// resource, before handling it. Returns the status to respond with if one fails: `412
+/// Precondition Failed`, or `304 Not Modified` for GET and HEAD.
+///
+/// `exists` is whether the resource currently has a representation, which is what `*` in
+/// `If-Match` and `If-None-Match` matches. So a create-only PUT with `If-None-Match: *` passes
+/// when `exists` is false.
+///
+/// Needed for methods other than GET and HEAD, whose preconditions aren't checked automatically.
+pub fn check(
+    req: &Request,
+    exists: bool,
+    etag: Option<&HeaderValue>,
+    last_modified: Option<SystemTime>,
+) -> Option<StatusCode> {
+    let etag = etag.and_then(|etag| etag.to_str().ok());
+    Preconditions::from_headers(req.headers())
+        .and_then(|preconditions| preconditions.check(req.method(), exists, etag, last_modified))
+}
+
+impl Preconditions {
+    /// `None` if the request has no preconditions.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let preconditions = Self {
+            if_match: headers.get(header::IF_MATCH).cloned(),
+            if_none_match: headers.get(header::IF_NONE_MATCH).cloned(),
+            if_modified_since: headers.get(header::IF_MODIFIED_SINCE).cloned(),
+            if_unmodified_since: headers.get(header::IF_UNMODIFIED_SINCE).cloned(),
+        };
+
+        if preconditions.if_match.is_none()
+            && preconditions.if_none_match.is_none()
+            && preconditions.if_modified_since.is_none()
+            && preconditions.if_unmodified_since.is_none()
+        {
+            None
+        } else {
+            Some(preconditions)
+        }
+    }
+
+    /// Turn the response into a 304 or 412 if a precondition fails. Only for GET and HEAD, as
+    /// any other method has already been applied, and the response describes the new state.
+    pub(crate) fn evaluate(
+        &self,
+        method: &Method,
+        status: &mut StatusCode,
+        headers: &mut HeaderMap,
+        body: &mut Body,
+    ) {
+        let is_get_or_head = *method == Method::GET || *method == Method::HEAD;
+        if !is_get_or_head || !status.is_success() {
+            return;
+        }
+        let etag = headers
+            .get(header::ETAG)
+            .and_then(|etag| etag.to_str().ok())
+            .map(|etag| etag.to_owned());
+        let last_modified = headers
+            .get(header::LAST_MODIFIED)
+            .and_then(|lm| lm.to_str().ok())
+            .and_then(|lm| httpdate::parse_http_date(lm).ok());
+        if etag.is_none() && last_modified.is_none() {
+            return;
+        }
+
+        // a 2xx response to GET or HEAD is the current representation
+        let failed = match self.check(method, true, etag.as_deref(), last_modified) {
+            Some(failed) => failed,
+            None => return,
+        };
+
+        *body = Body::empty();
+        // The body is gone, and with it what described it.
+        let content_headers: Vec<_> = headers
+            .keys()
+            .filter(|name| {
+                name.as_str().starts_with("content-") && *name != header::CONTENT_LOCATION
+            })
+            .cloned()
+            .collect();
+        for name in content_headers {
+            headers.remove(name);
+        }
+
+        if failed == StatusCode::NOT_MODIFIED {
+            // Last-Modified only helps a cache when there's no ETag.
+            if etag.is_some() {
+                headers.remove(header::LAST_MODIFIED);
+            }
+        }
+        *status = failed;
+    }
+
+    // The failing status, if any
+    pub(crate) fn check(
+        &self,
+        method: &Method,
+        exists: bool,
+        etag: Option<&str>,
+        last_modified: Option<SystemTime>,
+    ) -> Option<StatusCode> {
+        let is_get_or_head = *method == Method::GET || *method == Method::HEAD;
+
+        // 1. and 2.
+        if let Some(if_match) = header_str(&self.if_match) {
+            if !etag_list_matches(if_match, exists, etag, true) {
+                return Some(StatusCode::PRECONDITION_FAILED);
+            }
+        } else if let Some(since) = header_date(&self.if_unmodified_since) {
+            // without a Last-Modified to compare to, it's ignored
+            if last_modified.map(|lm| lm > since).unwrap_or(false) {
+                return Some(StatusCode::PRECONDITION_FAILED);
+            }
+        }
+
+        // 3. and 4.
+        if let Some(if_none_match) = header_str(&self.if_none_match) {
+            if etag_list_matches(if_none_match, exists, etag, false) {
+                return if is_get_or_head {
+                    Some(StatusCode::NOT_MODIFIED)
+                } else {
+                    Some(StatusCode::PRECONDITION_FAILED)
+                };
+            }
+        } else if is_get_or_head {
+            if let (Some(since), Some(last_modified)) =
+                (header_date(&self.if_modified_since), last_modified)
+            {
+                if last_modified <= since {
+                    return Some(StatusCode::NOT_MODIFIED);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+fn header_str(value: &Option<HeaderValue>) -> Option<&str> {
+    value.as_ref().and_then(|value| value.to_str().ok())
+}
+
+// An invalid date is ignored, as if the header wasn't sent.
+fn header_date(value: &Option<HeaderValue>) -> Option<SystemTime> {
+    header_str(value).and_then(|value| httpdate::parse_http_date(value).ok())
+}
+
+/// Whether a list of entity tags (or `*`) matches `etag`. With strong comparison, weak tags never
+/// match. `*` matches any current representation, so only if one `exists`.
+fn etag_list_matches(list: &str, exists: bool, etag: Option<&str>, strong: bool) -> bool {
+    if list.trim() == "*" {
+        return exists;
+    }
+    let etag = match etag {
+        Some(etag) => etag.trim(),
+        None => return false,
+    };
+    if strong && etag.starts_with("W/") {
+        return false;
+    }
+    let opaque = |tag: &str| tag.trim_start_matches("W/").to_owned();
+
+    list.split(',').map(str::trim).any(|tag| {
+        if strong && tag.starts_with("W/") {
+            return false;
+        }
+        !tag.is_empty() && opaque(tag) == opaque(etag)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const LAST_MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+    const BEFORE: &str = "Sun, 06 Nov 1994 08:49:36 GMT";
+
+    fn check(
+        method: Method,
+        req_headers: &[(&str, &str)],
+        etag: Option<&str>,
+    ) -> Option<StatusCode> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in req_headers {
+            headers.insert(
+                header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        let last_modified = httpdate::parse_http_date(LAST_MODIFIED).ok();
+        Preconditions::from_headers(&headers)
+            .unwrap()
+            .check(&method, true, etag, last_modified)
+    }
+
+    #[test]
+    fn test_etag_list_matches() {
+        assert!(etag_list_matches("\"a\"", true, Some("\"a\""), true));
+        assert!(etag_list_matches("\"b\", \"a\"", true, Some("\"a\""), true));
+        assert!(etag_list_matches("*", true, None, true));
+        assert!(!etag_list_matches("*", false, None, true));
+        assert!(!etag_list_matches("*", false, None, false));
+        assert!(!etag_list_matches("\"a\"", true, None, false));
+        assert!(!etag_list_matches("W/\"a\"", true, Some("\"a\""), true));
+        assert!(!etag_list_matches("\"a\"", true, Some("W/\"a\""), true));
+        assert!(etag_list_matches("W/\"a\"", true, Some("\"a\""), false));
+        assert!(etag_list_matches("\"a\"", true, Some("W/\"a\""), false));
+        assert!(!etag_list_matches("\"b\"", true, Some("\"a\""), false));
+    }
+
+    #[test]
+    fn test_precedence() {
+        let etag = Some("\"a\"");
+        let not_modified = Some(StatusCode::NOT_MODIFIED);
+        let failed = Some(StatusCode::PRECONDITION_FAILED);
+
+        assert_eq!(check(Method::GET, &[("if-none-match", "\"a\"")], etag), not_modified);
+        assert_eq!(check(Method::GET, &[("if-none-match", "\"b\"")], etag), None);
+        assert_eq!(check(Method::PUT, &[("if-none-match", "*")], etag), failed);
+        let headers = [("if-modified-since", LAST_MODIFIED)];
+        assert_eq!(check(Method::GET, &headers, etag), not_modified);
+        assert_eq!(check(Method::GET, &[("if-modified-since", BEFORE)], etag), None);
+        assert_eq!(check(Method::POST, &[("if-modified-since", LAST_MODIFIED)], etag), None);
+        assert_eq!(check(Method::GET, &[("if-modified-since", "garbage")], etag), None);
+
+        // If-None-Match wins over If-Modified-Since
+        let headers = [("if-none-match", "\"b\""), ("if-modified-since", LAST_MODIFIED)];
+        assert_eq!(check(Method::GET, &headers, etag), None);
+
+        assert_eq!(check(Method::PUT, &[("if-match", "\"a\"")], etag), None);
+        assert_eq!(check(Method::PUT, &[("if-match", "\"b\"")], etag), failed);
+        assert_eq!(check(Method::PUT, &[("if-match", "\"a\"")], None), failed);
+        assert_eq!(check(Method::PUT, &[("if-unmodified-since", BEFORE)], etag), failed);
+        assert_eq!(check(Method::PUT, &[("if-unmodified-since", LAST_MODIFIED)], etag), None);
+
+        // If-Match wins over If-Unmodified-Since, and is checked before If-None-Match
+        let headers = [("if-match", "\"a\""), ("if-unmodified-since", BEFORE)];
+        assert_eq!(check(Method::PUT, &headers, etag), None);
+        let headers = [("if-match", "\"b\""), ("if-none-match", "\"a\"")];
+        assert_eq!(check(Method::GET, &headers, etag), failed);
+    }
+
+    #[test]
+    fn test_etag() {
+        assert_eq!(etag(b"hello"), "\"5-a430d84680aabd0b\"");
+        assert_ne!(etag(b"hello"), etag(b"hellp"));
+    }
+}
@@ -0,0 +1,395 @@
+// Reading a connection across requests.
+//
+// A connection has one buffered reader, which lives for the whole connection so that bytes read
+// past the end of one request (e.g. a pipelined request) are kept for the next. While a request
+// is being handled, its body owns the reader; once the body is dropped, the reader is handed
+// back to `accept` through a `BodySlot`.
+
+use futures_lite::io::{self as async_io, BufReader, Take};
+use futures_lite::{AsyncBufRead, AsyncRead, AsyncReadExt};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::body::LimitedReader;
+use crate::chunked::ChunkedDecoder;
+use crate::timeout::TimeoutReader;
+
+const BUF_SIZE: usize = 8 * 1024;
+
+// More unread body than this is not worth reading just to keep the connection open.
+const MAX_BODY_DRAIN: u64 = 64 * 1024;
+
+// The unread length of a chunked body which hasn't been read to the end.
+const UNKNOWN_LENGTH: u64 = u64::MAX;
+
+/// Buffered reader for a connection. Unlike `BufReader`, bytes can be put back in front of the
+/// buffer.
+pub(crate) struct ConnReader<IO> {
+    io: IO,
+    buf: Vec<u8>,
+    pos: usize,
+    cap: usize,
+}
+
+impl<IO> ConnReader<IO> {
+    pub(crate) fn new(io: IO) -> Self {
+        Self {
+            io,
+            buf: vec![0; BUF_SIZE],
+            pos: 0,
+            cap: 0,
+        }
+    }
+
+    /// The io, and any bytes which were read from it but not consumed.
+    pub(crate) fn into_parts(self) -> (IO, Vec<u8>) {
+        let buffered = self.buf[self.pos..self.cap].to_vec();
+        (self.io, buffered)
+    }
+
+    /// Put bytes back, to be read before the rest of the buffer.
+    fn unread(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        let mut buf =
+            Vec::with_capacity(std::cmp::max(BUF_SIZE, bytes.len() + self.cap - self.pos));
+        buf.extend_from_slice(bytes);
+        buf.extend_from_slice(&self.buf[self.pos..self.cap]);
+        self.cap = buf.len();
+        self.pos = 0;
+        buf.resize(buf.capacity(), 0);
+        self.buf = buf;
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for ConnReader<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let src = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(src)) => src,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let n = std::cmp::min(src.len(), buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncBufRead for ConnReader<IO> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&'_ [u8]>> {
+        let this = self.get_mut();
+        if this.pos >= this.cap {
+            match Pin::new(&mut this.io).poll_read(cx, &mut this.buf) {
+                Poll::Ready(Ok(n)) => {
+                    this.pos = 0;
+                    this.cap = n;
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..this.cap]))
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.pos = std::cmp::min(self.pos + amt, self.cap);
+    }
+}
+
+/// Reads one request body from the connection reader.
+pub(crate) enum BodyDecoder<IO> {
+    Fixed(Take<TimeoutReader<ConnReader<IO>>>),
+    Chunked(LimitedReader<BufReader<ChunkedDecoder<TimeoutReader<ConnReader<IO>>>>>),
+}
+
+impl<IO: AsyncRead + Unpin> BodyDecoder<IO> {
+    fn unread(&self) -> u64 {
+        match self {
+            BodyDecoder::Fixed(reader) => reader.limit(),
+            BodyDecoder::Chunked(_) => UNKNOWN_LENGTH,
+        }
+    }
+
+    /// The connection reader, once the body has been read to the end. `None` if the end of a
+    /// chunked body wasn't reached.
+    fn into_conn(self) -> Option<ConnReader<IO>> {
+        match self {
+            BodyDecoder::Fixed(reader) => Some(reader.into_inner().into_inner()),
+            BodyDecoder::Chunked(reader) => {
+                let reader = reader.into_inner();
+                if !reader.buffer().is_empty() {
+                    return None;
+                }
+                // The chunked decoder may read past the end of the body.
+                let (reader, leftover) = reader.into_inner().into_parts()?;
+                let mut conn = reader.into_inner();
+                conn.unread(&leftover);
+                Some(conn)
+            }
+        }
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for BodyDecoder<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            BodyDecoder::Fixed(reader) => Pin::new(reader).poll_read(cx, buf),
+            BodyDecoder::Chunked(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncBufRead for BodyDecoder<IO> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&'_ [u8]>> {
+        match self.get_mut() {
+            BodyDecoder::Fixed(reader) => Pin::new(reader).poll_fill_buf(cx),
+            BodyDecoder::Chunked(reader) => Pin::new(reader).poll_fill_buf(cx),
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        match self.get_mut() {
+            BodyDecoder::Fixed(reader) => Pin::new(reader).consume(amt),
+            BodyDecoder::Chunked(reader) => Pin::new(reader).consume(amt),
+        }
+    }
+}
+
+/// Where a request body leaves its decoder when dropped.
+pub(crate) struct BodySlot<IO> {
+    slot: Arc<Mutex<Slot<IO>>>,
+    // How much of the current request body is left, kept up to date while it's read.
+    unread: Arc<AtomicU64>,
+    // Set in `new`, so that a response writer can drain the body without being bound to read.
+    drain: DrainFn<IO>,
+}
+
+type DrainFn<IO> =
+    for<'a> fn(&'a BodySlot<IO>) -> Pin<Box<dyn Future<Output = bool> + Send + 'a>>;
+
+struct Slot<IO> {
+    decoder: Option<BodyDecoder<IO>>,
+    // the connection was handed over to another protocol
+    upgraded: bool,
+    // a streamed response was started, but not finished
+    streaming: bool,
+}
+
+impl<IO> Clone for BodySlot<IO> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+            unread: self.unread.clone(),
+            drain: self.drain,
+        }
+    }
+}
+
+impl<IO> BodySlot<IO> {
+    /// Set while a streamed response is being written. If the handler returns before finishing
+    /// it, the connection can't be used for another response.
+    pub(crate) fn set_streaming(&self, streaming: bool) {
+        self.slot.lock().expect("body slot lock poisoned").streaming = streaming;
+    }
+
+    pub(crate) fn is_streaming(&self) -> bool {
+        self.slot.lock().expect("body slot lock poisoned").streaming
+    }
+
+    /// Whether what's left of the request body is little enough for `reclaim` to read, so that
+    /// the connection can stay open after the response.
+    ///
+    /// A body which was dropped is read now. Of a body still held by the handler, only a
+    /// fixed-length one's remainder is known; a chunked one is assumed to be drained, as its
+    /// length isn't known until it's read to the end.
+    pub(crate) async fn will_drain(&self) -> bool {
+        (self.drain)(self).await
+    }
+}
+
+impl<IO: AsyncRead + Unpin> BodySlot<IO> {
+    pub(crate) fn new() -> Self
+    where
+        IO: Send,
+    {
+        Self {
+            slot: Arc::new(Mutex::new(Slot {
+                decoder: None,
+                upgraded: false,
+                streaming: false,
+            })),
+            unread: Arc::new(AtomicU64::new(0)),
+            drain: Self::drain,
+        }
+    }
+
+    /// The reader for a request body, which returns to this slot when dropped.
+    pub(crate) fn body_reader(&self, decoder: BodyDecoder<IO>) -> RequestBody<IO> {
+        self.unread.store(decoder.unread(), Ordering::Relaxed);
+        RequestBody {
+            decoder: Some(decoder),
+            slot: self.slot.clone(),
+            unread: self.unread.clone(),
+        }
+    }
+
+    /// For a request without a body, the decoder goes straight back into the slot.
+    pub(crate) fn put(&self, decoder: BodyDecoder<IO>) {
+        self.unread.store(decoder.unread(), Ordering::Relaxed);
+        self.slot.lock().expect("body slot lock poisoned").decoder = Some(decoder);
+    }
+
+    // Behind `will_drain`, as only here is `IO` known to be readable.
+    fn drain(&self) -> Pin<Box<dyn Future<Output = bool> + Send + '_>>
+    where
+        IO: Send,
+    {
+        Box::pin(async move {
+            let decoder = self.slot.lock().expect("body slot lock poisoned").decoder.take();
+            let mut decoder = match decoder {
+                Some(decoder) => decoder,
+                None => {
+                    let unread = self.unread.load(Ordering::Relaxed);
+                    return unread == UNKNOWN_LENGTH || unread <= MAX_BODY_DRAIN;
+                }
+            };
+
+            let drained =
+                async_io::copy((&mut decoder).take(MAX_BODY_DRAIN + 1), async_io::sink()).await;
+            self.slot.lock().expect("body slot lock poisoned").decoder = Some(decoder);
+            match drained {
+                Ok(drained) if drained <= MAX_BODY_DRAIN => {
+                    self.unread.store(0, Ordering::Relaxed);
+                    true
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Take back the connection reader from the last request body, after reading whatever the
+    /// handler left unread.
+    ///
+    /// `None` if the body is still held somewhere, if too much of it is left, or if it fails to
+    /// read; the connection can't be used for another request.
+    pub(crate) async fn reclaim(&self) -> Option<ConnReader<IO>> {
+        let mut decoder = self.slot.lock().expect("body slot lock poisoned").decoder.take()?;
+
+        let drained = async_io::copy((&mut decoder).take(MAX_BODY_DRAIN + 1), async_io::sink())
+            .await
+            .ok()?;
+        if drained > MAX_BODY_DRAIN {
+            return None;
+        }
+
+        decoder.into_conn()
+    }
+
+    /// Take the connection reader to hand the connection over to another protocol. Like
+    /// `reclaim`, except that `accept` won't read any more requests.
+    pub(crate) async fn upgrade(&self) -> Option<ConnReader<IO>> {
+        let conn = self.reclaim().await?;
+        self.slot.lock().expect("body slot lock poisoned").upgraded = true;
+        Some(conn)
+    }
+
+    pub(crate) fn is_upgraded(&self) -> bool {
+        self.slot.lock().expect("body slot lock poisoned").upgraded
+    }
+}
+
+/// The reader inside a request `Body`.
+pub(crate) struct RequestBody<IO: AsyncRead + Unpin> {
+    // only `None` once dropped
+    decoder: Option<BodyDecoder<IO>>,
+    slot: Arc<Mutex<Slot<IO>>>,
+    unread: Arc<AtomicU64>,
+}
+
+impl<IO: AsyncRead + Unpin> RequestBody<IO> {
+    fn decoder(&mut self) -> Pin<&mut BodyDecoder<IO>> {
+        Pin::new(
+            self.decoder
+                .as_mut()
+                .expect("request body decoder is only taken on drop"),
+        )
+    }
+
+    fn count_read(&self, amt: usize) {
+        // An unknown length stays unknown until the end of the body.
+        let _ = self.unread.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |unread| {
+            if unread == UNKNOWN_LENGTH {
+                None
+            } else {
+                Some(unread.saturating_sub(amt as u64))
+            }
+        });
+    }
+
+    fn count_read_to_end(&self) {
+        self.unread.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<IO: AsyncRead + Unpin> Drop for RequestBody<IO> {
+    fn drop(&mut self) {
+        if let Ok(mut slot) = self.slot.lock() {
+            slot.decoder = self.decoder.take();
+        }
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for RequestBody<IO> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = this.decoder().poll_read(cx, buf);
+        match res {
+            Poll::Ready(Ok(0)) if !buf.is_empty() => this.count_read_to_end(),
+            Poll::Ready(Ok(n)) => this.count_read(n),
+            _ => (),
+        }
+        res
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncBufRead for RequestBody<IO> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&'_ [u8]>> {
+        let this = self.get_mut();
+        let decoder = this
+            .decoder
+            .as_mut()
+            .expect("request body decoder is only taken on drop");
+        let res = Pin::new(decoder).poll_fill_buf(cx);
+        if let Poll::Ready(Ok(buf)) = &res {
+            if buf.is_empty() {
+                this.unread.store(0, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.decoder().consume(amt);
+        this.count_read(amt);
+    }
+}
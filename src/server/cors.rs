@@ -370,8 +370,8 @@ impl Cors {
                 // Simple
                 if self.is_origin_allowed(origin) {
                     // set common headers
-                    let mut headers = resp_wtr.response_mut().headers_mut();
-                    self.append_common_headers(&mut headers);
+                    let headers = resp_wtr.response_mut().headers_mut();
+                    self.append_common_headers(headers);
                     // set allowed-origin header
                     resp_wtr.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
 
@@ -438,7 +438,7 @@ pub trait IntoOrigin {
     fn into_origin(self) -> Origin;
 }
 
-impl<'a> IntoOrigin for &'a str {
+impl IntoOrigin for &str {
     fn into_origin(self) -> Origin {
         let mut parts = self.splitn(2, "://");
         let scheme = parts.next().expect("missing scheme");
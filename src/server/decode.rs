@@ -13,16 +13,20 @@ use http::{
 use std::fmt;
 use tracing::debug;
 
-use crate::body::Body;
+use crate::body::{Body, LimitedReader};
 use crate::chunked::ChunkedDecoder;
+use crate::timeout::TimeoutReader;
 use crate::Request;
 
+use super::conn::{BodyDecoder, BodySlot, ConnReader};
 use super::response_writer::InnerResponse;
 use super::error::ServerError;
+use super::ServerOpts;
 
 const LF: u8 = b'\n';
 
-const SUPPORTED_TRANSFER_ENCODING: [&[u8]; 2] = [b"chunked", b"identity"];
+// Only chunked is decoded, so any other coding would reach the handler still applied.
+const SUPPORTED_TRANSFER_CODINGS: [&str; 2] = ["chunked", "identity"];
 
 /// Decode and http request
 ///
@@ -30,20 +34,33 @@ const SUPPORTED_TRANSFER_ENCODING: [&[u8]; 2] = [b"chunked", b"identity"];
 /// are defined in this module.
 ///
 /// `None` means that no request was read.
-pub(crate) async fn decode<IO>(mut io: IO) -> Result<Option<Request>, DecodeFail>
+///
+/// `conn` reads from `io`, which is only written to directly for `100 Continue`. Once the head is
+/// read, the request body takes `conn`, and returns it to `body_slot` when dropped.
+pub(crate) async fn decode<IO>(
+    conn: &mut Option<ConnReader<IO>>,
+    mut io: IO,
+    opts: &ServerOpts,
+    body_slot: &BodySlot<IO>,
+) -> Result<Option<Request>, DecodeFail>
 where
     IO: AsyncRead + AsyncWrite + Clone + Unpin + Send + Sync + 'static,
 {
     use DecodeFail::*;
 
-    let mut reader = BufReader::new(io.clone());
+    let mut reader = conn.as_mut().expect("connection reader is returned after each request");
+
     let mut buf = Vec::new();
-    let mut headers = [httparse::EMPTY_HEADER; 16];
+    let mut headers = vec![httparse::EMPTY_HEADER; opts.max_headers];
     let mut httparse_req = httparse::Request::new(&mut headers);
 
     // Keep reading bytes from the stream until we hit the end of the head.
     loop {
-        let bytes_read = reader
+        // Never read more than one byte past the limit, so that a client can't make us buffer an
+        // endless head.
+        let limit = (opts.max_head_size + 1).saturating_sub(buf.len()) as u64;
+        let bytes_read = (&mut reader)
+            .take(limit)
             .read_until(LF, &mut buf)
             .await
             .map_err(ConnectionLost)?;
@@ -53,6 +70,15 @@ where
             return Ok(None);
         }
 
+        if buf.len() > opts.max_head_size {
+            // If the request line isn't finished yet, it's the request-target that's too long.
+            if buf.contains(&LF) {
+                return Err(HttpHeadTooLarge);
+            } else {
+                return Err(HttpUriTooLong);
+            }
+        }
+
         // We've hit the end delimiter of the head.
         let idx = buf.len() - 1;
         if idx >= 3 && &buf[idx - 3..=idx] == b"\r\n\r\n" {
@@ -61,7 +87,10 @@ where
     }
 
     // Convert head buf into an httparse instance, and validate.
-    let status = httparse_req.parse(&buf).map_err(HttpHeadParse)?;
+    let status = httparse_req.parse(&buf).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => HttpTooManyHeaders,
+        err => HttpHeadParse(err),
+    })?;
     if status.is_partial() {
         return Err(HttpMalformedHead);
     };
@@ -69,13 +98,11 @@ where
     // Check that req basics are here
     let method = http::Method::from_bytes(httparse_req.method.ok_or(HttpNoMethod)?.as_bytes())
         .map_err(HttpMethod)?;
+    // httparse only recognizes HTTP/1.0 and HTTP/1.1
     let version = if httparse_req.version.ok_or(HttpNoVersion)? == 1 {
-        //TODO keep_alive = true, is_http_11 = true
         http::Version::HTTP_11
     } else {
-        //TODO keep_alive = false, is_http_11 = false
-        //http::Version::HTTP_10
-        return Err(Http10NotSupported);
+        http::Version::HTTP_10
     };
 
     // Start with the basic request build, so we can add headers directly.
@@ -83,31 +110,50 @@ where
 
     // Now check headers for special cases (e.g. content-length, host), and append all headers
     // TODO check hyper for all the subtleties
+    //
+    // The body length follows https://tools.ietf.org/html/rfc7230#section-3.3.3 strictly, since
+    // any disagreement with a proxy about where a request ends allows request smuggling.
     let mut content_length = None;
     let mut has_host = false;
     let mut is_te = false;
+    // whether chunked is the last coding so far
     let mut is_chunked = false;
     #[allow(clippy::borrow_interior_mutable_const)] // TODO see if I can remove this later
     for header in httparse_req.headers.iter() {
         if header.name == header::CONTENT_LENGTH {
-            content_length = Some(
-                std::str::from_utf8(header.value)
-                    .map_err(|_| HttpInvalidContentLength)?
-                    .parse::<usize>()
-                    .map_err(|_| HttpInvalidContentLength)?,
-            );
+            // Duplicates, in one header or several, are only allowed if they're all the same.
+            let value = std::str::from_utf8(header.value).map_err(|_| HttpInvalidContentLength)?;
+            for len in value.split(',') {
+                let len = parse_content_length(len).ok_or(HttpInvalidContentLength)?;
+                match content_length {
+                    Some(prev) if prev != len => return Err(HttpConflictingContentLength),
+                    _ => content_length = Some(len),
+                }
+            }
         } else if header.name == header::TRANSFER_ENCODING {
-            // return error if transfer encoding not supported
-            // TODO this allocates to lowercase ascii. fix?
-            if !SUPPORTED_TRANSFER_ENCODING.contains(&header.value.to_ascii_lowercase().as_slice())
-            {
-                return Err(HttpUnsupportedTransferEncoding);
+            // HTTP/1.0 has no transfer codings, so a proxy might have ignored it, and found the
+            // end of the body some other way. https://tools.ietf.org/html/rfc7230#section-3.3.1
+            if version == http::Version::HTTP_10 {
+                return Err(HttpTransferEncodingHttp10);
+            }
+            // Codings are listed in the order they were applied, across one or more headers.
+            let value =
+                std::str::from_utf8(header.value).map_err(|_| HttpUnsupportedTransferEncoding)?;
+            for coding in value.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+                if !SUPPORTED_TRANSFER_CODINGS
+                    .iter()
+                    .any(|supported| supported.eq_ignore_ascii_case(coding))
+                {
+                    return Err(HttpUnsupportedTransferEncoding);
+                }
+                // Nothing can be applied after chunked, including chunked again.
+                if is_chunked {
+                    return Err(HttpChunkedNotFinal);
+                }
+
+                is_te = true;
+                is_chunked = coding.eq_ignore_ascii_case("chunked");
             }
-
-            is_te = true;
-            is_chunked = String::from_utf8_lossy(header.value)
-                .trim()
-                .eq_ignore_ascii_case("chunked");
         } else if header.name == header::HOST {
             has_host = true;
         }
@@ -118,44 +164,108 @@ where
         );
     }
 
-    handle_100_continue(&req, &mut io).await?;
+    // Without chunked as the final coding, the server can't tell where a request body ends.
+    if is_te && !is_chunked {
+        return Err(HttpChunkedNotFinal);
+    }
+    // A proxy might have used either one to find the end of the body.
+    if is_te && content_length.is_some() {
+        return Err(HttpContentLengthWithTransferEncoding);
+    }
+
+    // Reject an oversized body before telling the client to go ahead and send it.
+    if let (Some(len), Some(max)) = (content_length, opts.max_body_size) {
+        if len > max {
+            return Err(HttpPayloadTooLarge);
+        }
+    }
+
+    // Check the content coding before the client sends the body.
+    #[cfg(feature = "compression")]
+    let has_body = is_chunked || content_length.unwrap_or(0) > 0;
+    #[cfg(feature = "compression")]
+    let content_codings = if opts.decompress_requests && has_body {
+        let headers = req.headers_ref().expect("Request builder error");
+        super::compression::request_codings(headers).ok_or(HttpUnsupportedContentEncoding)?
+    } else {
+        Vec::new()
+    };
 
     // Now handle more complex parts of HTTP protocol
 
     // Handle path according to https://tools.ietf.org/html/rfc2616#section-5.2
     // Tophat ignores the host when determining resource identified. However, the Host header is
-    // still required.
-    if !has_host {
+    // still required for HTTP/1.1 (HTTP/1.0 predates it).
+    if version == http::Version::HTTP_11 && !has_host {
         return Err(HttpNoHost);
     }
     let path = httparse_req.path.ok_or(HttpNoPath)?;
+    if path.len() > opts.max_uri_length {
+        return Err(HttpUriTooLong);
+    }
+    let uri: http::Uri = path.parse().map_err(|_| HttpRequestBuild)?;
 
-    // Handling content-length v. transfer-encoding:
-    // TODO double-check with https://tools.ietf.org/html/rfc7230#section-3.3.3
+    // Only tell the client to send the body once the request is known to be acceptable.
+    handle_100_continue(&req, version, &mut io).await?;
+
+    // Content-length and transfer-encoding can't both be set, checked above.
     let content_length = content_length.unwrap_or(0);
 
     // Decode body as fixed_body or as chunked
-    let body = if is_te && is_chunked {
+    let reader = conn.take().expect("connection reader is returned after each request");
+    let reader = TimeoutReader::new(reader, opts.body_read_timeout, "Request body read timeout");
+    let body = if is_chunked {
         let mut body = Body::empty();
         let trailer_sender = body.send_trailers();
         let reader = BufReader::new(ChunkedDecoder::new(reader, trailer_sender));
-        body.set_inner(reader, None);
+        let max = opts.max_body_size.unwrap_or(usize::MAX);
+        let decoder = BodyDecoder::Chunked(LimitedReader::new(reader, max));
+        body.set_inner(body_slot.body_reader(decoder), None);
         body
+    } else if content_length == 0 {
+        // Nothing for the body to read, so the reader goes straight back to the connection.
+        body_slot.put(BodyDecoder::Fixed(reader.take(0)));
+        Body::empty()
     } else {
-        Body::from_reader(reader.take(content_length as u64), Some(content_length))
+        let decoder = BodyDecoder::Fixed(reader.take(content_length as u64));
+        Body::from_reader(body_slot.body_reader(decoder), Some(content_length))
+    };
+
+    #[cfg(feature = "compression")]
+    let body = if content_codings.is_empty() {
+        body
+    } else {
+        let mut body = body;
+        let max = opts.max_body_size.unwrap_or(usize::MAX);
+        super::compression::decompress(&mut body, &content_codings, max);
+
+        // The handler sees the decoded body, of unknown length.
+        let headers = req.headers_mut().expect("Request builder error");
+        headers.remove(header::CONTENT_ENCODING);
+        headers.remove(header::CONTENT_LENGTH);
+        body
     };
 
     // Finally build the rest of the req
     let req = req
         .method(method)
         .version(version)
-        .uri(path)
+        .uri(uri)
         .body(body)
         .map_err(|_| HttpRequestBuild)?;
 
     Ok(Some(req))
 }
 
+// Only digits, `usize::from_str` would also accept a leading `+`.
+fn parse_content_length(value: &str) -> Option<usize> {
+    let value = value.trim();
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
 const EXPECT_HEADER_VALUE: &[u8] = b"100-continue";
 const EXPECT_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
 
@@ -163,15 +273,26 @@ const EXPECT_RESPONSE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
 // to another way (that may/may not be better?) that requires use of `spawn`. See
 // https://tools.ietf.org/html/rfc7231#section-6.2.1 and
 // https://github.com/http-rs/async-h1/issues/135
-async fn handle_100_continue<W>(req: &Builder, wtr: &mut W) -> Result<(), DecodeFail>
+//
+// An HTTP/1.0 client can't understand a 1xx response, so it never gets one.
+async fn handle_100_continue<W>(
+    req: &Builder,
+    version: http::Version,
+    wtr: &mut W,
+) -> Result<(), DecodeFail>
     where
     W: AsyncWrite + Unpin
 {
-    let expect_header = req.headers_ref()
+    if version != http::Version::HTTP_11 {
+        return Ok(());
+    }
+
+    let expects_continue = req.headers_ref()
         .and_then(|hs| hs.get(header::EXPECT))
-        .map(|h| h.as_bytes());
+        .map(|h| h.as_bytes().eq_ignore_ascii_case(EXPECT_HEADER_VALUE))
+        .unwrap_or(false);
 
-    if let Some(EXPECT_HEADER_VALUE) = expect_header {
+    if expects_continue {
         wtr.write_all(EXPECT_RESPONSE)
             .await
             .map_err(DecodeFail::ConnectionLost)?;
@@ -184,21 +305,32 @@ async fn handle_100_continue<W>(req: &Builder, wtr: &mut W) -> Result<(), Decode
 // the `error` module.
 #[derive(Debug)]
 pub(crate) enum DecodeFail {
-    // These errors should result in a connection closure
+    // These errors are bubbled up as a `ServerError`
     ConnectionLost(std::io::Error),
     HttpMalformedHead,
     HttpUnsupportedTransferEncoding,
+    HttpHeadTooLarge,
+    HttpTooManyHeaders,
+    HttpUriTooLong,
+    HttpPayloadTooLarge,
+    // The body length is unknown or ambiguous
+    HttpInvalidContentLength,
+    HttpConflictingContentLength,
+    HttpContentLengthWithTransferEncoding,
+    HttpChunkedNotFinal,
+    HttpTransferEncodingHttp10,
+    #[cfg(feature = "compression")]
+    HttpUnsupportedContentEncoding,
 
-    // Below failures should be handled with a Response, but not with connection closure.
+    // Below failures are only handled with a Response. The connection is still closed, as the
+    // request body is never read, and would otherwise be parsed as the next request.
 
     // TODO check that these are actually errors, and not just something to handle
     HttpNoPath,
     HttpNoMethod,
     HttpNoVersion,
     HttpNoHost,
-    HttpInvalidContentLength,
     HttpRequestBuild,
-    Http10NotSupported,
 
     // conversions related to http and httparse lib
     HttpHeadParse(httparse::Error),
@@ -214,13 +346,24 @@ impl fmt::Display for DecodeFail {
             ConnectionLost(err) => write!(f, "Connection Lost: {}", err),
             HttpMalformedHead => write!(f, "Http parse malformed head"),
             HttpUnsupportedTransferEncoding => write!(f, "Http transfer encoding not supported"),
+            HttpHeadTooLarge => write!(f, "Http request head too large"),
+            HttpUriTooLong => write!(f, "Http request-target too long"),
+            HttpPayloadTooLarge => write!(f, "Http content length over the body size limit"),
+            HttpInvalidContentLength => write!(f, "Http invalid content length"),
+            HttpConflictingContentLength => write!(f, "Http conflicting content lengths"),
+            HttpContentLengthWithTransferEncoding => {
+                write!(f, "Http both content length and transfer encoding")
+            }
+            HttpChunkedNotFinal => write!(f, "Http chunked is not the final transfer coding"),
+            HttpTransferEncodingHttp10 => write!(f, "Http transfer encoding in an HTTP/1.0 request"),
+            #[cfg(feature = "compression")]
+            HttpUnsupportedContentEncoding => write!(f, "Http content encoding not supported"),
             HttpNoPath => write!(f, "Http no path found"),
             HttpNoMethod => write!(f, "Http no method found"),
             HttpNoVersion => write!(f, "Http no version found"),
             HttpNoHost => write!(f, "Http no host found"),
-            HttpInvalidContentLength => write!(f, "Http invalid content length"),
             HttpRequestBuild => write!(f, "Http request could not be built"),
-            Http10NotSupported => write!(f, "Http version 1.0 not supported"),
+            HttpTooManyHeaders => write!(f, "Http too many headers"),
             HttpHeadParse(err) => write!(f, "Http header parsing error: {}", err),
             HttpMethod(err) => write!(f, "Http Method error: {}", err),
             HttpHeaderName(err) => write!(f, "Http Header name error: {}", err),
@@ -238,7 +381,13 @@ pub(crate) fn fail_to_response_and_log(fail: &DecodeFail) -> Option<InnerRespons
     match fail {
         ConnectionLost(_) => None,
         HttpUnsupportedTransferEncoding => Some(InnerResponse::not_implemented()),
-        Http10NotSupported => Some(InnerResponse::version_not_supported()),
+        HttpHeadTooLarge | HttpTooManyHeaders => {
+            Some(InnerResponse::request_header_fields_too_large())
+        }
+        HttpUriTooLong => Some(InnerResponse::uri_too_long()),
+        HttpPayloadTooLarge => Some(InnerResponse::payload_too_large()),
+        #[cfg(feature = "compression")]
+        HttpUnsupportedContentEncoding => Some(InnerResponse::unsupported_media_type()),
         _ => Some(InnerResponse::bad_request()),
     }
 }
@@ -252,6 +401,23 @@ pub(crate) fn fail_to_crate_err(fail: DecodeFail) -> Option<ServerError> {
     match fail {
         //ConnectionLost(err) => Some(Error::ConnectionLost(err)),
         HttpUnsupportedTransferEncoding => Some(ServerError::ConnectionClosedUnsupportedTransferEncoding),
+        // The rest of the head is still unread, or the body is
+        HttpHeadTooLarge | HttpUriTooLong | HttpTooManyHeaders => {
+            Some(ServerError::ConnectionClosedHeadTooLarge)
+        }
+        // The body is still unread
+        HttpPayloadTooLarge => Some(ServerError::ConnectionClosedPayloadTooLarge),
+        // The next request can't be found
+        HttpInvalidContentLength
+        | HttpConflictingContentLength
+        | HttpContentLengthWithTransferEncoding
+        | HttpChunkedNotFinal
+        | HttpTransferEncodingHttp10 => Some(ServerError::ConnectionClosedInvalidBodyLength),
+        // The body is still unread
+        #[cfg(feature = "compression")]
+        HttpUnsupportedContentEncoding => {
+            Some(ServerError::ConnectionClosedUnsupportedContentEncoding)
+        }
         _ => None,
     }
 }
@@ -267,7 +433,7 @@ mod test {
         let req = http::request::Builder::new();
         let mut io = Cursor::new(Vec::new());
         smol::block_on(async {
-            let result = handle_100_continue(&req, &mut io).await;
+            let result = handle_100_continue(&req, http::Version::HTTP_11, &mut io).await;
             assert_eq!(
                 std::str::from_utf8(&io.into_inner()).unwrap(),
                 "",
@@ -286,7 +452,26 @@ mod test {
         );
         let mut io = Cursor::new(Vec::new());
         smol::block_on(async {
-            let result = handle_100_continue(&req, &mut io).await;
+            let result = handle_100_continue(&req, http::Version::HTTP_11, &mut io).await;
+            assert_eq!(
+                std::str::from_utf8(&io.into_inner()).unwrap(),
+                "HTTP/1.1 100 Continue\r\n\r\n",
+            );
+
+            assert!(result.is_ok())
+        });
+    }
+
+    #[test]
+    fn test_handle_100_continue_ignores_case() {
+        let mut req = http::request::Builder::new();
+        req.headers_mut().expect("Request builder error").append(
+            HeaderName::from_bytes(b"expect").unwrap(),
+            HeaderValue::from_bytes(b"100-Continue").unwrap(),
+        );
+        let mut io = Cursor::new(Vec::new());
+        smol::block_on(async {
+            let result = handle_100_continue(&req, http::Version::HTTP_11, &mut io).await;
             assert_eq!(
                 std::str::from_utf8(&io.into_inner()).unwrap(),
                 "HTTP/1.1 100 Continue\r\n\r\n",
@@ -296,6 +481,25 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_handle_100_continue_does_nothing_for_http10() {
+        let mut req = http::request::Builder::new();
+        req.headers_mut().expect("Request builder error").append(
+            HeaderName::from_bytes(b"expect").unwrap(),
+            HeaderValue::from_bytes(b"100-continue").unwrap(),
+        );
+        let mut io = Cursor::new(Vec::new());
+        smol::block_on(async {
+            let result = handle_100_continue(&req, http::Version::HTTP_10, &mut io).await;
+            assert_eq!(
+                std::str::from_utf8(&io.into_inner()).unwrap(),
+                "",
+            );
+
+            assert!(result.is_ok())
+        });
+    }
+
     #[test]
     fn test_handle_100_continue_sends_header_if_expects_is_wrong() {
         let mut req = http::request::Builder::new();
@@ -305,7 +509,7 @@ mod test {
         );
         let mut io = Cursor::new(Vec::new());
         smol::block_on(async {
-            let result = handle_100_continue(&req, &mut io).await;
+            let result = handle_100_continue(&req, http::Version::HTTP_11, &mut io).await;
             assert_eq!(
                 std::str::from_utf8(&io.into_inner()).unwrap(),
                 "",
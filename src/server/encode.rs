@@ -6,15 +6,16 @@
 // must have mucked up what the stream was reading back out.
 
 use futures_lite::AsyncRead;
-use http::header;
+use http::{header, Method, StatusCode, Version};
 use httpdate::fmt_http_date;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use tracing::trace;
 
 use crate::chunked::ChunkedEncoder;
+use crate::util::header_has_token;
 
-use super::response_writer::InnerResponse;
+use super::response_writer::{InnerResponse, RequestInfo};
 
 pub(crate) struct Encoder {
     resp: InnerResponse,
@@ -31,11 +32,46 @@ pub(crate) struct Encoder {
     body_bytes_read: usize,
 
     chunked: ChunkedEncoder,
+
+    // HTTP/1.0 has no chunked encoding, so a body of unknown length is delimited by closing the
+    // connection.
+    http10: bool,
+    keep_alive: bool,
+
+    // See https://tools.ietf.org/html/rfc7230#section-3.3
+    // A response to HEAD has the headers it would have for GET, but no body.
+    has_body: bool,
+    // 1xx, 204, and 304 responses have neither a body nor framing headers.
+    has_framing: bool,
+
+    // The body is written separately, after the head. See `BodyWriter`.
+    head_only: bool,
+}
+
+/// How a body written after the head is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BodyFraming {
+    /// No body is sent, e.g. for HEAD.
+    None,
+    Chunked,
+    /// HTTP/1.0, the connection is closed after the body.
+    CloseDelimited,
 }
 
 impl Encoder {
-    pub(crate) fn encode(resp: InnerResponse) -> Self {
+    pub(crate) fn encode(resp: InnerResponse, req_info: &RequestInfo) -> Self {
         let content_length = resp.body.length;
+        let http10 = req_info.version == Version::HTTP_10 || resp.version == Version::HTTP_10;
+        let status = resp.status;
+        let has_framing = !(status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED);
+        let has_body = has_framing && req_info.method != Method::HEAD;
+        // After a 101, the connection belongs to another protocol.
+        let keep_alive = req_info.keep_alive()
+            && status != StatusCode::SWITCHING_PROTOCOLS
+            && !header_has_token(&resp.headers, header::CONNECTION, "close")
+            && !(http10 && has_body && content_length.is_none());
 
         Self {
             resp,
@@ -45,13 +81,46 @@ impl Encoder {
             head_bytes_read: 0,
             content_length,
             body_bytes_read: 0,
-            chunked: ChunkedEncoder::new(),
+            chunked: ChunkedEncoder::new(req_info.trailers_timeout),
+            http10,
+            keep_alive,
+            has_body,
+            has_framing,
+            head_only: false,
+        }
+    }
+
+    /// Only encode the head. The body is ignored, and framed as if its length is unknown.
+    pub(crate) fn head_only(mut self) -> Self {
+        self.content_length = None;
+        self.keep_alive = self.keep_alive && !(self.http10 && self.has_body);
+        self.head_only = true;
+        self
+    }
+
+    /// How the body which follows a head-only encoding must be framed.
+    pub(crate) fn body_framing(&self) -> BodyFraming {
+        if !self.has_body {
+            BodyFraming::None
+        } else if self.http10 {
+            BodyFraming::CloseDelimited
+        } else {
+            BodyFraming::Chunked
         }
     }
 
+    /// Whether the connection can be reused after this response.
+    pub(crate) fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
     /// At start, prep headers for writing
     fn start(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
-        let version = self.resp.version;
+        let version = if self.http10 {
+            Version::HTTP_10
+        } else {
+            self.resp.version
+        };
         let status = self.resp.status;
         let date = if !self.resp.headers.contains_key(header::DATE) {
             Some(fmt_http_date(std::time::SystemTime::now()))
@@ -60,36 +129,65 @@ impl Encoder {
         };
         // if there's a body, and if no content-type header, set as application/octet-stream as default
         #[allow(clippy::collapsible_if)]
-        if self.content_length.is_none() || matches!(self.content_length, Some(x) if x > 0) {
+        if self.has_framing
+            && (self.content_length.is_none() || matches!(self.content_length, Some(x) if x > 0))
+        {
             if !self.resp.headers.contains_key(header::CONTENT_TYPE) {
                 self.resp.headers.insert(header::CONTENT_TYPE, "application/octet-stream".parse().unwrap());
             }
         }
+        // Declare the trailers which will follow a chunked body.
+        let chunked = self.has_framing && self.content_length.is_none() && !self.http10;
+        let trailer_names = &self.resp.body.trailer_names;
+        if chunked && !trailer_names.is_empty() && !self.resp.headers.contains_key(header::TRAILER) {
+            let names: Vec<_> = trailer_names.iter().map(|name| name.as_str()).collect();
+            if let Ok(value) = names.join(", ").parse() {
+                self.resp.headers.insert(header::TRAILER, value);
+            }
+        }
+        // When closing, the `connection: close` written below replaces any user-set value. An
+        // upgrade sets its own `connection: upgrade`.
+        let upgrade = status == StatusCode::SWITCHING_PROTOCOLS;
+        let keep_alive = self.keep_alive || upgrade;
+        let has_keep_alive = header_has_token(&self.resp.headers, header::CONNECTION, "keep-alive");
         let headers = self
             .resp
             .headers
             .iter()
             .filter(|(h, _)| **h != header::CONTENT_LENGTH)
-            .filter(|(h, _)| **h != header::TRANSFER_ENCODING);
+            .filter(|(h, _)| **h != header::TRANSFER_ENCODING)
+            .filter(|(h, _)| keep_alive || **h != header::CONNECTION);
 
         std::io::Write::write_fmt(
             &mut self.head_buf,
             format_args!("{:?} {}\r\n", version, status),
         )?;
-        if let Some(len) = self.content_length {
-            std::io::Write::write_fmt(
-                &mut self.head_buf,
-                format_args!("content-length: {}\r\n", len),
-            )?;
-        } else {
-            std::io::Write::write_fmt(
-                &mut self.head_buf,
-                format_args!("transfer-encoding: chunked\r\n"),
-            )?;
+        if self.has_framing {
+            if let Some(len) = self.content_length {
+                std::io::Write::write_fmt(
+                    &mut self.head_buf,
+                    format_args!("content-length: {}\r\n", len),
+                )?;
+            } else if !self.http10 {
+                std::io::Write::write_fmt(
+                    &mut self.head_buf,
+                    format_args!("transfer-encoding: chunked\r\n"),
+                )?;
+            }
         }
         if let Some(date) = date {
             std::io::Write::write_fmt(&mut self.head_buf, format_args!("date: {}\r\n", date))?;
         }
+        // Always tell the client when the connection is closing. HTTP/1.0 connections close by
+        // default, so persistence must be confirmed explicitly.
+        if !keep_alive {
+            std::io::Write::write_fmt(&mut self.head_buf, format_args!("connection: close\r\n"))?;
+        } else if self.http10 && !has_keep_alive {
+            std::io::Write::write_fmt(
+                &mut self.head_buf,
+                format_args!("connection: keep-alive\r\n"),
+            )?;
+        }
         for (header, value) in headers {
             // write broken up, because value may contain opaque bytes.
             std::io::Write::write_fmt(&mut self.head_buf, format_args!("{}: ", header))?;
@@ -121,11 +219,21 @@ impl Encoder {
         // if entire head_buf is read, continue to body encoding, else keep state and return
         // Poll::Ready for this iteration
         if self.head_bytes_read == self.head_buf.len() {
+            if !self.has_body || self.head_only {
+                self.state = EncoderState::Done;
+                return Poll::Ready(Ok(self.bytes_read));
+            }
+
             match self.content_length {
                 Some(_) => {
                     self.state = EncoderState::FixedBody;
                     self.encode_fixed_body(cx, buf)
                 }
+                None if self.http10 => {
+                    self.state = EncoderState::CloseDelimitedBody;
+                    trace!("Server response encoding: close-delimited body");
+                    self.encode_close_delimited_body(cx, buf)
+                }
                 None => {
                     self.state = EncoderState::ChunkedBody;
                     trace!("Server response encoding: chunked body");
@@ -184,6 +292,36 @@ impl Encoder {
         }
     }
 
+    /// Send the body as-is; the end of the body is signaled by closing the connection. Only used
+    /// for HTTP/1.0 responses whose length is not known up front.
+    fn encode_close_delimited_body(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        // first check that there's more room in buffer
+        if self.bytes_read == buf.len() {
+            return Poll::Ready(Ok(self.bytes_read));
+        }
+
+        let range = self.bytes_read..buf.len();
+        match Pin::new(&mut self.resp.body).poll_read(cx, &mut buf[range]) {
+            Poll::Ready(Ok(0)) => {
+                self.state = EncoderState::Done;
+                Poll::Ready(Ok(self.bytes_read))
+            }
+            Poll::Ready(Ok(n)) => {
+                self.bytes_read += n;
+                Poll::Ready(Ok(self.bytes_read))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => match self.bytes_read {
+                0 => Poll::Pending,
+                n => Poll::Ready(Ok(n)),
+            },
+        }
+    }
+
     /// Encode an AsyncBufRead using "chunked" framing. This is used for streams
     /// whose length is not known up front.
     fn encode_chunked_body(
@@ -226,6 +364,7 @@ impl AsyncRead for Encoder {
             Head => self.encode_head(cx, buf),
             FixedBody => self.encode_fixed_body(cx, buf),
             ChunkedBody => self.encode_chunked_body(cx, buf),
+            CloseDelimitedBody => self.encode_close_delimited_body(cx, buf),
             Done => Poll::Ready(Ok(0)),
         }
     }
@@ -237,5 +376,6 @@ enum EncoderState {
     Head,
     FixedBody,
     ChunkedBody,
+    CloseDelimitedBody,
     Done,
 }
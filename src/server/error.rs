@@ -11,6 +11,20 @@ pub enum ServerError {
     /// Error because tophat does not support the transfer encoding.
     ConnectionClosedUnsupportedTransferEncoding,
 
+    /// Error because the request head went over the limits set in `ServerOpts`.
+    ConnectionClosedHeadTooLarge,
+
+    /// Error because the request content-length went over the limit set in `ServerOpts`.
+    ConnectionClosedPayloadTooLarge,
+
+    /// Error because the request body length was invalid or ambiguous, so the end of the request
+    /// can't be found. See https://tools.ietf.org/html/rfc7230#section-3.3.3
+    ConnectionClosedInvalidBodyLength,
+
+    /// Error because tophat can't decompress the request body's content encoding, with
+    /// `ServerOpts::decompress_requests` set.
+    ConnectionClosedUnsupportedContentEncoding,
+
     /// Connection lost
     ConnectionLost(std::io::Error),
 }
@@ -20,6 +34,10 @@ impl std::error::Error for ServerError {
         use ServerError::*;
         match self {
             ConnectionClosedUnsupportedTransferEncoding => None,
+            ConnectionClosedHeadTooLarge => None,
+            ConnectionClosedPayloadTooLarge => None,
+            ConnectionClosedInvalidBodyLength => None,
+            ConnectionClosedUnsupportedContentEncoding => None,
             ConnectionLost(err) => Some(err),
         }
     }
@@ -32,6 +50,16 @@ impl fmt::Display for ServerError {
             ConnectionClosedUnsupportedTransferEncoding => {
                 write!(f, "Connection closed: Unsupported Transfer Encoding")
             }
+            ConnectionClosedHeadTooLarge => write!(f, "Connection closed: Request head too large"),
+            ConnectionClosedPayloadTooLarge => {
+                write!(f, "Connection closed: Request payload too large")
+            }
+            ConnectionClosedInvalidBodyLength => {
+                write!(f, "Connection closed: Request body length invalid or ambiguous")
+            }
+            ConnectionClosedUnsupportedContentEncoding => {
+                write!(f, "Connection closed: Unsupported Content Encoding")
+            }
             ConnectionLost(err) => write!(f, "Connection lost: {}", err),
         }
     }
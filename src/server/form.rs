@@ -0,0 +1,520 @@
+//! Form module
+//!
+//! Deserialize query strings and `application/x-www-form-urlencoded` bodies with serde:
+//!
+//! ```rust,no_run
+//! # use futures_util::io::{AsyncRead, AsyncWrite};
+//! # use tophat::{Request, server::{glitch::Result, ResponseWriter, ResponseWritten}};
+//! use serde::Deserialize;
+//! use tophat::server::form::FormRequestExt;
+//!
+//! #[derive(Deserialize)]
+//! struct Search {
+//!     q: String,
+//!     page: Option<u32>,
+//!     #[serde(default)]
+//!     tag: Vec<String>,
+//! }
+//!
+//! async fn search<W>(req: Request, resp_wtr: ResponseWriter<W>) -> Result<ResponseWritten>
+//!     where W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+//! {
+//!     // e.g. `/search?q=top+hat&tag=a&tag=b`
+//!     let search: Search = req.query()?;
+//!     # let _ = search;
+//!     resp_wtr.send().await
+//! }
+//! ```
+//!
+//! Keys and values are percent-decoded (and `+` is a space). A key repeated in the input can be
+//! deserialized into a `Vec` (or other sequence); for a single value, the last one is used. A
+//! missing key is `None` for an `Option`; for an empty `Vec`, use `#[serde(default)]`.
+//!
+//! Only flat structs (or maps) of strings, numbers, bools, unit enums and sequences of those are
+//! supported, as that's all a form can hold.
+//!
+//! A `FormError` converts into a 400 `Glitch` (413 for a body over the size limit), with a message
+//! naming the field which failed.
+
+use futures_lite::AsyncReadExt;
+use http::StatusCode;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::body::{error::BodyError, Body, LimitedReader};
+use crate::request::Request;
+use crate::server::glitch::Glitch;
+use crate::util::percent_decode;
+
+/// Default size limit for `Body::into_form`, in bytes.
+pub const DEFAULT_FORM_LIMIT: usize = 1024 * 1024;
+
+/// Trait for a convenience method on a Request, to deserialize the query string.
+pub trait FormRequestExt {
+    /// Deserialize the query string. No query string is the same as an empty one.
+    fn query<T: DeserializeOwned>(&self) -> Result<T, FormError>;
+}
+
+impl FormRequestExt for Request {
+    fn query<T: DeserializeOwned>(&self) -> Result<T, FormError> {
+        from_bytes(self.uri().query().unwrap_or("").as_bytes())
+    }
+}
+
+impl Body {
+    /// Read an `application/x-www-form-urlencoded` body and deserialize it, up to
+    /// `DEFAULT_FORM_LIMIT` bytes. Consumes Body.
+    pub async fn into_form<T: DeserializeOwned>(self) -> Result<T, FormError> {
+        self.into_form_with_limit(DEFAULT_FORM_LIMIT).await
+    }
+
+    /// Read an `application/x-www-form-urlencoded` body and deserialize it, up to `limit` bytes.
+    /// Consumes Body.
+    pub async fn into_form_with_limit<T: DeserializeOwned>(
+        self,
+        limit: usize,
+    ) -> Result<T, FormError> {
+        let mut buf = Vec::new();
+        LimitedReader::new(self, limit)
+            .read_to_end(&mut buf)
+            .await
+            .map_err(|err| FormError::Body(err.into()))?;
+        from_bytes(&buf)
+    }
+}
+
+/// Deserialize `application/x-www-form-urlencoded` bytes, e.g. a query string.
+pub fn from_bytes<T: DeserializeOwned>(input: &[u8]) -> Result<T, FormError> {
+    let fields = parse(input)?;
+    T::deserialize(FormDeserializer { fields }).map_err(|err| FormError::Invalid {
+        field: err.field,
+        message: err.message,
+    })
+}
+
+/// Error deserializing a form or query string.
+///
+/// Converts into a `Glitch`.
+#[derive(Debug)]
+pub enum FormError {
+    /// Reading the body failed, or it was over the size limit.
+    Body(BodyError),
+    /// The input couldn't be deserialized.
+    Invalid {
+        /// The field which failed, if known
+        field: Option<String>,
+        /// What went wrong
+        message: String,
+    },
+}
+
+impl FormError {
+    /// The response status for this error: 413 for a body over the size limit, otherwise 400.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FormError::Body(BodyError::TooLarge(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::Body(err) => write!(f, "Error reading form: {}", err),
+            FormError::Invalid {
+                field: Some(field),
+                message,
+            } => write!(f, "Invalid form field `{}`: {}", field, message),
+            FormError::Invalid {
+                field: None,
+                message,
+            } => write!(f, "Invalid form: {}", message),
+        }
+    }
+}
+
+// Not a `std::error::Error`, so that this can be a 400 instead of the 500 from the blanket impl.
+impl From<FormError> for Glitch {
+    fn from(err: FormError) -> Self {
+        Glitch::new_with_status_context(err.status(), err.to_string())
+    }
+}
+
+// Fields in order of first appearance, with all their values.
+type Fields = Vec<(String, Vec<String>)>;
+
+fn parse(input: &[u8]) -> Result<Fields, FormError> {
+    let mut fields: Fields = Vec::new();
+    // Position of each key in `fields`, so that many keys aren't quadratic
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for pair in input.split(|b| *b == b'&').filter(|pair| !pair.is_empty()) {
+        let mut split = pair.splitn(2, |b| *b == b'=');
+        let key = split.next().unwrap_or(b"");
+        let value = split.next().unwrap_or(b"");
+
+        let key = decode(key).ok_or_else(|| FormError::Invalid {
+            field: None,
+            message: "invalid percent-encoding in key".into(),
+        })?;
+        let value = decode(value).ok_or_else(|| FormError::Invalid {
+            field: Some(key.clone()),
+            message: "invalid percent-encoding".into(),
+        })?;
+
+        match positions.get(&key) {
+            Some(i) => fields[*i].1.push(value),
+            None => {
+                positions.insert(key.clone(), fields.len());
+                fields.push((key, vec![value]));
+            }
+        }
+    }
+
+    Ok(fields)
+}
+
+// `+` is a space, then percent-decode; must be utf-8.
+fn decode(s: &[u8]) -> Option<String> {
+    let s = std::str::from_utf8(s).ok()?.replace('+', " ");
+    String::from_utf8(percent_decode(&s)?).ok()
+}
+
+/// Error from the deserializers, before it's turned into a `FormError`.
+#[derive(Debug)]
+struct DeError {
+    field: Option<String>,
+    message: String,
+}
+
+impl DeError {
+    fn in_field(mut self, field: &str) -> Self {
+        if self.field.is_none() {
+            self.field = Some(field.to_owned());
+        }
+        self
+    }
+}
+
+impl fmt::Display for DeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for DeError {}
+
+impl de::Error for DeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        DeError {
+            field: None,
+            message: msg.to_string(),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        DeError {
+            field: Some(field.to_owned()),
+            message: "missing".into(),
+        }
+    }
+
+    fn unknown_field(field: &str, _expected: &'static [&'static str]) -> Self {
+        DeError {
+            field: Some(field.to_owned()),
+            message: "unknown field".into(),
+        }
+    }
+}
+
+/// The whole form, as a map
+struct FormDeserializer {
+    fields: Fields,
+}
+
+impl<'de> de::Deserializer<'de> for FormDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(FieldsAccess {
+            fields: self.fields.into_iter(),
+            current: None,
+        })
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier
+        ignored_any
+    }
+}
+
+struct FieldsAccess {
+    fields: std::vec::IntoIter<(String, Vec<String>)>,
+    // The field whose values are next
+    current: Option<(String, Vec<String>)>,
+}
+
+impl<'de> de::MapAccess<'de> for FieldsAccess {
+    type Error = DeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, DeError> {
+        match self.fields.next() {
+            Some((key, values)) => {
+                let de = key.clone().into_deserializer();
+                self.current = Some((key, values));
+                seed.deserialize(de).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, DeError> {
+        let (key, values) = self
+            .current
+            .take()
+            .ok_or_else(|| de::Error::custom("value without a key"))?;
+        seed.deserialize(ValuesDeserializer { values })
+            .map_err(|err| err.in_field(&key))
+    }
+}
+
+/// All the values of one field
+struct ValuesDeserializer {
+    values: Vec<String>,
+}
+
+impl ValuesDeserializer {
+    fn last(mut self) -> ValueDeserializer {
+        ValueDeserializer(self.values.pop().unwrap_or_default())
+    }
+}
+
+macro_rules! forward_to_last {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                self.last().$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ValuesDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.last().deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        let values = self.values.into_iter().map(ValueDeserializer);
+        visitor.visit_seq(de::value::SeqDeserializer::new(values))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.last().deserialize_enum(name, variants, visitor)
+    }
+
+    forward_to_last! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_i128 deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+        deserialize_u128 deserialize_f32 deserialize_f64 deserialize_char deserialize_str
+        deserialize_string deserialize_bytes deserialize_byte_buf deserialize_unit
+        deserialize_identifier deserialize_ignored_any
+    }
+
+    serde::forward_to_deserialize_any! {
+        unit_struct tuple_struct map struct
+    }
+}
+
+/// One value
+struct ValueDeserializer(String);
+
+impl ValueDeserializer {
+    fn parse<T>(&self) -> Result<T, DeError>
+    where
+        T: std::str::FromStr,
+        T::Err: fmt::Display,
+    {
+        self.0.parse().map_err(de::Error::custom)
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_string(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    serde::forward_to_deserialize_any! {
+        str string bytes byte_buf unit_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for ValueDeserializer {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Sort {
+        Asc,
+        Desc,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Search {
+        q: String,
+        page: Option<u32>,
+        #[serde(default)]
+        tag: Vec<String>,
+        sort: Option<Sort>,
+        exact: Option<bool>,
+    }
+
+    fn invalid(err: FormError) -> (Option<String>, String) {
+        match err {
+            FormError::Invalid { field, message } => (field, message),
+            err => panic!("{:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let search: Search = from_bytes(b"q=top+hat%21&tag=a&page=2&tag=b%26c&sort=desc").unwrap();
+        assert_eq!(
+            search,
+            Search {
+                q: "top hat!".into(),
+                page: Some(2),
+                tag: vec!["a".into(), "b&c".into()],
+                sort: Some(Sort::Desc),
+                exact: None,
+            }
+        );
+
+        // last value wins when a single value is expected
+        let search: Search = from_bytes(b"q=a&q=b&exact=true&&").unwrap();
+        assert_eq!(search.q, "b");
+        assert_eq!(search.exact, Some(true));
+        assert!(search.tag.is_empty());
+
+        let map: HashMap<String, String> = from_bytes(b"a=1&b=").unwrap();
+        assert_eq!(map["a"], "1");
+        assert_eq!(map["b"], "");
+    }
+
+    #[test]
+    fn test_from_bytes_errors() {
+        let (field, message) = invalid(from_bytes::<Search>(b"page=2").unwrap_err());
+        assert_eq!((field.as_deref(), message.as_str()), (Some("q"), "missing"));
+
+        let (field, _) = invalid(from_bytes::<Search>(b"q=a&page=two").unwrap_err());
+        assert_eq!(field.as_deref(), Some("page"));
+
+        let (field, _) = invalid(from_bytes::<Search>(b"q=a&sort=up").unwrap_err());
+        assert_eq!(field.as_deref(), Some("sort"));
+
+        let err = from_bytes::<Search>(b"q=%zz").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid form field `q`: invalid percent-encoding");
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,493 @@
+//! Serving files from a directory
+//!
+//! `ServeDir` serves the files under a root directory. With the router, mount it at a path ending
+//! in a catch-all param, which holds the path of the file to serve:
+//!
+//! ```ignore
+//! let router = Router::build()
+//!     .at(Method::GET, "/static/*path", ServeDir::new("./public", SmolFs))
+//!     .at(Method::HEAD, "/static/*path", ServeDir::new("./public", SmolFs))
+//!     .finish();
+//! ```
+//!
+//! Without the router, call `ServeDir::serve` with the path from the request.
+//!
+//! tophat doesn't pick an async runtime, so files are read through the `FileSystem` trait, which
+//! is a few lines to implement with e.g. `smol::fs` or `tokio::fs` (see the `serve_dir` and
+//! `tokio-tophat` examples).
+//!
+//! Paths are percent-decoded and normalized, and a path that would leave the root (`..`) is not
+//! found. Any error reading a file's metadata or opening it (e.g. permissions) is also not found.
+//! Symlinks inside the root are followed, wherever they point.
+//!
+//! Hidden files and directories, whose name starts with `.` (e.g. `.git` or `.env`), are not found
+//! and left out of listings, unless `ServeDir::serve_hidden` is set.
+//!
+//! For a file, the response has:
+//! - `Content-Type` guessed from the file extension, or `application/octet-stream`.
+//! - `Content-Length` from the file metadata.
+//! - `Last-Modified` from the file's modified time, and an `ETag` from that and the size. So
+//!   conditional requests get `304 Not Modified` (see the `conditional` module).
+//! - `Accept-Ranges: bytes`, and a GET with a `Range` gets partial content (see the `range`
+//!   module).
+//!
+//! For a directory, a request path without a trailing slash is redirected to one with it (so that
+//! relative links work), then the index file (`index.html` by default) is served. Without an
+//! index file, a simple html listing is served if enabled, otherwise it's not found.
+
+use futures_lite::{AsyncRead, AsyncSeek};
+use http::{header, HeaderValue, Method, StatusCode};
+use httpdate::fmt_http_date;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::SystemTime;
+use tracing::debug;
+
+use crate::server::{ResponseWriter, ResponseWritten, Result};
+use crate::util::percent_decode;
+use crate::Request;
+
+/// A boxed future, returned from `FileSystem` methods.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Async access to files, so that `ServeDir` works with any runtime.
+///
+/// e.g. with `smol`:
+///
+/// ```ignore
+/// struct SmolFs;
+///
+/// impl FileSystem for SmolFs {
+///     type File = smol::fs::File;
+///
+///     fn open<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Self::File>> {
+///         Box::pin(smol::fs::File::open(path))
+///     }
+///
+///     fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Metadata>> {
+///         Box::pin(async move { smol::fs::metadata(path).await.map(Metadata::from) })
+///     }
+///
+///     fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<DirEntry>>> {
+///         Box::pin(async move {
+///             let mut entries = Vec::new();
+///             let mut dir = smol::fs::read_dir(path).await?;
+///             while let Some(entry) = dir.try_next().await? {
+///                 entries.push(DirEntry {
+///                     name: entry.file_name().to_string_lossy().into_owned(),
+///                     is_dir: entry.file_type().await?.is_dir(),
+///                 });
+///             }
+///             Ok(entries)
+///         })
+///     }
+/// }
+/// ```
+pub trait FileSystem: Send + Sync + 'static {
+    /// An open file, for reading. Seeking is used for range requests.
+    type File: AsyncRead + AsyncSeek + Unpin + Send + Sync + 'static;
+
+    /// Open the file at `path` for reading.
+    fn open<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Self::File>>;
+
+    /// Metadata of the file or directory at `path`, following symlinks.
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Metadata>>;
+
+    /// Entries of the directory at `path`, in any order. Only used for directory listings.
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<DirEntry>>>;
+}
+
+/// What `ServeDir` needs to know about a file or directory.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    /// Size of the file in bytes.
+    pub len: u64,
+    /// Whether it's a directory.
+    pub is_dir: bool,
+    /// Last modification time, if available.
+    pub modified: Option<SystemTime>,
+}
+
+impl From<std::fs::Metadata> for Metadata {
+    fn from(metadata: std::fs::Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            is_dir: metadata.is_dir(),
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+/// An entry in a directory listing.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// File name, without the directory.
+    pub name: String,
+    /// Whether it's a directory.
+    pub is_dir: bool,
+}
+
+/// Endpoint for serving the files under a directory. See the module docs.
+pub struct ServeDir<F> {
+    root: PathBuf,
+    fs: F,
+    index_file: Option<String>,
+    list_directories: bool,
+    serve_hidden: bool,
+    #[cfg(feature = "router")]
+    param: String,
+}
+
+impl<F: FileSystem> ServeDir<F> {
+    /// Serve the files under `root`, read through `fs`.
+    pub fn new(root: impl Into<PathBuf>, fs: F) -> Self {
+        Self {
+            root: root.into(),
+            fs,
+            index_file: Some("index.html".to_owned()),
+            list_directories: false,
+            serve_hidden: false,
+            #[cfg(feature = "router")]
+            param: "path".to_owned(),
+        }
+    }
+
+    /// File served for a directory, if it exists. `None` to never serve one.
+    ///
+    /// Default `index.html`.
+    pub fn index_file(mut self, index_file: Option<&str>) -> Self {
+        self.index_file = index_file.map(|s| s.to_owned());
+        self
+    }
+
+    /// Serve an html listing for a directory without an index file.
+    ///
+    /// Default `false`.
+    pub fn list_directories(mut self, list_directories: bool) -> Self {
+        self.list_directories = list_directories;
+        self
+    }
+
+    /// Serve (and list) files and directories whose name starts with `.`.
+    ///
+    /// Default `false`.
+    pub fn serve_hidden(mut self, serve_hidden: bool) -> Self {
+        self.serve_hidden = serve_hidden;
+        self
+    }
+
+    /// Name of the router's catch-all param which holds the file path, e.g. `file` for
+    /// `/static/*file`.
+    ///
+    /// Default `path`.
+    #[cfg(feature = "router")]
+    pub fn param(mut self, param: &str) -> Self {
+        self.param = param.to_owned();
+        self
+    }
+
+    /// Respond with the file at `path` (still percent-encoded, as in the request uri) under the
+    /// root directory.
+    ///
+    /// Responds 404 if there's no such file, and 405 to methods other than GET and HEAD.
+    pub async fn serve<W>(
+        &self,
+        path: &str,
+        req: &Request,
+        mut resp_wtr: ResponseWriter<W>,
+    ) -> Result<ResponseWritten>
+    where
+        W: futures_lite::AsyncRead
+            + futures_lite::AsyncWrite
+            + Clone
+            + Send
+            + Sync
+            + Unpin
+            + 'static,
+    {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            resp_wtr.set_status(StatusCode::METHOD_NOT_ALLOWED);
+            resp_wtr.insert_header(header::ALLOW, HeaderValue::from_static("GET, HEAD"));
+            return resp_wtr.send().await;
+        }
+
+        let file_path = match normalize_path(&self.root, path, self.serve_hidden) {
+            Some(file_path) => file_path,
+            None => return resp_wtr.send_code(404).await,
+        };
+
+        let metadata = match self.fs.metadata(&file_path).await {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                debug!("ServeDir metadata for {:?}: {}", file_path, err);
+                return resp_wtr.send_code(404).await;
+            }
+        };
+
+        if !metadata.is_dir {
+            return self.serve_file(&file_path, metadata, req, resp_wtr).await;
+        }
+
+        // Relative links in a directory's page need the trailing slash.
+        let uri_path = req.uri().path();
+        if !uri_path.ends_with('/') {
+            // Relative to the request path, as a path like `//evil.example/dir` would otherwise be
+            // an off-site redirect. The `./` keeps a segment like `a:b` from reading as a scheme.
+            let segment = uri_path.rsplit('/').next().unwrap_or_default();
+            let mut location = format!("./{}/", segment);
+            if let Some(query) = req.uri().query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            resp_wtr.set_status(StatusCode::MOVED_PERMANENTLY);
+            resp_wtr.insert_header(header::LOCATION, location.parse()?);
+            return resp_wtr.send().await;
+        }
+
+        if let Some(index_file) = &self.index_file {
+            let index_path = file_path.join(index_file);
+            match self.fs.metadata(&index_path).await {
+                Ok(metadata) if !metadata.is_dir => {
+                    return self.serve_file(&index_path, metadata, req, resp_wtr).await;
+                }
+                _ => (),
+            }
+        }
+
+        if !self.list_directories {
+            return resp_wtr.send_code(404).await;
+        }
+
+        let mut entries = self.fs.read_dir(&file_path).await?;
+        if !self.serve_hidden {
+            entries.retain(|entry| !entry.name.starts_with('.'));
+        }
+        let title = percent_decode(uri_path)
+            .map(|path| String::from_utf8_lossy(&path).into_owned())
+            .unwrap_or_else(|| uri_path.to_owned());
+        let is_root = path.trim_matches('/').is_empty();
+        resp_wtr.set_body(directory_listing(&title, is_root, entries).into());
+        resp_wtr.insert_header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        resp_wtr.send().await
+    }
+
+    async fn serve_file<W>(
+        &self,
+        file_path: &Path,
+        metadata: Metadata,
+        req: &Request,
+        mut resp_wtr: ResponseWriter<W>,
+    ) -> Result<ResponseWritten>
+    where
+        W: futures_lite::AsyncRead
+            + futures_lite::AsyncWrite
+            + Clone
+            + Send
+            + Sync
+            + Unpin
+            + 'static,
+    {
+        let file = match self.fs.open(file_path).await {
+            Ok(file) => file,
+            Err(err) => {
+                debug!("ServeDir open {:?}: {}", file_path, err);
+                return resp_wtr.send_code(404).await;
+            }
+        };
+
+        resp_wtr.insert_header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(guess_content_type(file_path)),
+        );
+        if let Some(modified) = metadata.modified {
+            resp_wtr.insert_header(header::LAST_MODIFIED, fmt_http_date(modified).parse()?);
+
+            // Changes when the file does, like nginx's.
+            let secs = modified
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            let etag = format!("\"{:x}-{:x}\"", secs, metadata.len);
+            resp_wtr.insert_header(header::ETAG, etag.parse()?);
+        }
+        resp_wtr.set_ranged_body(req, file, metadata.len);
+        resp_wtr.send().await
+    }
+}
+
+#[cfg(feature = "router")]
+impl<F, W> crate::server::router::Endpoint<W> for ServeDir<F>
+where
+    F: FileSystem,
+    W: futures_util::io::AsyncRead
+        + futures_util::io::AsyncWrite
+        + Clone
+        + Send
+        + Sync
+        + Unpin
+        + 'static,
+{
+    fn call(
+        &self,
+        req: Request,
+        resp_wtr: ResponseWriter<W>,
+    ) -> BoxFuture<'_, Result<ResponseWritten>> {
+        use crate::server::router::RouterRequestExt;
+
+        Box::pin(async move {
+            let path = req.get_param(&self.param).unwrap_or("").to_owned();
+            self.serve(&path, &req, resp_wtr).await
+        })
+    }
+}
+
+// The file at `path` (percent-encoded) under `root`. `None` if the path is invalid, would leave
+// `root`, or is hidden when hidden files aren't served.
+fn normalize_path(root: &Path, path: &str, serve_hidden: bool) -> Option<PathBuf> {
+    let path = String::from_utf8(percent_decode(path)?).ok()?;
+
+    let mut file_path = root.to_path_buf();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => return None,
+            // Would be read as a separator, drive, or end of the path on some platform.
+            segment if segment.contains(&['\\', ':', '\0'][..]) => return None,
+            segment if !serve_hidden && segment.starts_with('.') => return None,
+            segment => file_path.push(segment),
+        }
+    }
+    Some(file_path)
+}
+
+fn guess_content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+
+    match ext.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("xml") => "text/xml; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("mp3") => "audio/mpeg",
+        Some("ogg") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn directory_listing(title: &str, is_root: bool, mut entries: Vec<DirEntry>) -> String {
+    // directories first
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let title = escape_html(title);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body>\n<h1>Index of {0}</h1>\n<ul>\n",
+        title,
+    );
+    if !is_root {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for entry in entries {
+        let slash = if entry.is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{}\">{}{}</a></li>\n",
+            percent_encode_segment(&entry.name),
+            slash,
+            escape_html(&entry.name),
+            slash,
+        ));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+    html
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn percent_encode_segment(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize_path() {
+        let root = Path::new("/srv");
+        assert_eq!(normalize_path(root, "a/b.txt", false), Some(PathBuf::from("/srv/a/b.txt")));
+        assert_eq!(normalize_path(root, "/a//./b.txt", false), Some(PathBuf::from("/srv/a/b.txt")));
+        assert_eq!(normalize_path(root, "", false), Some(PathBuf::from("/srv")));
+        assert_eq!(normalize_path(root, "a%20b.txt", false), Some(PathBuf::from("/srv/a b.txt")));
+        assert_eq!(normalize_path(root, "../etc/passwd", false), None);
+        assert_eq!(normalize_path(root, "a/../../etc/passwd", false), None);
+        assert_eq!(normalize_path(root, "%2e%2e/etc/passwd", false), None);
+        assert_eq!(normalize_path(root, "a%2f..%2f..%2fetc", false), None);
+        assert_eq!(normalize_path(root, "..%5cetc", false), None);
+        assert_eq!(normalize_path(root, "c:/windows", false), None);
+        assert_eq!(normalize_path(root, "a%00b", false), None);
+        assert_eq!(normalize_path(root, "a%zz", false), None);
+        assert_eq!(normalize_path(root, "%ff", false), None);
+        assert_eq!(normalize_path(root, ".git/config", false), None);
+        assert_eq!(normalize_path(root, "a/%2eenv", false), None);
+        assert_eq!(normalize_path(root, "./a/b.txt", false), Some(PathBuf::from("/srv/a/b.txt")));
+        assert_eq!(normalize_path(root, ".env", true), Some(PathBuf::from("/srv/.env")));
+    }
+
+    #[test]
+    fn test_guess_content_type() {
+        assert_eq!(guess_content_type(Path::new("index.HTML")), "text/html; charset=utf-8");
+        assert_eq!(guess_content_type(Path::new("a/b.png")), "image/png");
+        assert_eq!(guess_content_type(Path::new("README")), "application/octet-stream");
+    }
+}
@@ -19,7 +19,7 @@
 //! ## Functionality
 //! A `Glitch` allows you to:
 //! - Just use `?` on any error, and it will be turned into a 500 response. (`anyhow` feature
-//! only)
+//!   only)
 //! - use `.map_err` to easily convert your error to a Glitch.
 //!
 //! In this system, it's easy to use standard `From` and `Into` traits to convert your custom
@@ -44,7 +44,7 @@ pub type Result<T> = std::result::Result<T, Glitch>;
 /// Note that if you create a message or error, they will be converted to a message string, and the
 /// content-type will be set to `text/plain`. For more control over the response, use
 /// `ResponseWriter`.
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Glitch {
     pub(crate) status: Option<StatusCode>,
     pub(crate) headers: Option<HeaderMap>,
@@ -65,18 +65,6 @@ where
     }
 }
 
-impl std::default::Default for Glitch {
-    fn default() -> Self {
-        Self {
-            status: None,
-            headers: None,
-            version: None,
-            message: None,
-            trace: None,
-        }
-    }
-}
-
 impl Glitch {
     /// Create a Glitch
     pub fn new() -> Self {
@@ -135,7 +123,7 @@ impl Glitch {
 
     pub(crate) fn into_inner_response(self, verbose: bool) -> InnerResponse {
         // Always start with user-created message
-        let mut msg: String = self.message.unwrap_or_else(|| "".to_string());
+        let mut msg: String = self.message.unwrap_or_default();
 
         if verbose {
             // must be a less awkward way to do this.
@@ -150,7 +138,7 @@ impl Glitch {
         }
 
         // as a default, set header to content-type text/plain if there's a message or trace.
-        let mut headers = self.headers.unwrap_or_else(HeaderMap::new);
+        let mut headers = self.headers.unwrap_or_default();
         if !msg.is_empty() {
             headers.insert(http::header::CONTENT_TYPE, "text/plain".parse().unwrap());
         }
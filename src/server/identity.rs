@@ -14,7 +14,7 @@
 //! - set jwt token on Response `identity.set_authorization(res)`
 //! - check authentication on Request `identity.authorized_user(req)`
 //! - forget (clear jwt token, basically sets a cookie with no name and no duration)
-//! `identity.forget(res)`
+//!   `identity.forget(res)`
 
 use cookie::Cookie;
 use futures_util::io::{AsyncRead, AsyncWrite};
@@ -63,7 +63,7 @@ impl Identity {
     /// Checked for an authorized user for the incoming request
     pub fn authorized_user(&self, req: &Request) -> Option<String> {
         // Get Cookie and token
-        let jwtstr = get_cookie(&req, &self.cookie_name);
+        let jwtstr = get_cookie(req, &self.cookie_name);
 
         // Decode token
         if let Some(jwtstr) = jwtstr {
@@ -0,0 +1,429 @@
+//! Json module
+//!
+//! Read and write JSON bodies with serde:
+//!
+//! ```rust,no_run
+//! # use futures_util::io::{AsyncRead, AsyncWrite};
+//! # use tophat::{Request, server::{glitch::Result, ResponseWriter, ResponseWritten}};
+//! use serde::{Deserialize, Serialize};
+//! use tophat::server::json;
+//!
+//! #[derive(Deserialize, Serialize)]
+//! struct Todo {
+//!     title: String,
+//!     done: bool,
+//! }
+//!
+//! async fn create<W>(req: Request, mut resp_wtr: ResponseWriter<W>) -> Result<ResponseWritten>
+//!     where W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+//! {
+//!     let todo: Todo = json::from_request(req).await?;
+//!     resp_wtr.set_json(&todo)?;
+//!     resp_wtr.send().await
+//! }
+//! ```
+//!
+//! `from_request` checks that the request's `Content-Type` is `application/json` (or another
+//! `+json` type) before reading the body; `Body::into_json` only reads the body, as a `Body`
+//! doesn't have the request's headers.
+//!
+//! Bodies are read up to a size limit, `DEFAULT_JSON_LIMIT` unless set. A `JsonError` converts
+//! into a `Glitch`: 415 for the wrong content type, 413 over the limit, and 400 for invalid JSON,
+//! with the line and column of the error.
+//!
+//! For large inputs, `Body::into_json_stream` deserializes the items of a JSON array, or the
+//! values of NDJSON, one at a time as they arrive.
+//!
+//! For large outputs, `ResponseWriter::set_ndjson` sends a `Stream` of items as NDJSON, one line
+//! per item, each flushed as soon as it's produced.
+
+use futures_lite::{AsyncBufReadExt, AsyncReadExt, Stream};
+use http::{header, HeaderMap, StatusCode};
+use serde::de::DeserializeOwned;
+use std::fmt;
+
+use crate::body::{error::BodyError, Body, LimitedReader};
+use crate::request::Request;
+use crate::server::glitch::Glitch;
+
+/// Default size limit for a JSON body (or a single item of a JSON stream), in bytes.
+pub const DEFAULT_JSON_LIMIT: usize = 1024 * 1024;
+
+/// Whether the `Content-Type` is `application/json`, or another `+json` type like
+/// `application/problem+json`.
+pub fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .map(|ct| {
+            let media_type = ct.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+            media_type == "application/json"
+                || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+        })
+        .unwrap_or(false)
+}
+
+/// Deserialize a JSON request body, up to `DEFAULT_JSON_LIMIT` bytes. The `Content-Type` must be
+/// JSON, see `is_json`.
+pub async fn from_request<T: DeserializeOwned>(req: Request) -> Result<T, JsonError> {
+    from_request_with_limit(req, DEFAULT_JSON_LIMIT).await
+}
+
+/// Deserialize a JSON request body, up to `limit` bytes. The `Content-Type` must be JSON, see
+/// `is_json`.
+pub async fn from_request_with_limit<T: DeserializeOwned>(
+    req: Request,
+    limit: usize,
+) -> Result<T, JsonError> {
+    if !is_json(req.headers()) {
+        return Err(JsonError::UnsupportedMediaType);
+    }
+    req.into_body().into_json_with_limit(limit).await
+}
+
+impl Body {
+    /// Read a JSON body and deserialize it, up to `DEFAULT_JSON_LIMIT` bytes. Consumes Body.
+    pub async fn into_json<T: DeserializeOwned>(self) -> Result<T, JsonError> {
+        self.into_json_with_limit(DEFAULT_JSON_LIMIT).await
+    }
+
+    /// Read a JSON body and deserialize it, up to `limit` bytes. Consumes Body.
+    pub async fn into_json_with_limit<T: DeserializeOwned>(
+        self,
+        limit: usize,
+    ) -> Result<T, JsonError> {
+        let mut buf = Vec::new();
+        LimitedReader::new(self, limit)
+            .read_to_end(&mut buf)
+            .await
+            .map_err(|err| JsonError::Body(err.into()))?;
+        serde_json::from_slice(&buf).map_err(JsonError::Parse)
+    }
+
+    /// Deserialize the items of a JSON array body, or the values of an NDJSON body (any
+    /// whitespace-separated JSON values), as they are read. Each item can be up to
+    /// `DEFAULT_JSON_LIMIT` bytes.
+    ///
+    /// The stream ends after the first error.
+    pub fn into_json_stream<T>(self) -> impl Stream<Item = Result<T, JsonError>> + Send
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        self.into_json_stream_with_limit(DEFAULT_JSON_LIMIT)
+    }
+
+    /// Deserialize the items of a JSON array body, or the values of an NDJSON body, as they are
+    /// read. Each item can be up to `limit` bytes.
+    ///
+    /// The stream ends after the first error.
+    pub fn into_json_stream_with_limit<T>(
+        self,
+        limit: usize,
+    ) -> impl Stream<Item = Result<T, JsonError>> + Send
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let splitter = Splitter {
+            body: self,
+            limit,
+            array: None,
+            done: false,
+        };
+
+        futures_util::stream::unfold(splitter, |mut splitter| async move {
+            if splitter.done {
+                return None;
+            }
+            let item = match splitter.next_item().await {
+                Ok(Some(item)) => serde_json::from_slice(&item).map_err(JsonError::Parse),
+                Ok(None) => return None,
+                Err(err) => Err(err),
+            };
+            if item.is_err() {
+                splitter.done = true;
+            }
+            Some((item, splitter))
+        })
+    }
+}
+
+/// Splits a body into the bytes of each JSON value, without parsing them.
+struct Splitter {
+    body: Body,
+    limit: usize,
+    // Whether the body is an array, once known
+    array: Option<bool>,
+    done: bool,
+}
+
+impl Splitter {
+    async fn next_item(&mut self) -> Result<Option<Vec<u8>>, JsonError> {
+        self.skip_whitespace().await?;
+
+        match self.array {
+            None => {
+                let array = self.peek().await? == Some(b'[');
+                self.array = Some(array);
+                if array {
+                    self.body.consume(1);
+                    self.skip_whitespace().await?;
+                    if self.peek().await? == Some(b']') {
+                        self.done = true;
+                        return Ok(None);
+                    }
+                }
+            }
+            Some(true) => match self.peek().await? {
+                Some(b',') => {
+                    self.body.consume(1);
+                    self.skip_whitespace().await?;
+                }
+                Some(b']') => {
+                    self.done = true;
+                    return Ok(None);
+                }
+                Some(_) => return Err(JsonError::Malformed("expected `,` or `]` in array")),
+                None => return Err(JsonError::Malformed("array without closing `]`")),
+            },
+            Some(false) => (),
+        }
+
+        match self.peek().await? {
+            Some(_) => self.read_value().await.map(Some),
+            None if self.array == Some(true) => {
+                Err(JsonError::Malformed("array without closing `]`"))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // The bytes of one value: an object, array or string up to its end, or anything else up to
+    // the next delimiter. Scans what's buffered, and consumes it a chunk at a time.
+    async fn read_value(&mut self) -> Result<Vec<u8>, JsonError> {
+        let mut value = Vec::new();
+        // The value's first byte, which tells what kind of value it is
+        let mut kind = None;
+        let mut depth = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        loop {
+            let buf = self
+                .body
+                .fill_buf()
+                .await
+                .map_err(|err| JsonError::Body(err.into()))?;
+            if buf.is_empty() {
+                break;
+            }
+
+            // How much of `buf` is part of the value, and whether the value ends there
+            let mut used = buf.len();
+            let mut complete = false;
+            for (i, &b) in buf.iter().enumerate() {
+                let is_container = matches!(kind, Some(b'{') | Some(b'[') | Some(b'"'));
+                if depth == 0 && !in_string && kind.is_some() && !is_container {
+                    // a scalar ends at the next delimiter, which isn't part of it
+                    if b.is_ascii_whitespace() || b == b',' || b == b']' {
+                        used = i;
+                        complete = true;
+                        break;
+                    }
+                }
+
+                if kind.is_none() {
+                    kind = Some(b);
+                }
+
+                if in_string {
+                    match b {
+                        _ if escaped => escaped = false,
+                        b'\\' => escaped = true,
+                        b'"' => in_string = false,
+                        _ => (),
+                    }
+                } else {
+                    match b {
+                        b'"' => in_string = true,
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => depth = depth.saturating_sub(1),
+                        _ => (),
+                    }
+                }
+
+                // a container or string is complete
+                let is_container = matches!(kind, Some(b'{') | Some(b'[') | Some(b'"'));
+                if depth == 0 && !in_string && is_container {
+                    used = i + 1;
+                    complete = true;
+                    break;
+                }
+            }
+
+            if value.len() + used > self.limit {
+                return Err(JsonError::Body(BodyError::TooLarge(self.limit)));
+            }
+            value.extend_from_slice(&buf[..used]);
+            self.body.consume(used);
+            if complete {
+                break;
+            }
+        }
+
+        Ok(value)
+    }
+
+    async fn skip_whitespace(&mut self) -> Result<(), JsonError> {
+        loop {
+            let buf = self
+                .body
+                .fill_buf()
+                .await
+                .map_err(|err| JsonError::Body(err.into()))?;
+            let n = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            let more = !buf.is_empty() && n == buf.len();
+            self.body.consume(n);
+            if !more {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn peek(&mut self) -> Result<Option<u8>, JsonError> {
+        let buf = self
+            .body
+            .fill_buf()
+            .await
+            .map_err(|err| JsonError::Body(err.into()))?;
+        Ok(buf.first().copied())
+    }
+}
+
+/// Error reading a JSON body.
+///
+/// Converts into a `Glitch`.
+#[derive(Debug)]
+pub enum JsonError {
+    /// The request's `Content-Type` isn't JSON.
+    UnsupportedMediaType,
+    /// Reading the body failed, or it was over the size limit.
+    Body(BodyError),
+    /// The body isn't valid JSON for the type.
+    Parse(serde_json::Error),
+    /// A JSON stream isn't valid.
+    Malformed(&'static str),
+}
+
+impl JsonError {
+    /// The response status for this error: 415 for the wrong content type, 413 for a body over
+    /// the size limit, otherwise 400.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            JsonError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            JsonError::Body(BodyError::TooLarge(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::UnsupportedMediaType => write!(f, "Content-Type is not application/json"),
+            JsonError::Body(err) => write!(f, "Error reading JSON: {}", err),
+            // serde_json's message includes the line and column
+            JsonError::Parse(err) => write!(f, "Invalid JSON: {}", err),
+            JsonError::Malformed(msg) => write!(f, "Invalid JSON: {}", msg),
+        }
+    }
+}
+
+// Not a `std::error::Error`, so that this can be a 4xx instead of the 500 from the blanket impl.
+impl From<JsonError> for Glitch {
+    fn from(err: JsonError) -> Self {
+        Glitch::new_with_status_context(err.status(), err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_lite::StreamExt;
+    use serde_json::Value;
+
+    fn stream(body: &str) -> Vec<Result<Value, JsonError>> {
+        smol::block_on(Body::from(body.to_owned()).into_json_stream_with_limit(16).collect())
+    }
+
+    fn values(body: &str) -> Vec<Value> {
+        stream(body).into_iter().map(Result::unwrap).collect()
+    }
+
+    // Buffers `chunk` bytes at a time, so that values span several buffers.
+    fn values_in_chunks(body: &str, chunk: usize) -> Vec<Value> {
+        let cursor = futures_lite::io::Cursor::new(body.as_bytes().to_vec());
+        let reader = futures_lite::io::BufReader::with_capacity(chunk, cursor);
+        let items: Vec<_> = smol::block_on(
+            Body::from_reader(reader, None)
+                .into_json_stream_with_limit(16)
+                .collect(),
+        );
+        items.into_iter().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_is_json() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_json(&headers));
+        for (content_type, expected) in &[
+            ("application/json", true),
+            ("Application/JSON; charset=utf-8", true),
+            ("application/problem+json", true),
+            ("text/plain", false),
+            ("application/jsonx", false),
+        ] {
+            headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+            assert_eq!(is_json(&headers), *expected, "{}", content_type);
+        }
+    }
+
+    #[test]
+    fn test_json_stream() {
+        let expected = vec![
+            serde_json::json!({"a": "]}\""}),
+            serde_json::json!([1, [2]]),
+            serde_json::json!("s,]"),
+            serde_json::json!(-1.5),
+            serde_json::json!(null),
+        ];
+        let array = r#" [ {"a": "]}\""}, [1,[2]] ,"s,]",-1.5,null ] "#;
+        assert_eq!(values(array), expected);
+        let ndjson = "{\"a\": \"]}\\\"\"}\n[1,[2]]\n\"s,]\"\n-1.5\r\nnull\n";
+        assert_eq!(values(ndjson), expected);
+        for chunk in 1..4 {
+            assert_eq!(values_in_chunks(array, chunk), expected);
+            assert_eq!(values_in_chunks(ndjson, chunk), expected);
+        }
+
+        assert!(values(" [ ] ").is_empty());
+        assert!(values("").is_empty());
+    }
+
+    #[test]
+    fn test_json_stream_errors() {
+        let items = stream("[1, 2");
+        assert_eq!(items.len(), 3);
+        assert!(matches!(items[2], Err(JsonError::Malformed(_))));
+
+        let items = stream("[1 2]");
+        assert!(matches!(items[1], Err(JsonError::Malformed(_))));
+
+        let items = stream("1\n{\"a\": nope}\n3");
+        assert_eq!(items.len(), 2);
+        assert!(matches!(items[1], Err(JsonError::Parse(_))));
+
+        // over the item limit
+        let items = stream("[\"0123456789abcdef\"]");
+        assert_eq!(items[0].as_ref().unwrap_err().status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -4,18 +4,39 @@
 
 #[cfg(feature = "cors")]
 pub mod cors;
+mod body_writer;
+pub mod broadcast;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod conditional;
+mod conn;
 mod decode;
 mod encode;
+#[cfg(feature = "form")]
+pub mod form;
+#[cfg(feature = "fs")]
+pub mod fs;
 pub mod glitch;
 #[cfg(feature = "identity")]
 pub mod identity;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "multipart")]
+pub mod multipart;
+pub mod range;
 mod response_writer;
 #[cfg(feature = "router")]
 pub mod router;
 pub mod error;
+mod shutdown;
+pub mod sse;
+mod upgrade;
+#[cfg(feature = "websocket")]
+pub mod websocket;
 
-use futures_lite::{AsyncRead, AsyncWrite, Future};
+use futures_lite::{future, AsyncBufReadExt, AsyncRead, AsyncWrite, Future};
 use std::time::Duration;
+use tracing::debug;
 
 use crate::body::Body;
 use crate::request::Request;
@@ -23,13 +44,17 @@ use crate::response::Response;
 use crate::server::decode::DecodeFail;
 use crate::timeout::{timeout, TimeoutError};
 
+use self::conn::{BodySlot, ConnReader};
 use self::decode::decode;
+pub use self::body_writer::BodyWriter;
 pub use self::error::ServerError;
 pub use self::glitch::{Glitch, Result};
-use self::response_writer::InnerResponse;
+use self::response_writer::{InnerResponse, RequestInfo};
 pub use self::response_writer::{ResponseWriter, ResponseWritten};
+pub use self::shutdown::Shutdown;
+pub use self::upgrade::Upgraded;
 
-/// Accept a new incoming Http/1.1 connection
+/// Accept a new incoming Http/1.1 (or Http/1.0) connection
 ///
 /// Automatically supports KeepAlive
 pub async fn accept<RW, F, Fut>(io: RW, endpoint: F) -> std::result::Result<(), ServerError>
@@ -41,9 +66,11 @@ where
     accept_with_opts(io, ServerOpts::default(), endpoint).await
 }
 
-/// Accept a new incoming Http/1.1 connection
+/// Accept a new incoming Http/1.1 (or Http/1.0) connection
 ///
 /// Automatically supports KeepAlive
+///
+/// To shut down gracefully, see `Shutdown`.
 pub async fn accept_with_opts<RW, F, Fut>(
     io: RW,
     opts: ServerOpts,
@@ -56,48 +83,126 @@ where
 {
     // All errors should be bubbled up to this fn to handle, either in logs or in responses.
 
+    let _conn_guard = opts.shutdown.as_ref().map(Shutdown::track_connection);
+
+    // One reader for the connection, so that bytes read past the end of a request are kept for
+    // the next. While a request is handled, its body holds the reader.
+    let mut conn = Some(ConnReader::new(io.clone()));
+    let body_slot = BodySlot::new();
+
     loop {
+        if opts.shutdown.as_ref().map(Shutdown::is_signaled).unwrap_or(false) {
+            break;
+        }
+
+        // After a request, take back the reader from its body, reading whatever is left unread.
+        if conn.is_none() {
+            conn = body_slot.reclaim().await;
+        }
+        let reader = match conn.as_mut() {
+            Some(reader) => reader,
+            None => {
+                debug!("Request body could not be drained, closing connection");
+                break;
+            }
+        };
+
+        // Wait for the next request to start.
+        // EOF, a lost connection, or shutdown all end the connection here; a request which has
+        // started to arrive is still handled after shutdown is signaled.
+        let idle_fut = async {
+            let readable = async {
+                reader
+                    .fill_buf()
+                    .await
+                    .map(|buf| !buf.is_empty())
+                    .unwrap_or(false)
+            };
+            match opts.shutdown {
+                Some(ref shutdown) => {
+                    future::or(readable, async {
+                        shutdown.wait().await;
+                        false
+                    })
+                    .await
+                }
+                None => readable.await,
+            }
+        };
+        match with_timeout(opts.idle_timeout, idle_fut).await {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(TimeoutError { .. }) => {
+                debug!("Connection idle timeout");
+                break;
+            }
+        }
+
         // decode to Request
         // returns Ok(None) if no request to decode. So no need to exit on a ConnectionLost error.
-        let req_fut = decode(io.clone());
-
-        // Handle req failure modes, timeout, eof
-        let req = if let Some(timeout_duration) = opts.timeout {
-            // this arm is for with timeout
-            match timeout(timeout_duration, req_fut).await {
-                Ok(Ok(Some(r))) => r,
-                Ok(Ok(None)) | Err(TimeoutError { .. }) => {
-                    //debug!("Timeout Error");
-                    break; // EOF or timeout
-                }
-                Ok(Err(err)) => {
-                    handle_decode_fail(err, io.clone()).await?;
-                    // and continue on to next request
-                    continue;
-                }
+        let req_fut = decode(&mut conn, io.clone(), &opts, &body_slot);
+        let req = match with_timeout(opts.header_read_timeout, req_fut).await {
+            Ok(Ok(Some(r))) => r,
+            Ok(Ok(None)) => break, // EOF
+            Ok(Err(err)) => {
+                handle_decode_fail(err, io.clone(), &opts).await?;
+                // The rest of the request is unread, so the next one can't be found.
+                break;
             }
-        } else {
-            // This arm is for no timeout
-            match req_fut.await {
-                Ok(Some(r)) => r,
-                Ok(None) => break, // EOF
-                Err(err) => {
-                    handle_decode_fail(err, io.clone()).await?;
-                    // and continue on to next request
-                    continue;
-                }
+            Err(TimeoutError { .. }) => {
+                debug!("Request head read timeout");
+                let req_info = RequestInfo {
+                    keep_alive: false,
+                    ..RequestInfo::new(&opts)
+                };
+                let _ = InnerResponse::request_timeout().send(io.clone(), &req_info).await;
+                break;
             }
         };
 
+        let req_info = RequestInfo::from_request(&req, &opts);
+
+        let mut response = Response::new(Body::empty());
+        *response.version_mut() = req.version();
         let resp_wtr = ResponseWriter {
             writer: io.clone(),
-            response: Response::new(Body::empty()),
+            response,
+            req_info: req_info.clone(),
+            body_slot: body_slot.clone(),
         };
-        if let Err(glitch) = endpoint(req, resp_wtr).await {
-            let _ = glitch
-                .into_inner_response(opts.verbose_glitch)
-                .send(io.clone())
-                .await;
+
+        let res = endpoint(req, resp_wtr).await;
+
+        // The connection now belongs to another protocol, even if the endpoint failed after.
+        if body_slot.is_upgraded() {
+            break;
+        }
+
+        // Part of a response was already sent, there's no way to send a glitch or another
+        // response after it.
+        if body_slot.is_streaming() {
+            debug!("Streamed response was not finished, closing connection");
+            break;
+        }
+
+        let keep_alive = match res {
+            Ok(resp_written) => resp_written.keep_alive,
+            Err(glitch) => {
+                let req_info = RequestInfo {
+                    keep_alive: req_info.keep_alive && body_slot.will_drain().await,
+                    ..req_info
+                };
+                glitch
+                    .into_inner_response(opts.verbose_glitch)
+                    .send(io.clone(), &req_info)
+                    .await
+                    .map(|resp_written| resp_written.keep_alive)
+                    .unwrap_or(false)
+            }
+        };
+
+        if !keep_alive {
+            break;
         }
     }
 
@@ -107,32 +212,105 @@ where
 /// Options for the tophat server.
 #[derive(Clone)]
 pub struct ServerOpts {
-    /// Connection timeout (in seconds)
-    pub timeout: Option<Duration>,
+    /// How long a connection can wait for the first byte of a request before it's closed. This
+    /// covers the time before the first request, and between requests on a kept-alive connection.
+    pub idle_timeout: Option<Duration>,
+    /// How long the rest of the request head can take to arrive, once its first byte has. On
+    /// expiry, responds with 408 and closes the connection.
+    pub header_read_timeout: Option<Duration>,
+    /// How long a single read of the request body can wait for data. On expiry, the body read
+    /// fails with an `io::ErrorKind::TimedOut` error.
+    pub body_read_timeout: Option<Duration>,
+    /// How long a single write of the response can wait for the client to accept data. On expiry,
+    /// sending the response fails and the connection is closed.
+    pub write_timeout: Option<Duration>,
+    /// How long a chunked response waits for its trailers after the last chunk, if a
+    /// `TrailersSender` is still held, before ending without them. Default 1 second.
+    pub trailers_timeout: Option<Duration>,
     /// Option to send error (from convertin error to Glitch) traces in an error response (Glitch)
     pub verbose_glitch: bool,
+    /// Max number of headers in a request. Over the limit responds with 431 and closes the
+    /// connection
+    pub max_headers: usize,
+    /// Max size of the request head (in bytes). Over the limit responds with 431 and closes the
+    /// connection
+    pub max_head_size: usize,
+    /// Max length of the request-target (in bytes). Over the limit responds with 414 and closes
+    /// the connection
+    pub max_uri_length: usize,
+    /// Max size of a request body (in bytes), default unlimited. A larger content-length responds
+    /// with 413 and closes the connection; a chunked body fails to read past the limit with
+    /// `BodyError::TooLarge`.
+    pub max_body_size: Option<usize>,
+    /// Signal to gracefully shut down the connection.
+    pub shutdown: Option<Shutdown>,
+    /// Compress responses sent with `ResponseWriter::send`, if the client accepts it. Default
+    /// `None`.
+    #[cfg(feature = "compression")]
+    pub compression: Option<compression::Compression>,
+    /// Decompress request bodies sent with a gzip, deflate, or br `Content-Encoding`; other
+    /// codings respond with 415 and close the connection. `max_body_size` limits the decompressed
+    /// size. Default `false`.
+    #[cfg(feature = "compression")]
+    pub decompress_requests: bool,
 }
 
 impl Default for ServerOpts {
     fn default() -> Self {
         Self {
-            timeout: Some(Duration::from_secs(60)),
+            idle_timeout: Some(Duration::from_secs(60)),
+            header_read_timeout: Some(Duration::from_secs(60)),
+            body_read_timeout: Some(Duration::from_secs(60)),
+            write_timeout: Some(Duration::from_secs(60)),
+            trailers_timeout: Some(Duration::from_secs(1)),
             verbose_glitch: false,
+            max_headers: 128,
+            max_head_size: 16 * 1024,
+            max_uri_length: 8 * 1024,
+            max_body_size: None,
+            shutdown: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "compression")]
+            decompress_requests: false,
         }
     }
 }
 
-// handles both writing error response and bubbling up major system errors as necessary.
-async fn handle_decode_fail<RW>(fail: DecodeFail, io: RW) -> std::result::Result<(), ServerError>
+// `timeout`, but never times out for `None`.
+async fn with_timeout<F, T>(dur: Option<Duration>, f: F) -> std::result::Result<T, TimeoutError>
+where
+    F: Future<Output = T>,
+{
+    match dur {
+        Some(dur) => timeout(dur, f).await,
+        None => Ok(f.await),
+    }
+}
+
+// handles both writing error response and bubbling up major system errors as necessary. The
+// connection is always closed after.
+async fn handle_decode_fail<RW>(
+    fail: DecodeFail,
+    io: RW,
+    opts: &ServerOpts,
+) -> std::result::Result<(), ServerError>
 where
     RW: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
 {
-    // send a resp for errors from decoding
-    if let Some(err_resp) = decode::fail_to_response_and_log(&fail) {
-        let _ = err_resp.send(io.clone()).await;
+    let err_resp = decode::fail_to_response_and_log(&fail);
+    let crate_err = decode::fail_to_crate_err(fail);
+
+    // send a resp for errors from decoding, announcing the close.
+    if let Some(err_resp) = err_resp {
+        let req_info = RequestInfo {
+            keep_alive: false,
+            ..RequestInfo::new(opts)
+        };
+        let _ = err_resp.send(io.clone(), &req_info).await;
     }
     // Early return if there's a major error.
-    if let Some(crate_err) = decode::fail_to_crate_err(fail) {
+    if let Some(crate_err) = crate_err {
         return Err(crate_err);
     }
 
@@ -0,0 +1,804 @@
+//! Multipart module
+//!
+//! Streaming `multipart/form-data` (RFC 7578) request bodies. Parts are read as they arrive, and
+//! each part's body is an `AsyncRead`, so large uploads can go straight to disk without being
+//! buffered in memory:
+//!
+//! ```rust,no_run
+//! # use futures_util::io::{AsyncRead, AsyncWrite};
+//! # use tophat::{Request, server::{glitch::Result, ResponseWriter, ResponseWritten}};
+//! use futures_lite::io::{copy, sink};
+//! use tophat::server::multipart::{Multipart, MultipartError};
+//!
+//! async fn upload<W>(req: Request, resp_wtr: ResponseWriter<W>) -> Result<ResponseWritten>
+//!     where W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+//! {
+//!     let mut multipart = Multipart::from_request(req).map_err(MultipartError::into_glitch)?;
+//!
+//!     while let Some(part) = multipart.next_part().await {
+//!         let mut part = part.map_err(MultipartError::into_glitch)?;
+//!         if part.filename().is_some() {
+//!             // e.g. copy to a file instead
+//!             copy(&mut part, &mut sink()).await?;
+//!         }
+//!     }
+//!
+//!     resp_wtr.send().await
+//! }
+//! ```
+//!
+//! `Multipart` is also a `Stream` of parts.
+//!
+//! Parts are read in order: asking for the next part skips whatever is left of the current one,
+//! after which reading the old part returns eof.
+//!
+//! Limits on the size of each part, of the whole body, on the number of parts and on the size of
+//! each part's headers are set in `MultipartConfig`. Going over a limit, or malformed input, is an
+//! error from `next_part` or from reading the part; `MultipartError::into_glitch` turns it into a
+//! response with a matching status.
+
+use futures_lite::{ready, AsyncBufRead, AsyncRead, AsyncReadExt, Stream};
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::StatusCode;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::body::{error::BodyError, Body};
+use crate::request::Request;
+use crate::server::glitch::Glitch;
+use crate::util::percent_decode;
+
+// Most headers a part can have
+const MAX_PART_HEADERS: usize = 32;
+// Longest line allowed after a boundary, for transport padding
+const MAX_BOUNDARY_LINE: usize = 256;
+
+/// Limits for reading a multipart body.
+#[derive(Debug, Clone)]
+pub struct MultipartConfig {
+    /// Largest body of a single part, in bytes.
+    ///
+    /// Default no limit.
+    pub max_part_size: Option<u64>,
+    /// Largest multipart body, in bytes, including part headers and boundaries.
+    ///
+    /// Default no limit (`ServerOpts::max_body_size` still applies).
+    pub max_total_size: Option<u64>,
+    /// Most parts in a body.
+    ///
+    /// Default 1024.
+    pub max_parts: usize,
+    /// Largest headers section of a single part, in bytes.
+    ///
+    /// Default 8 KiB.
+    pub max_headers_size: usize,
+}
+
+impl Default for MultipartConfig {
+    fn default() -> Self {
+        Self {
+            max_part_size: None,
+            max_total_size: None,
+            max_parts: 1024,
+            max_headers_size: 8 * 1024,
+        }
+    }
+}
+
+/// The boundary from a `Content-Type: multipart/...` header.
+pub fn boundary(headers: &HeaderMap) -> Result<String, MultipartError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .ok_or(MultipartError::NotMultipart)?;
+
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    if !media_type.to_ascii_lowercase().starts_with("multipart/") {
+        return Err(MultipartError::NotMultipart);
+    }
+
+    let boundary = parse_params(content_type)
+        .into_iter()
+        .find(|(name, _)| name == "boundary")
+        .map(|(_, value)| value)
+        .ok_or(MultipartError::InvalidBoundary)?;
+
+    // https://tools.ietf.org/html/rfc2046#section-5.1.1
+    if boundary.is_empty() || boundary.len() > 70 || boundary.ends_with(' ') {
+        return Err(MultipartError::InvalidBoundary);
+    }
+    Ok(boundary)
+}
+
+/// A streaming multipart body. Yields `Part`s with `next_part`, or as a `Stream`.
+pub struct Multipart {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Multipart {
+    /// Read `body` as multipart, with parts separated by `boundary`.
+    pub fn new(body: Body, boundary: &str) -> Self {
+        Self::with_config(body, boundary, MultipartConfig::default())
+    }
+
+    /// Read `body` as multipart, with parts separated by `boundary`, and custom limits.
+    pub fn with_config(body: Body, boundary: &str, config: MultipartConfig) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+
+        let inner = Inner {
+            body,
+            delimiter,
+            // So that the first boundary, at the very start of the body, is also found as a
+            // delimiter.
+            buf: b"\r\n".to_vec(),
+            eof: false,
+            state: State::Preamble,
+            config,
+            parts: 0,
+            part_size: 0,
+            total_size: 0,
+        };
+
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// Read the request body as multipart, using the boundary from its `Content-Type`.
+    pub fn from_request(req: Request) -> Result<Self, MultipartError> {
+        Self::from_request_with_config(req, MultipartConfig::default())
+    }
+
+    /// Read the request body as multipart, using the boundary from its `Content-Type`, and custom
+    /// limits.
+    pub fn from_request_with_config(
+        req: Request,
+        config: MultipartConfig,
+    ) -> Result<Self, MultipartError> {
+        let boundary = boundary(req.headers())?;
+        Ok(Self::with_config(req.into_body(), &boundary, config))
+    }
+
+    /// The next part, skipping the rest of the current one. `None` after the last part, or after
+    /// an error.
+    pub async fn next_part(&mut self) -> Option<Result<Part, MultipartError>> {
+        futures_lite::future::poll_fn(|cx| self.poll_next_part(cx)).await
+    }
+
+    fn poll_next_part(&self, cx: &mut Context<'_>) -> Poll<Option<Result<Part, MultipartError>>> {
+        let mut inner = self.inner.lock().expect("multipart lock poisoned");
+        match ready!(inner.poll_next_part(cx)) {
+            Some(Ok(headers)) => {
+                let params = headers
+                    .get(header::CONTENT_DISPOSITION)
+                    .and_then(|cd| cd.to_str().ok())
+                    .map(parse_params)
+                    .unwrap_or_default();
+                let param = |name: &str| {
+                    params
+                        .iter()
+                        .find(|(param, _)| param == name)
+                        .map(|(_, value)| value.clone())
+                };
+
+                let filename = param("filename*")
+                    .and_then(|value| decode_ext_value(&value))
+                    .or_else(|| param("filename"));
+
+                Poll::Ready(Some(Ok(Part {
+                    name: param("name"),
+                    filename,
+                    headers,
+                    index: inner.parts,
+                    inner: self.inner.clone(),
+                })))
+            }
+            Some(Err(err)) => Poll::Ready(Some(Err(err))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl Stream for Multipart {
+    type Item = Result<Part, MultipartError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_next_part(cx)
+    }
+}
+
+impl fmt::Debug for Multipart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Multipart").finish()
+    }
+}
+
+/// One part of a multipart body. Read its body with `AsyncRead`.
+pub struct Part {
+    headers: HeaderMap,
+    name: Option<String>,
+    filename: Option<String>,
+    // which part this is, counting from 1
+    index: usize,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Part {
+    /// The part's headers
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// The form field name, from `Content-Disposition`
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The file name, from `Content-Disposition` (`filename*` if present, else `filename`).
+    ///
+    /// This is sent by the client, so don't use it as a path without checking it.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// The part's `Content-Type`
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|ct| ct.to_str().ok())
+    }
+
+    /// Read the part's body into bytes. Consumes Part.
+    pub async fn into_bytes(mut self) -> Result<Vec<u8>, MultipartError> {
+        let mut buf = Vec::new();
+        self.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Read the part's body into a String. Consumes Part.
+    pub async fn into_string(mut self) -> Result<String, MultipartError> {
+        let mut buf = String::new();
+        self.read_to_string(&mut buf).await?;
+        Ok(buf)
+    }
+}
+
+impl AsyncRead for Part {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = self.inner.lock().expect("multipart lock poisoned");
+        if inner.state == State::Failed {
+            return Poll::Ready(Err(MultipartError::Failed.into()));
+        }
+        // Already skipped by `next_part`
+        if inner.parts != self.index {
+            return Poll::Ready(Ok(0));
+        }
+        inner.poll_read_body(cx, buf).map_err(io::Error::from)
+    }
+}
+
+impl fmt::Debug for Part {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Part")
+            .field("headers", &self.headers)
+            .field("name", &self.name)
+            .field("filename", &self.filename)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Before the first boundary
+    Preamble,
+    // Just after a boundary, before the end of its line
+    Boundary,
+    Headers,
+    Body,
+    // After the closing boundary
+    End,
+    Failed,
+}
+
+// Shared between `Multipart` and its `Part`s. Only one part is read at a time, so it's a simple
+// state machine over one buffer.
+struct Inner {
+    body: Body,
+    // `\r\n--boundary`
+    delimiter: Vec<u8>,
+    // Read from the body, not yet handled
+    buf: Vec<u8>,
+    eof: bool,
+    state: State,
+    config: MultipartConfig,
+    // Parts started so far
+    parts: usize,
+    part_size: u64,
+    total_size: u64,
+}
+
+impl Inner {
+    fn poll_next_part(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<HeaderMap, MultipartError>>> {
+        match self.poll_next_part_inner(cx) {
+            Poll::Ready(Err(err)) => {
+                self.state = State::Failed;
+                Poll::Ready(Some(Err(err)))
+            }
+            Poll::Ready(Ok(headers)) => Poll::Ready(headers.map(Ok)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_next_part_inner(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, MultipartError>> {
+        loop {
+            match self.state {
+                State::Preamble => match find(&self.buf, &self.delimiter) {
+                    Some(i) => {
+                        self.buf.drain(..i + self.delimiter.len());
+                        self.state = State::Boundary;
+                    }
+                    None => {
+                        // Keep what could be the start of the delimiter
+                        let keep = self.delimiter.len() - 1;
+                        if self.buf.len() > keep {
+                            self.buf.drain(..self.buf.len() - keep);
+                        }
+                        ready!(self.poll_fill(cx))?;
+                    }
+                },
+                State::Boundary => {
+                    if self.buf.starts_with(b"--") {
+                        // The epilogue is ignored
+                        self.buf.clear();
+                        self.state = State::End;
+                        continue;
+                    }
+                    match find(&self.buf, b"\r\n") {
+                        Some(i) => {
+                            if !self.buf[..i].iter().all(|b| *b == b' ' || *b == b'\t') {
+                                return Poll::Ready(Err(MultipartError::Malformed(
+                                    "invalid characters after boundary",
+                                )));
+                            }
+                            self.buf.drain(..i + 2);
+                            self.state = State::Headers;
+                        }
+                        None if self.buf.len() > MAX_BOUNDARY_LINE => {
+                            return Poll::Ready(Err(MultipartError::Malformed(
+                                "invalid characters after boundary",
+                            )));
+                        }
+                        None => ready!(self.poll_fill(cx))?,
+                    }
+                }
+                State::Headers => {
+                    let end = if self.buf.starts_with(b"\r\n") {
+                        Some(2)
+                    } else {
+                        find(&self.buf, b"\r\n\r\n").map(|i| i + 4)
+                    };
+                    let max = self.config.max_headers_size;
+                    match end {
+                        Some(end) if end > max => {
+                            return Poll::Ready(Err(MultipartError::HeadersTooLarge(max)));
+                        }
+                        Some(end) => {
+                            let headers = parse_headers(&self.buf[..end])?;
+                            self.buf.drain(..end);
+
+                            self.parts += 1;
+                            if self.parts > self.config.max_parts {
+                                return Poll::Ready(Err(MultipartError::TooManyParts(
+                                    self.config.max_parts,
+                                )));
+                            }
+                            self.part_size = 0;
+                            self.state = State::Body;
+                            return Poll::Ready(Ok(Some(headers)));
+                        }
+                        None if self.buf.len() > max => {
+                            return Poll::Ready(Err(MultipartError::HeadersTooLarge(max)));
+                        }
+                        None => ready!(self.poll_fill(cx))?,
+                    }
+                }
+                State::Body => {
+                    // Skip the rest of the current part
+                    let mut scratch = [0; 4096];
+                    ready!(self.poll_read_body(cx, &mut scratch))?;
+                }
+                State::End | State::Failed => return Poll::Ready(Ok(None)),
+            }
+        }
+    }
+
+    // Read from the current part's body; 0 at its end.
+    fn poll_read_body(
+        &mut self,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<Result<usize, MultipartError>> {
+        let res = self.poll_read_body_inner(cx, out);
+        if let Poll::Ready(Err(_)) = res {
+            self.state = State::Failed;
+        }
+        res
+    }
+
+    fn poll_read_body_inner(
+        &mut self,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<Result<usize, MultipartError>> {
+        loop {
+            if self.state != State::Body || out.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            let available = match find(&self.buf, &self.delimiter) {
+                Some(0) => {
+                    self.buf.drain(..self.delimiter.len());
+                    self.state = State::Boundary;
+                    return Poll::Ready(Ok(0));
+                }
+                Some(i) => i,
+                // Hold back what could be the start of the delimiter
+                None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+            };
+
+            if available > 0 {
+                let n = available.min(out.len());
+                out[..n].copy_from_slice(&self.buf[..n]);
+                self.buf.drain(..n);
+
+                self.part_size += n as u64;
+                if let Some(max) = self.config.max_part_size {
+                    if self.part_size > max {
+                        return Poll::Ready(Err(MultipartError::PartTooLarge(max)));
+                    }
+                }
+                return Poll::Ready(Ok(n));
+            }
+
+            ready!(self.poll_fill(cx))?;
+        }
+    }
+
+    // Read more of the body into `buf`. Eof before the closing boundary is an error.
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), MultipartError>> {
+        if self.eof {
+            return Poll::Ready(Err(MultipartError::UnexpectedEof));
+        }
+
+        let mut body = Pin::new(&mut self.body);
+        let data = ready!(body.as_mut().poll_fill_buf(cx)).map_err(BodyError::from)?;
+        let n = data.len();
+        self.buf.extend_from_slice(data);
+        body.consume(n);
+
+        if n == 0 {
+            self.eof = true;
+            return Poll::Ready(Err(MultipartError::UnexpectedEof));
+        }
+
+        self.total_size += n as u64;
+        if let Some(max) = self.config.max_total_size {
+            if self.total_size > max {
+                return Poll::Ready(Err(MultipartError::TooLarge(max)));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn parse_headers(buf: &[u8]) -> Result<HeaderMap, MultipartError> {
+    let mut parsed = [httparse::EMPTY_HEADER; MAX_PART_HEADERS];
+    let parsed = match httparse::parse_headers(buf, &mut parsed) {
+        Ok(httparse::Status::Complete((_, parsed))) => parsed,
+        _ => return Err(MultipartError::Malformed("invalid part headers")),
+    };
+
+    let mut headers = HeaderMap::with_capacity(parsed.len());
+    for h in parsed {
+        let name = HeaderName::from_bytes(h.name.as_bytes())
+            .map_err(|_| MultipartError::Malformed("invalid part headers"))?;
+        let value = HeaderValue::from_bytes(h.value)
+            .map_err(|_| MultipartError::Malformed("invalid part headers"))?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+/// The parameters of a header value like `form-data; name="a"; filename="b.txt"`, with names
+/// lowercased and quoted values unquoted.
+fn parse_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    // skip the type, e.g. `form-data`
+    let mut rest = match value.find(';') {
+        Some(i) => &value[i + 1..],
+        None => return params,
+    };
+
+    while let Some(eq) = rest.find('=') {
+        let name = rest[..eq].trim().to_ascii_lowercase();
+        rest = rest[eq + 1..].trim_start();
+
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    // Browsers don't escape backslashes in file names (they percent-encode `"`
+                    // instead), so only unescape what must be.
+                    '\\' if quoted[i + 1..].starts_with(&['"', '\\'][..]) => {
+                        if let Some((_, escaped)) = chars.next() {
+                            value.push(escaped);
+                        }
+                    }
+                    c => value.push(c),
+                }
+            }
+            rest = &quoted[end..];
+            value
+        } else {
+            let end = rest.find(';').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_owned();
+            rest = &rest[end..];
+            value
+        };
+
+        if !name.is_empty() {
+            params.push((name, value));
+        }
+
+        match rest.find(';') {
+            Some(i) => rest = &rest[i + 1..],
+            None => break,
+        }
+    }
+
+    params
+}
+
+/// Decode an RFC 5987 value like `UTF-8''na%C3%AFve.txt`. Only utf-8 is supported.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut split = value.splitn(3, '\'');
+    let charset = split.next()?;
+    let _language = split.next()?;
+    let encoded = split.next()?;
+
+    if !charset.eq_ignore_ascii_case("utf-8") {
+        return None;
+    }
+    String::from_utf8(percent_decode(encoded)?).ok()
+}
+
+/// Error reading a multipart body.
+#[derive(Debug)]
+pub enum MultipartError {
+    /// The request's `Content-Type` isn't multipart.
+    NotMultipart,
+    /// The `Content-Type` has a missing or invalid boundary.
+    InvalidBoundary,
+    /// The body isn't valid multipart.
+    Malformed(&'static str),
+    /// The body ended before the closing boundary.
+    UnexpectedEof,
+    /// A part's body was larger than `MultipartConfig::max_part_size`.
+    PartTooLarge(u64),
+    /// The body was larger than `MultipartConfig::max_total_size`.
+    TooLarge(u64),
+    /// There were more parts than `MultipartConfig::max_parts`.
+    TooManyParts(usize),
+    /// A part's headers were larger than `MultipartConfig::max_headers_size`.
+    HeadersTooLarge(usize),
+    /// Reading the request body failed.
+    Body(BodyError),
+    /// An earlier error stopped the multipart body from being read.
+    Failed,
+}
+
+impl MultipartError {
+    /// The response status for this error: 415 if not multipart, 413 for the limits, and
+    /// otherwise 400.
+    pub fn status(&self) -> StatusCode {
+        use MultipartError::*;
+        match self {
+            NotMultipart => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            PartTooLarge(_) | TooLarge(_) | TooManyParts(_) | HeadersTooLarge(_) => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Body(BodyError::TooLarge(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    /// A `Glitch` with the status from `status` and the error as message.
+    pub fn into_glitch(self) -> Glitch {
+        Glitch::new_with_status_context(self.status(), self.to_string())
+    }
+}
+
+impl std::error::Error for MultipartError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MultipartError::Body(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use MultipartError::*;
+        match self {
+            NotMultipart => write!(f, "Content-Type is not multipart"),
+            InvalidBoundary => write!(f, "Missing or invalid multipart boundary"),
+            Malformed(msg) => write!(f, "Malformed multipart body: {}", msg),
+            UnexpectedEof => write!(f, "Multipart body ended before the closing boundary"),
+            PartTooLarge(limit) => write!(f, "Multipart part larger than limit of {} bytes", limit),
+            TooLarge(limit) => write!(f, "Multipart body larger than limit of {} bytes", limit),
+            TooManyParts(limit) => write!(f, "More than {} multipart parts", limit),
+            HeadersTooLarge(limit) => {
+                write!(f, "Multipart part headers larger than limit of {} bytes", limit)
+            }
+            Body(err) => write!(f, "Error reading multipart body: {}", err),
+            Failed => write!(f, "Multipart body failed on an earlier error"),
+        }
+    }
+}
+
+impl From<BodyError> for MultipartError {
+    fn from(err: BodyError) -> Self {
+        MultipartError::Body(err)
+    }
+}
+
+impl From<MultipartError> for io::Error {
+    fn from(err: MultipartError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// When reading a `Part` as `AsyncRead`, use this conversion to recover a `MultipartError` from
+/// the `io::Error`.
+impl From<io::Error> for MultipartError {
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().map(|inner| inner.is::<MultipartError>()).unwrap_or(false) {
+            let inner = err.into_inner().expect("checked inner error exists");
+            *inner.downcast::<MultipartError>().expect("checked inner error is MultipartError")
+        } else {
+            MultipartError::Body(BodyError::from(err))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures_lite::{io::BufReader, StreamExt};
+
+    const BODY: &str = "preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        a\r\n--Xy look-alike\r\n\
+        --XyZ  \r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        file contents\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    // Reads one byte at a time, to split boundaries across reads.
+    struct Trickle(&'static [u8]);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Poll::Ready(Ok(1))
+        }
+    }
+
+    fn trickle(body: &'static str) -> Body {
+        Body::from_reader(BufReader::with_capacity(1, Trickle(body.as_bytes())), None)
+    }
+
+    #[test]
+    fn test_parse_params() {
+        let params = parse_params(r#"form-data; name="a;b"; filename="C:\dir\x \"y\".txt""#);
+        assert_eq!(params[0], ("name".into(), "a;b".into()));
+        assert_eq!(params[1], ("filename".into(), r#"C:\dir\x "y".txt"#.into()));
+
+        let params = parse_params("multipart/form-data; Boundary=abc ; charset=utf-8");
+        assert_eq!(params[0], ("boundary".into(), "abc".into()));
+        assert_eq!(params[1], ("charset".into(), "utf-8".into()));
+
+        assert_eq!(decode_ext_value("UTF-8''na%C3%AFve.txt").unwrap(), "naïve.txt");
+        assert_eq!(decode_ext_value("iso-8859-1'en'x"), None);
+    }
+
+    #[test]
+    fn test_boundary() {
+        let mut headers = HeaderMap::new();
+        assert!(matches!(boundary(&headers), Err(MultipartError::NotMultipart)));
+        headers.insert(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+        assert!(matches!(boundary(&headers), Err(MultipartError::NotMultipart)));
+        headers.insert(header::CONTENT_TYPE, "multipart/form-data".parse().unwrap());
+        assert!(matches!(boundary(&headers), Err(MultipartError::InvalidBoundary)));
+        let content_type = "multipart/form-data; boundary=\"a b\"".parse().unwrap();
+        headers.insert(header::CONTENT_TYPE, content_type);
+        assert_eq!(boundary(&headers).unwrap(), "a b");
+    }
+
+    #[test]
+    fn test_split_reads() {
+        smol::block_on(async {
+            let mut multipart = Multipart::new(trickle(BODY), "XyZ");
+
+            let part = multipart.next().await.unwrap().unwrap();
+            assert_eq!(part.name(), Some("title"));
+            assert_eq!(part.into_string().await.unwrap(), "a\r\n--Xy look-alike");
+
+            let part = multipart.next().await.unwrap().unwrap();
+            assert_eq!(part.name(), Some("file"));
+            assert_eq!(part.filename(), Some("a.txt"));
+            assert_eq!(part.content_type(), Some("text/plain"));
+            assert_eq!(part.into_string().await.unwrap(), "file contents");
+
+            assert!(multipart.next().await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_skip_part() {
+        smol::block_on(async {
+            let mut multipart = Multipart::new(trickle(BODY), "XyZ");
+
+            let mut first = multipart.next_part().await.unwrap().unwrap();
+            let second = multipart.next_part().await.unwrap().unwrap();
+            assert_eq!(second.name(), Some("file"));
+
+            // skipped, so empty
+            let mut buf = Vec::new();
+            first.read_to_end(&mut buf).await.unwrap();
+            assert!(buf.is_empty());
+
+            assert_eq!(second.into_string().await.unwrap(), "file contents");
+        });
+    }
+}
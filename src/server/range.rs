@@ -0,0 +1,463 @@
+//! Range requests
+//!
+//! For a body which can seek (e.g. a file), `ResponseWriter::set_ranged_body` answers a GET
+//! request's `Range` header with `206 Partial Content`:
+//! - one range is sent as is, with `Content-Range`.
+//! - several ranges are sent as `multipart/byteranges`, each part with its own `Content-Range`
+//!   and the response's `Content-Type`. Overlapping or adjacent ranges are merged first.
+//! - if no range fits in the body, responds `416 Range Not Satisfiable`.
+//!
+//! The whole body is sent instead (with the status already set) if there's no `Range`, it's not
+//! a `bytes` range or can't be parsed, the request isn't a GET, the status isn't 200, or an
+//! `If-Range` doesn't match the response's `ETag` or `Last-Modified`. So set those headers
+//! before `set_ranged_body`.
+//!
+//! `Accept-Ranges: bytes` is always set.
+//!
+//! `parse_range` is also available, for handlers which serve ranges some other way.
+
+use futures_lite::io::{AsyncRead, AsyncSeek, BufReader, SeekFrom};
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use crate::body::Body;
+use crate::request::Request;
+use crate::response::Response;
+
+// More ranges than this (after merging) are ignored, and the whole body is sent.
+const MAX_RANGES: usize = 64;
+
+/// A range of bytes in a body, from `start` to `end` inclusive (as in `Content-Range`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    /// First byte
+    pub start: u64,
+    /// Last byte, included
+    pub end: u64,
+}
+
+impl ByteRange {
+    fn size(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    fn content_range(&self, complete_len: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, complete_len)
+    }
+}
+
+/// Error from parsing a `Range` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeError {
+    /// Not a valid `bytes` range; the header should be ignored.
+    Invalid,
+    /// Valid, but none of the ranges overlap the body. Respond with 416.
+    Unsatisfiable,
+}
+
+impl std::error::Error for RangeError {}
+
+impl fmt::Display for RangeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RangeError::Invalid => write!(f, "Invalid range"),
+            RangeError::Unsatisfiable => write!(f, "Range not satisfiable"),
+        }
+    }
+}
+
+/// Parse a `Range` header value (e.g. `bytes=0-99,200-`) for a body of `len` bytes.
+///
+/// Ranges are clamped to the body, and those entirely past its end are dropped. Returned in the
+/// order requested, without merging.
+pub fn parse_range(value: &str, len: u64) -> Result<Vec<ByteRange>, RangeError> {
+    let mut value = value.trim().splitn(2, '=');
+    let unit = value.next().ok_or(RangeError::Invalid)?;
+    let specs = value.next().ok_or(RangeError::Invalid)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(RangeError::Invalid);
+    }
+
+    let parse_pos = |s: &str| -> Result<u64, RangeError> {
+        if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(RangeError::Invalid);
+        }
+        s.parse().map_err(|_| RangeError::Invalid)
+    };
+
+    let mut ranges = Vec::new();
+    let mut any = false;
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        any = true;
+        let mut spec = spec.splitn(2, '-');
+        let first = spec.next().ok_or(RangeError::Invalid)?.trim();
+        let last = spec.next().ok_or(RangeError::Invalid)?.trim();
+
+        if first.is_empty() {
+            // suffix: the last `n` bytes
+            let n = parse_pos(last)?;
+            if n > 0 && len > 0 {
+                ranges.push(ByteRange {
+                    start: len.saturating_sub(n),
+                    end: len - 1,
+                });
+            }
+        } else {
+            let start = parse_pos(first)?;
+            let end = if last.is_empty() {
+                u64::MAX
+            } else {
+                parse_pos(last)?
+            };
+            if end < start {
+                return Err(RangeError::Invalid);
+            }
+            if start < len {
+                ranges.push(ByteRange {
+                    start,
+                    end: std::cmp::min(end, len - 1),
+                });
+            }
+        }
+    }
+
+    if !any {
+        Err(RangeError::Invalid)
+    } else if ranges.is_empty() {
+        Err(RangeError::Unsatisfiable)
+    } else {
+        Ok(ranges)
+    }
+}
+
+// Sort, and merge ranges which overlap or touch.
+fn merge(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = std::cmp::max(last.end, range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Whether an `If-Range` value matches the response's validators. An entity tag must match the
+/// `ETag` strongly, a date must equal `Last-Modified`.
+fn if_range_matches(if_range: &HeaderValue, resp_headers: &HeaderMap) -> bool {
+    let if_range = match if_range.to_str() {
+        Ok(if_range) => if_range.trim(),
+        Err(_) => return false,
+    };
+
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        if if_range.starts_with("W/") {
+            return false;
+        }
+        resp_headers
+            .get(header::ETAG)
+            .map(|etag| etag.as_bytes() == if_range.as_bytes())
+            .unwrap_or(false)
+    } else {
+        let parse_date = |s: &str| httpdate::parse_http_date(s).ok();
+        let last_modified: Option<SystemTime> = resp_headers
+            .get(header::LAST_MODIFIED)
+            .and_then(|lm| lm.to_str().ok())
+            .and_then(parse_date);
+        match (parse_date(if_range), last_modified) {
+            (Some(date), Some(last_modified)) => date == last_modified,
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn set_ranged_body<R>(
+    response: &mut Response,
+    req: &Request,
+    source: R,
+    len: u64,
+) where
+    R: AsyncRead + AsyncSeek + Unpin + Send + Sync + 'static,
+{
+    response
+        .headers_mut()
+        .insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let ranges = if req.method() == Method::GET && response.status() == StatusCode::OK {
+        requested_ranges(req.headers(), response.headers(), len)
+    } else {
+        None
+    };
+
+    let ranges = match ranges {
+        None => {
+            *response.body_mut() = Body::from_reader(BufReader::new(source), Some(len as usize));
+            return;
+        }
+        Some(Err(_)) => {
+            *response.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+            let content_range = format!("bytes */{}", len).parse().expect("valid header value");
+            response.headers_mut().insert(header::CONTENT_RANGE, content_range);
+            *response.body_mut() = Body::empty();
+            return;
+        }
+        Some(Ok(ranges)) => ranges,
+    };
+
+    *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+
+    if let [range] = ranges[..] {
+        let content_range = range.content_range(len).parse().expect("valid header value");
+        response.headers_mut().insert(header::CONTENT_RANGE, content_range);
+        let parts = vec![Part {
+            head: Vec::new(),
+            range,
+        }];
+        let reader = RangesReader::new(source, parts, Vec::new());
+        let size = range.size() as usize;
+        *response.body_mut() = Body::from_reader(BufReader::new(reader), Some(size));
+        return;
+    }
+
+    let boundary = boundary();
+    let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+
+    let parts: Vec<Part> = ranges
+        .into_iter()
+        .map(|range| {
+            let mut head = format!("\r\n--{}\r\n", boundary).into_bytes();
+            if let Some(content_type) = &content_type {
+                head.extend_from_slice(b"Content-Type: ");
+                head.extend_from_slice(content_type.as_bytes());
+                head.extend_from_slice(b"\r\n");
+            }
+            let content_range = format!("Content-Range: {}\r\n\r\n", range.content_range(len));
+            head.extend_from_slice(content_range.as_bytes());
+            Part { head, range }
+        })
+        .collect();
+    let end = format!("\r\n--{}--\r\n", boundary).into_bytes();
+
+    let body_len = parts
+        .iter()
+        .map(|part| part.head.len() as u64 + part.range.size())
+        .sum::<u64>()
+        + end.len() as u64;
+
+    let multipart_type = format!("multipart/byteranges; boundary={}", boundary);
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, multipart_type.parse().expect("valid header value"));
+    let reader = RangesReader::new(source, parts, end);
+    *response.body_mut() = Body::from_reader(BufReader::new(reader), Some(body_len as usize));
+}
+
+// `None` if the whole body should be sent. An invalid range is treated as no range, as is one
+// with more ranges than allowed.
+fn requested_ranges(
+    req_headers: &HeaderMap,
+    resp_headers: &HeaderMap,
+    len: u64,
+) -> Option<Result<Vec<ByteRange>, RangeError>> {
+    let range = req_headers.get(header::RANGE)?.to_str().ok()?;
+
+    if let Some(if_range) = req_headers.get(header::IF_RANGE) {
+        if !if_range_matches(if_range, resp_headers) {
+            return None;
+        }
+    }
+
+    match parse_range(range, len) {
+        Ok(ranges) => {
+            let ranges = merge(ranges);
+            if ranges.len() > MAX_RANGES {
+                None
+            } else {
+                Some(Ok(ranges))
+            }
+        }
+        Err(RangeError::Invalid) => None,
+        Err(err) => Some(Err(err)),
+    }
+}
+
+// Unique enough not to show up in the body by accident.
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("tophat-{:016x}{:08x}", nanos, count)
+}
+
+struct Part {
+    // boundary and headers, before the range's bytes
+    head: Vec<u8>,
+    range: ByteRange,
+}
+
+enum ReadState {
+    Head(usize),
+    Seek,
+    Body(u64),
+    End(usize),
+}
+
+// Reads each part's head and then its range from the source, then `end`.
+struct RangesReader<R> {
+    source: R,
+    parts: std::vec::IntoIter<Part>,
+    part: Option<Part>,
+    end: Vec<u8>,
+    state: ReadState,
+}
+
+impl<R> RangesReader<R> {
+    fn new(source: R, parts: Vec<Part>, end: Vec<u8>) -> Self {
+        let mut parts = parts.into_iter();
+        let part = parts.next();
+        Self {
+            source,
+            parts,
+            part,
+            end,
+            state: ReadState::Head(0),
+        }
+    }
+}
+
+impl<R> AsyncRead for RangesReader<R>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        loop {
+            let part = match &this.part {
+                Some(part) => part,
+                None => {
+                    let pos = match this.state {
+                        ReadState::End(pos) => pos,
+                        _ => 0,
+                    };
+                    let n = std::cmp::min(buf.len(), this.end.len() - pos);
+                    buf[..n].copy_from_slice(&this.end[pos..pos + n]);
+                    this.state = ReadState::End(pos + n);
+                    return Poll::Ready(Ok(n));
+                }
+            };
+
+            match this.state {
+                ReadState::Head(pos) if pos < part.head.len() => {
+                    let n = std::cmp::min(buf.len(), part.head.len() - pos);
+                    buf[..n].copy_from_slice(&part.head[pos..pos + n]);
+                    this.state = ReadState::Head(pos + n);
+                    return Poll::Ready(Ok(n));
+                }
+                ReadState::Head(_) => this.state = ReadState::Seek,
+                ReadState::Seek => {
+                    let start = part.range.start;
+                    match Pin::new(&mut this.source).poll_seek(cx, SeekFrom::Start(start)) {
+                        Poll::Ready(Ok(_)) => this.state = ReadState::Body(part.range.size()),
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                ReadState::Body(0) => {
+                    this.part = this.parts.next();
+                    this.state = ReadState::Head(0);
+                }
+                ReadState::Body(remaining) => {
+                    if buf.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    let max = std::cmp::min(buf.len() as u64, remaining) as usize;
+                    match Pin::new(&mut this.source).poll_read(cx, &mut buf[..max]) {
+                        Poll::Ready(Ok(0)) => {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()))
+                        }
+                        Poll::Ready(Ok(n)) => {
+                            this.state = ReadState::Body(remaining - n as u64);
+                            return Poll::Ready(Ok(n));
+                        }
+                        other => return other,
+                    }
+                }
+                ReadState::End(_) => unreachable!("end is only read after the last part"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn range(start: u64, end: u64) -> ByteRange {
+        ByteRange { start, end }
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(vec![range(0, 99)]));
+        assert_eq!(parse_range("bytes=900-", 1000), Ok(vec![range(900, 999)]));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(vec![range(900, 999)]));
+        assert_eq!(parse_range("bytes=-2000", 1000), Ok(vec![range(0, 999)]));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Ok(vec![range(990, 999)]));
+        assert_eq!(
+            parse_range("Bytes = 0-0, -1 ,", 1000),
+            Ok(vec![range(0, 0), range(999, 999)])
+        );
+        // unsatisfiable ranges are dropped
+        assert_eq!(parse_range("bytes=0-9,1000-", 1000), Ok(vec![range(0, 9)]));
+
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(RangeError::Unsatisfiable));
+        assert_eq!(parse_range("bytes=0-", 0), Err(RangeError::Unsatisfiable));
+
+        assert_eq!(parse_range("bytes=9-0", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=a-b", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=+1-2", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range("bytes=1", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range("items=0-9", 1000), Err(RangeError::Invalid));
+        assert_eq!(parse_range("0-9", 1000), Err(RangeError::Invalid));
+    }
+
+    #[test]
+    fn test_merge() {
+        assert_eq!(
+            merge(vec![range(50, 59), range(0, 9), range(5, 19), range(20, 29)]),
+            vec![range(0, 29), range(50, 59)]
+        );
+    }
+
+    #[test]
+    fn test_if_range_matches() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, "\"abc\"".parse().unwrap());
+        headers.insert(header::LAST_MODIFIED, "Sun, 06 Nov 1994 08:49:37 GMT".parse().unwrap());
+
+        let matches = |if_range: &str| if_range_matches(&if_range.parse().unwrap(), &headers);
+        assert!(matches("\"abc\""));
+        assert!(!matches("\"abd\""));
+        assert!(!matches("W/\"abc\""));
+        assert!(matches("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!matches("Sun, 06 Nov 1994 08:49:38 GMT"));
+        assert!(!matches("yesterday"));
+    }
+}
@@ -1,17 +1,31 @@
-use futures_lite::{io, AsyncWrite};
+use futures_lite::{io, AsyncRead, AsyncSeek, AsyncWrite};
 use futures_util::TryStreamExt;
 use http::{
-    header::{HeaderMap, HeaderValue, IntoHeaderName},
+    header::{self, HeaderMap, HeaderValue, IntoHeaderName},
+    method::Method,
     status::StatusCode,
     version::Version,
 };
+use std::time::Duration;
 use tracing::error;
 
 use crate::body::Body;
+use crate::request::Request;
 use crate::response::Response;
+use crate::timeout::TimeoutWriter;
+use crate::util::{copy_flushing, empty, header_has_token};
 
+use super::body_writer::BodyWriter;
+#[cfg(feature = "compression")]
+use super::compression::Compression;
+use super::conditional::{self, Preconditions};
+use super::conn::BodySlot;
 use super::encode::Encoder;
 use super::glitch::Glitch;
+use super::range;
+use super::shutdown::Shutdown;
+use super::upgrade::Upgraded;
+use super::ServerOpts;
 
 pin_project_lite::pin_project! {
     pub(crate) struct InnerResponse {
@@ -36,16 +50,60 @@ impl InnerResponse {
         }
     }
 
-    /// used for version not supported in decoding. 505
-    pub(crate) fn version_not_supported() -> Self {
+    /// used for content-length over the body size limit in decoding. 413
+    pub(crate) fn payload_too_large() -> Self {
         Self {
-            status: StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            status: StatusCode::PAYLOAD_TOO_LARGE,
             headers: HeaderMap::new(),
             version: Version::default(),
             body: Body::empty(),
         }
     }
 
+    /// used for a request head which doesn't arrive in time. 408
+    pub(crate) fn request_timeout() -> Self {
+        Self {
+            status: StatusCode::REQUEST_TIMEOUT,
+            headers: HeaderMap::new(),
+            version: Version::default(),
+            body: Body::empty(),
+        }
+    }
+
+    /// used for request-target over the limit in decoding. 414
+    pub(crate) fn uri_too_long() -> Self {
+        Self {
+            status: StatusCode::URI_TOO_LONG,
+            headers: HeaderMap::new(),
+            version: Version::default(),
+            body: Body::empty(),
+        }
+    }
+
+    /// used for request head or header count over the limit in decoding. 431
+    pub(crate) fn request_header_fields_too_large() -> Self {
+        Self {
+            status: StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            headers: HeaderMap::new(),
+            version: Version::default(),
+            body: Body::empty(),
+        }
+    }
+
+    /// used for a request content-encoding which can't be decompressed. 415
+    #[cfg(feature = "compression")]
+    pub(crate) fn unsupported_media_type() -> Self {
+        let mut headers = HeaderMap::new();
+        // https://tools.ietf.org/html/rfc7694#section-3
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip, deflate, br"));
+        Self {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            headers,
+            version: Version::default(),
+            body: Body::empty(),
+        }
+    }
+
     /// used for unimplemented transfer-encoding in decoding. 501
     pub(crate) fn not_implemented() -> Self {
         Self {
@@ -56,13 +114,17 @@ impl InnerResponse {
         }
     }
 
-    pub(crate) async fn send<W>(self, writer: W) -> Result<ResponseWritten, std::io::Error>
+    pub(crate) async fn send<W>(
+        self,
+        writer: W,
+        req_info: &RequestInfo,
+    ) -> Result<ResponseWritten, std::io::Error>
     where
         W: AsyncWrite + Clone + Send + Sync + Unpin + 'static,
     {
-        let mut encoder = Encoder::encode(self);
-        let mut writer = writer;
-        let bytes_written = match io::copy(&mut encoder, &mut writer).await {
+        let mut encoder = Encoder::encode(self, req_info);
+        let mut writer = TimeoutWriter::new(writer, req_info.write_timeout, "Response write timeout");
+        let bytes_written = match copy_flushing(&mut encoder, &mut writer).await {
             Ok(b) => b,
             Err(err) => {
                 // only log, don't break connection here. If connection is really closed, then the
@@ -72,7 +134,93 @@ impl InnerResponse {
             }
         };
 
-        Ok(ResponseWritten { bytes_written })
+        Ok(ResponseWritten {
+            bytes_written,
+            keep_alive: encoder.keep_alive(),
+        })
+    }
+}
+
+/// The parts of a request (and its connection's options) which are needed to write its response.
+#[derive(Debug, Clone)]
+pub(crate) struct RequestInfo {
+    /// A response to HEAD has no body.
+    pub(crate) method: Method,
+    pub(crate) version: Version,
+    /// Whether the client allows the connection to stay open after the response.
+    pub(crate) keep_alive: bool,
+    /// If shutdown is signaled before the response is written, the connection closes after it.
+    pub(crate) shutdown: Option<Shutdown>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) trailers_timeout: Option<Duration>,
+    /// The protocols the client asked to upgrade to, if any.
+    pub(crate) upgrade: Option<HeaderValue>,
+    /// The request's `If-Match` etc., checked against the response's validators.
+    pub(crate) preconditions: Option<Preconditions>,
+    #[cfg(feature = "compression")]
+    pub(crate) accept_encoding: Option<HeaderValue>,
+    #[cfg(feature = "compression")]
+    pub(crate) compression: Option<Compression>,
+}
+
+impl RequestInfo {
+    /// Used when responding without a request, e.g. when a request fails to decode.
+    pub(crate) fn new(opts: &ServerOpts) -> Self {
+        Self {
+            method: Method::GET,
+            version: Version::HTTP_11,
+            keep_alive: true,
+            shutdown: opts.shutdown.clone(),
+            write_timeout: opts.write_timeout,
+            trailers_timeout: opts.trailers_timeout,
+            upgrade: None,
+            preconditions: None,
+            #[cfg(feature = "compression")]
+            accept_encoding: None,
+            #[cfg(feature = "compression")]
+            compression: opts.compression.clone(),
+        }
+    }
+
+    /// Persistence follows https://tools.ietf.org/html/rfc7230#section-6.3: HTTP/1.1 connections
+    /// stay open unless the client sends `Connection: close`, HTTP/1.0 connections only stay open
+    /// with `Connection: keep-alive`.
+    pub(crate) fn from_request(req: &Request, opts: &ServerOpts) -> Self {
+        let headers = req.headers();
+        let keep_alive = if header_has_token(headers, header::CONNECTION, "close") {
+            false
+        } else if req.version() == Version::HTTP_10 {
+            header_has_token(headers, header::CONNECTION, "keep-alive")
+        } else {
+            true
+        };
+
+        // Upgrades are only for HTTP/1.1, and need `Connection: upgrade` too.
+        // https://tools.ietf.org/html/rfc7230#section-6.7
+        let upgrade = if req.version() == Version::HTTP_11
+            && header_has_token(headers, header::CONNECTION, "upgrade")
+        {
+            headers.get(header::UPGRADE).cloned()
+        } else {
+            None
+        };
+
+        Self {
+            method: req.method().clone(),
+            version: req.version(),
+            keep_alive,
+            upgrade,
+            preconditions: Preconditions::from_headers(headers),
+            #[cfg(feature = "compression")]
+            accept_encoding: headers.get(header::ACCEPT_ENCODING).cloned(),
+            ..Self::new(opts)
+        }
+    }
+
+    /// Whether the connection can stay open after the response, as far as the request is
+    /// concerned.
+    pub(crate) fn keep_alive(&self) -> bool {
+        self.keep_alive && !self.shutdown.as_ref().map(Shutdown::is_signaled).unwrap_or(false)
     }
 }
 
@@ -83,14 +231,25 @@ impl InnerResponse {
 /// A `ResponseWriter` is initialized with a `Response` that contains:
 /// - An empty body
 /// - No headers (except that content-type defaults to `application/octet-stream` if not specified
-/// and there's a body)`
+///   and there's a body)`
 /// - A 200 OK status
+/// - The HTTP version of the request
 ///
 /// You can modify the `Response` as they see fit. Note, however, that a `Body` is not
 /// necessarily in sync with the `content-type` headers that are sent. for example, it's possible
 /// to set the Body using a string, and then set the content-type header on the Response to be
 /// `content-type: video/mp4'. The power is in the your hands.
 ///
+/// The connection is kept alive after the response is sent, unless the client asked for it to be
+/// closed, or a `Connection: close` header is set on the `Response`.
+///
+/// The body is never sent in response to a `HEAD` request, though its length is. Responses with
+/// a 1xx, 204, or 304 status have neither a body nor `content-length`/`transfer-encoding`.
+///
+/// To write the body bit by bit after sending the head, use `start_stream`.
+///
+/// To switch the connection to another protocol (e.g. websockets), use `upgrade`.
+///
 /// There are two convenience methods which will set the content-type:
 /// - `set_text`, because there's no guess as to content-type, and
 /// - `set_sse`, because the content-type `text/event-stream` is required.
@@ -112,6 +271,8 @@ where
 {
     pub(crate) response: Response,
     pub(crate) writer: W,
+    pub(crate) req_info: RequestInfo,
+    pub(crate) body_slot: BodySlot<W>,
 }
 
 impl<W> ResponseWriter<W>
@@ -119,17 +280,77 @@ where
     W: AsyncWrite + Clone + Send + Sync + Unpin + 'static,
 {
     /// send response, and return number of bytes written
-    pub async fn send(self) -> Result<ResponseWritten, Glitch> {
+    pub async fn send(mut self) -> Result<ResponseWritten, Glitch> {
+        self.close_if_undrained().await;
         let (parts, body) = self.response.into_parts();
 
-        let inner_resp = InnerResponse {
+        let mut inner_resp = InnerResponse {
             status: parts.status,
             headers: parts.headers,
             version: parts.version,
             body,
         };
 
-        Ok(inner_resp.send(self.writer).await?)
+        if let Some(preconditions) = &self.req_info.preconditions {
+            preconditions.evaluate(
+                &self.req_info.method,
+                &mut inner_resp.status,
+                &mut inner_resp.headers,
+                &mut inner_resp.body,
+            );
+        }
+
+        #[cfg(feature = "compression")]
+        if let Some(compression) = &self.req_info.compression {
+            compression.compress(
+                self.req_info.accept_encoding.as_ref(),
+                inner_resp.status,
+                &mut inner_resp.headers,
+                &mut inner_resp.body,
+            );
+        }
+
+        Ok(inner_resp.send(self.writer, &self.req_info).await?)
+    }
+
+    /// Send the response head now, and write the body afterwards with the returned `BodyWriter`.
+    ///
+    /// Any body already set on the response is ignored. The body is sent with
+    /// `transfer-encoding: chunked`, so a `content-length` header is not sent either.
+    pub async fn start_stream(mut self) -> Result<BodyWriter<W>, Glitch> {
+        self.close_if_undrained().await;
+        let (parts, _) = self.response.into_parts();
+
+        let inner_resp = InnerResponse {
+            status: parts.status,
+            headers: parts.headers,
+            version: parts.version,
+            body: Body::from_reader(empty(), None),
+        };
+
+        let mut encoder = Encoder::encode(inner_resp, &self.req_info).head_only();
+        let mut writer =
+            TimeoutWriter::new(self.writer, self.req_info.write_timeout, "Response write timeout");
+        let head_bytes_written = io::copy(&mut encoder, &mut writer).await.map_err(|err| {
+            error!("Error sending response: {}", err);
+            err
+        })?;
+
+        Ok(BodyWriter::new(
+            writer,
+            encoder.body_framing(),
+            head_bytes_written,
+            encoder.keep_alive(),
+            self.body_slot,
+        ))
+    }
+
+    // Announce the close if too much of the request body is left for the connection to be used
+    // again, instead of promising keep-alive and closing anyway.
+    async fn close_if_undrained(&mut self) {
+        if !self.body_slot.will_drain().await {
+            self.req_info.keep_alive = false;
+        }
     }
 
     /// Sets response to specified code and immediately sends.
@@ -230,10 +451,94 @@ where
         self
     }
 
+    /// Set response to:
+    /// - Content-type application/json
+    /// - Body from `value` serialized as JSON
+    ///
+    /// Fails if `value` can't be serialized, e.g. a map with non-string keys.
+    #[cfg(feature = "json")]
+    pub fn set_json<T: serde::Serialize + ?Sized>(
+        &mut self,
+        value: &T,
+    ) -> Result<&mut Self, serde_json::Error> {
+        let bytes = serde_json::to_vec(value)?;
+        self.insert_header(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        Ok(self.set_body(Body::from_bytes(bytes)))
+    }
+
+    /// Sets the response body as a stream of newline-delimited JSON (NDJSON): each item is
+    /// serialized as one line. Adds the content-type header `application/x-ndjson`.
+    ///
+    /// The body is sent chunked, and each line is sent (and flushed) as soon as the item is
+    /// produced. Items are only pulled from the stream as the client reads them.
+    ///
+    /// If an item can't be serialized, the response is cut off there.
+    #[cfg(feature = "json")]
+    pub fn set_ndjson<S>(&mut self, stream: S) -> &mut Self
+    where
+        S: futures_lite::Stream + Send + Sync + Unpin + 'static,
+        S::Item: serde::Serialize,
+    {
+        use futures_lite::StreamExt;
+
+        let lines = stream
+            .map(|item| {
+                let mut line = serde_json::to_vec(&item)?;
+                line.push(b'\n');
+                Ok::<_, std::io::Error>(line)
+            })
+            .into_async_read();
+
+        self.set_body(Body::from_reader(lines, None));
+        self.insert_header(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/x-ndjson"),
+        );
+        self
+    }
+
+    /// Compress the response body as the client accepts, see the `compression` module. Call
+    /// after the body and content-type are set.
+    ///
+    /// Sets the `content-encoding` and `vary` headers, and removes `content-length`.
+    #[cfg(feature = "compression")]
+    pub fn compress(&mut self, compression: &Compression) -> &mut Self {
+        let status = self.response.status();
+        let mut body = std::mem::replace(self.response.body_mut(), Body::empty());
+        compression.compress(
+            self.req_info.accept_encoding.as_ref(),
+            status,
+            self.response.headers_mut(),
+            &mut body,
+        );
+        *self.response.body_mut() = body;
+        self
+    }
+
+    /// Set the body from bytes, with a strong `etag` computed from them. A request with a
+    /// matching `If-None-Match` then gets a `304 Not Modified`, see the `conditional` module.
+    pub fn set_body_with_etag(&mut self, bytes: Vec<u8>) -> &mut Self {
+        self.insert_header(header::ETAG, conditional::etag(&bytes));
+        self.set_body(Body::from_bytes(bytes))
+    }
+
+    /// Set the body from a seekable source (e.g. a file) of `len` bytes, sending only the parts
+    /// asked for in the request's `Range`. See the `range` module.
+    ///
+    /// Call after setting the status and the `content-type`, `etag`, and `last-modified` headers.
+    pub fn set_ranged_body<R>(&mut self, req: &Request, source: R, len: u64) -> &mut Self
+    where
+        R: AsyncRead + AsyncSeek + Unpin + Send + Sync + 'static,
+    {
+        range::set_ranged_body(&mut self.response, req, source, len);
+        self
+    }
+
     /// Sets the response body as a Server Sent Events response stream.
     /// Adds the content-type header for SSE.
     ///
-    /// Takes a `futures::Stream`, and `futures::TryStreamExt` must be in scope.
+    /// Takes a `futures::Stream`, and `futures::TryStreamExt` must be in scope. For encoded
+    /// events and pings, use an `EventStream` from the `sse` module.
     pub fn set_sse<S>(&mut self, stream: S)
     where
         S: TryStreamExt<Error = std::io::Error> + Send + Sync + Unpin + 'static,
@@ -246,9 +551,60 @@ where
     }
 }
 
+impl<W> ResponseWriter<W>
+where
+    W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    /// Switch the connection to `protocol`, which the client must have asked for in its
+    /// `Upgrade` header. Sends a `101 Switching Protocols`, with the headers already set on the
+    /// response, plus `Upgrade` and `Connection: upgrade`. The body is not sent.
+    ///
+    /// A request body must be dropped (or fully read) first. No more requests are read from the
+    /// connection afterwards, see `Upgraded`.
+    pub async fn upgrade(self, protocol: &str) -> Result<Upgraded<W>, Glitch> {
+        let requested = self
+            .req_info
+            .upgrade
+            .as_ref()
+            .and_then(|upgrade| upgrade.to_str().ok())
+            .map(|upgrade| upgrade.split(',').any(|p| p.trim().eq_ignore_ascii_case(protocol)))
+            .unwrap_or(false);
+        if !requested {
+            return Err(Glitch::new_with_status_context(
+                StatusCode::BAD_REQUEST,
+                format!("Upgrade to {} was not requested", protocol),
+            ));
+        }
+        let upgrade_value: HeaderValue = protocol.parse()?;
+
+        let conn = self.body_slot.upgrade().await.ok_or_else(|| {
+            Glitch::new_with_status_context(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Request body must be dropped before upgrading",
+            )
+        })?;
+        let (io, buffered) = conn.into_parts();
+
+        let (parts, _) = self.response.into_parts();
+        let mut headers = parts.headers;
+        headers.insert(header::UPGRADE, upgrade_value);
+        headers.insert(header::CONNECTION, "upgrade".parse().unwrap());
+        let inner_resp = InnerResponse {
+            status: StatusCode::SWITCHING_PROTOCOLS,
+            headers,
+            version: parts.version,
+            body: Body::empty(),
+        };
+        let resp_written = inner_resp.send(self.writer, &self.req_info).await?;
+
+        Ok(Upgraded::new(io, buffered, resp_written.bytes_written))
+    }
+}
+
 /// A marker to ensure that a response is written inside a request handler.
 pub struct ResponseWritten {
-    bytes_written: u64,
+    pub(crate) bytes_written: u64,
+    pub(crate) keep_alive: bool,
 }
 
 impl ResponseWritten {
@@ -112,6 +112,7 @@ where
     /// - Catch-All parameters. e.g. *any, it must always be at the end of the pattern.
     /// - Supports multiple naming for the same path segment. e.g. /users/:id and /users/:user_id/repos.
     /// - Don't care about routes orders, recursive lookup, Static -> Named -> Catch-All.
+    ///
     /// (path-tree is used as the underlying router)
     pub fn at(self, method: Method, path: &str, endpoint: impl Endpoint<W>) -> Self {
         let mut this = self;
@@ -138,7 +139,7 @@ where
         let mut map = self
             .data
             .take()
-            .unwrap_or_else(type_map::concurrent::TypeMap::new);
+            .unwrap_or_default();
         map.insert(data);
         self.data = Some(map);
         self
@@ -164,7 +165,7 @@ where
         &self,
         req: Request,
         resp_wtr: ResponseWriter<W>,
-    ) -> BoxFuture<Result<ResponseWritten>>;
+    ) -> BoxFuture<'_, Result<ResponseWritten>>;
 }
 
 impl<F: Send + Sync + 'static, Fut, Res, W> Endpoint<W> for F
@@ -178,7 +179,7 @@ where
         &self,
         req: Request,
         resp: ResponseWriter<W>,
-    ) -> BoxFuture<Result<ResponseWritten>> {
+    ) -> BoxFuture<'_, Result<ResponseWritten>> {
         let fut = (self)(req, resp);
         Box::pin(async move {
             let res = fut.await?;
@@ -208,7 +209,7 @@ impl<T> std::ops::Deref for Data<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        &*self.0
+        &self.0
     }
 }
 
@@ -0,0 +1,129 @@
+// Graceful shutdown: signal connections to close, and track them until they drain.
+
+use async_channel::{Receiver, Sender};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::timeout::timeout;
+
+/// Handle to signal shutdown to connections, and to track when they've closed.
+///
+/// Set a `Shutdown` in `ServerOpts::shutdown` for each connection. Once `signal` is called, each
+/// connection finishes the request it's currently handling, responds with `Connection: close`,
+/// and stops reading new requests. Idle connections are closed right away.
+///
+/// `drain` waits for all the connections to finish, with a deadline:
+///
+/// ```rust,no_run
+/// # use std::time::Duration;
+/// # use tophat::server::Shutdown;
+/// # async fn deploy(shutdown: Shutdown) {
+/// // stop accepting new tcp connections first, then
+/// shutdown.signal();
+/// if !shutdown.drain(Duration::from_secs(30)).await {
+///     eprintln!("{} connections still open", shutdown.connection_count());
+/// }
+/// # }
+/// ```
+///
+/// Clones all share the same signal and connection count.
+#[derive(Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    // never sends, closed to signal shutdown
+    signal_tx: Sender<()>,
+    signal_rx: Receiver<()>,
+    connections: AtomicUsize,
+    // notified when connection count drops to zero
+    drained_tx: Sender<()>,
+    drained_rx: Receiver<()>,
+}
+
+impl Shutdown {
+    /// Create a new `Shutdown`
+    pub fn new() -> Self {
+        let (signal_tx, signal_rx) = async_channel::bounded(1);
+        let (drained_tx, drained_rx) = async_channel::bounded(1);
+
+        Self {
+            inner: Arc::new(Inner {
+                signal_tx,
+                signal_rx,
+                connections: AtomicUsize::new(0),
+                drained_tx,
+                drained_rx,
+            }),
+        }
+    }
+
+    /// Signal all connections to shut down.
+    pub fn signal(&self) {
+        self.inner.signal_tx.close();
+    }
+
+    /// Whether shutdown has been signaled.
+    pub fn is_signaled(&self) -> bool {
+        self.inner.signal_tx.is_closed()
+    }
+
+    /// Number of connections which are still open.
+    pub fn connection_count(&self) -> usize {
+        self.inner.connections.load(Ordering::SeqCst)
+    }
+
+    /// Wait until all connections have closed, or until `deadline` has passed.
+    ///
+    /// Returns `true` if all connections closed. Does not signal shutdown itself.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        let drained = async {
+            while self.connection_count() > 0 {
+                let _ = self.inner.drained_rx.recv().await;
+            }
+        };
+
+        timeout(deadline, drained).await.is_ok()
+    }
+
+    /// Resolves once shutdown is signaled.
+    pub(crate) async fn wait(&self) {
+        // only ever returns Err, when the channel is closed
+        let _ = self.inner.signal_rx.recv().await;
+    }
+
+    /// Counts a connection as open until the guard is dropped.
+    pub(crate) fn track_connection(&self) -> ConnectionGuard {
+        self.inner.connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(self.clone())
+    }
+}
+
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shutdown")
+            .field("signaled", &self.is_signaled())
+            .field("connections", &self.connection_count())
+            .finish()
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) struct ConnectionGuard(Shutdown);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let inner = &self.0.inner;
+        if inner.connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let _ = inner.drained_tx.try_send(());
+        }
+    }
+}
@@ -0,0 +1,354 @@
+//! Server Sent Events module
+//!
+//! Events are sent from anywhere through a `Sender`, while the endpoint sends the response with
+//! the matching `EventStream`:
+//!
+//! ```rust,no_run
+//! # use futures_util::io::{AsyncRead, AsyncWrite};
+//! # use tophat::{Request, server::{glitch::Result, ResponseWriter, ResponseWritten}};
+//! use tophat::server::sse::{self, Event};
+//!
+//! async fn clock<W>(req: Request, mut resp_wtr: ResponseWriter<W>) -> Result<ResponseWritten>
+//!     where W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+//! {
+//!     let mut tick: u64 = sse::last_event_id(&req)
+//!         .and_then(|id| id.parse().ok())
+//!         .unwrap_or(0);
+//!
+//!     let (sender, events) = sse::channel();
+//!     smol::spawn(async move {
+//!         loop {
+//!             tick += 1;
+//!             let event = Event::new(tick.to_string()).id(tick.to_string()).event("tick");
+//!             if sender.send(event).await.is_err() {
+//!                 // the client disconnected
+//!                 break;
+//!             }
+//!             smol::Timer::after(std::time::Duration::from_secs(1)).await;
+//!         }
+//!     })
+//!     .detach();
+//!
+//!     resp_wtr.set_sse(events);
+//!     resp_wtr.send().await
+//! }
+//! ```
+//!
+//! The response ends once every `Sender` is dropped. When the client disconnects, the
+//! `EventStream` is dropped and `Sender::send` fails, so producers know to stop.
+//!
+//! While no events are sent, the `EventStream` sends a comment as a ping every
+//! `SseConfig::ping_interval`, so that proxies don't time out the idle connection.
+//!
+//! A reconnecting client sends the id of the last event it received as `Last-Event-ID`; see
+//! `last_event_id`.
+//!
+//! To send the same events to many clients, publish them to a `broadcast::Hub<Event>`, and
+//! `subscribe` each client, which also replays the events it missed.
+
+use futures_lite::{Future, Stream, StreamExt};
+use futures_timer::Delay;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::request::Request;
+use crate::server::broadcast::Hub;
+
+/// Settings for an event stream.
+#[derive(Debug, Clone)]
+pub struct SseConfig {
+    /// How long the stream can be idle before a comment is sent as a ping. `None` for no pings.
+    ///
+    /// Default 15 seconds.
+    pub ping_interval: Option<Duration>,
+    /// How many events can be waiting to be written before `Sender::send` waits.
+    ///
+    /// Default 16.
+    pub capacity: usize,
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Some(Duration::from_secs(15)),
+            capacity: 16,
+        }
+    }
+}
+
+/// The request's `Last-Event-ID`, sent by a client reconnecting to an event stream.
+pub fn last_event_id(req: &Request) -> Option<&str> {
+    req.headers()
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty())
+}
+
+/// A `Sender` for events, and the `EventStream` to send as the response with
+/// `ResponseWriter::set_sse`.
+pub fn channel() -> (Sender, EventStream) {
+    channel_with_config(SseConfig::default())
+}
+
+/// A `Sender` for events, and the `EventStream` to send as the response with
+/// `ResponseWriter::set_sse`, with custom settings.
+pub fn channel_with_config(config: SseConfig) -> (Sender, EventStream) {
+    let (tx, rx) = async_channel::bounded(config.capacity.max(1));
+    (Sender { tx }, EventStream::new(rx, &config))
+}
+
+/// Subscribe to `topic` of `hub`, as the `EventStream` to send as the response.
+///
+/// Each event's id is set to its id in the topic. If the request has a `Last-Event-ID`, the
+/// events after it still in the hub's replay buffer are sent first.
+///
+/// The response ends if the subscriber is disconnected by the hub.
+pub fn subscribe(hub: &Hub<Event>, topic: &str, req: &Request) -> EventStream {
+    subscribe_with_config(hub, topic, req, SseConfig::default())
+}
+
+/// Subscribe to `topic` of `hub`, as the `EventStream` to send as the response, with custom
+/// settings. `SseConfig::capacity` is unused, as the hub's settings apply.
+pub fn subscribe_with_config(
+    hub: &Hub<Event>,
+    topic: &str,
+    req: &Request,
+    config: SseConfig,
+) -> EventStream {
+    let last_id = last_event_id(req).and_then(|id| id.parse().ok());
+    let events = hub
+        .subscribe_after(topic, last_id)
+        .map(|(id, event)| event.id(id.to_string()));
+
+    EventStream::new(events, &config)
+}
+
+/// A Server Sent Event.
+///
+/// Displays as its encoding on the wire, ending with the blank line which dispatches it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    id: Option<String>,
+    event: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+    comment: Option<String>,
+}
+
+impl Event {
+    /// An event with `data`, which can have several lines.
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            data: Some(data.into()),
+            ..Self::default()
+        }
+    }
+
+    /// A comment, which clients ignore. Can have several lines.
+    pub fn comment(comment: impl Into<String>) -> Self {
+        Self {
+            comment: Some(comment.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Set the event's id, which the client sends back as `Last-Event-ID` when reconnecting.
+    /// Line breaks and NUL are removed, as they can't be sent.
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        let id: String = id.into();
+        self.id = Some(id.chars().filter(|c| !matches!(c, '\r' | '\n' | '\0')).collect());
+        self
+    }
+
+    /// Set the event's name (the event type for `addEventListener`). Line breaks are removed, as
+    /// they can't be sent.
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        let event: String = event.into();
+        self.event = Some(event.chars().filter(|c| !matches!(c, '\r' | '\n')).collect());
+        self
+    }
+
+    /// Set how long the client waits before reconnecting, in milliseconds on the wire.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(comment) = &self.comment {
+            for line in lines(comment) {
+                writeln!(f, ": {}", line)?;
+            }
+        }
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", event)?;
+        }
+        if let Some(data) = &self.data {
+            for line in lines(data) {
+                writeln!(f, "data: {}", line)?;
+            }
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {}", id)?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        writeln!(f)
+    }
+}
+
+// Split on any of the line breaks the client splits on: `\r\n`, `\r` or `\n`.
+fn lines(s: &str) -> impl Iterator<Item = &str> {
+    s.split('\n').flat_map(|line| {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        line.split('\r')
+    })
+}
+
+/// Sends events to an `EventStream`. Can be cloned to send from several tasks.
+#[derive(Debug, Clone)]
+pub struct Sender {
+    tx: async_channel::Sender<Event>,
+}
+
+impl Sender {
+    /// Send an event, waiting if too many are waiting to be written. Fails if the client
+    /// disconnected (or the `EventStream` was dropped).
+    pub async fn send(&self, event: Event) -> Result<(), Disconnected> {
+        self.tx.send(event).await.map_err(|_| Disconnected)
+    }
+
+    /// Send an event if there's room, without waiting. Returns the event if there isn't.
+    pub fn try_send(&self, event: Event) -> Result<(), TrySendError> {
+        self.tx.try_send(event).map_err(|err| match err {
+            async_channel::TrySendError::Full(event) => TrySendError::Full(event),
+            async_channel::TrySendError::Closed(_) => TrySendError::Disconnected,
+        })
+    }
+
+    /// Whether the client disconnected (or the `EventStream` was dropped).
+    pub fn is_disconnected(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// The encoded events from a `Sender` (or a hub, see `subscribe`), with pings while idle. Set as
+/// the response body with `ResponseWriter::set_sse`.
+///
+/// Ends when all `Sender`s are dropped.
+pub struct EventStream {
+    events: Pin<Box<dyn Stream<Item = Event> + Send + Sync>>,
+    ping: Option<(Delay, Duration)>,
+}
+
+impl EventStream {
+    fn new(events: impl Stream<Item = Event> + Send + Sync + 'static, config: &SseConfig) -> Self {
+        let ping = config
+            .ping_interval
+            .map(|interval| (Delay::new(interval), interval));
+
+        Self {
+            events: Box::pin(events),
+            ping,
+        }
+    }
+}
+
+impl Stream for EventStream {
+    type Item = io::Result<String>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.events.poll_next(cx) {
+            Poll::Ready(Some(event)) => {
+                if let Some((delay, interval)) = &mut self.ping {
+                    delay.reset(*interval);
+                }
+                return Poll::Ready(Some(Ok(event.to_string())));
+            }
+            Poll::Ready(None) => return Poll::Ready(None),
+            Poll::Pending => (),
+        }
+
+        if let Some((delay, interval)) = &mut self.ping {
+            if Pin::new(&mut *delay).poll(cx).is_ready() {
+                delay.reset(*interval);
+                return Poll::Ready(Some(Ok(Event::comment("ping").to_string())));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventStream").finish()
+    }
+}
+
+/// The client disconnected, or the `EventStream` was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl std::error::Error for Disconnected {}
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Event stream disconnected")
+    }
+}
+
+/// Error from `Sender::try_send`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrySendError {
+    /// Too many events are waiting to be written; the event is returned.
+    Full(Event),
+    /// The client disconnected, or the `EventStream` was dropped.
+    Disconnected,
+}
+
+impl std::error::Error for TrySendError {}
+
+impl fmt::Display for TrySendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "Event stream full"),
+            TrySendError::Disconnected => write!(f, "Event stream disconnected"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_encode() {
+        let event = Event::new("one\ntwo\r\nthree\rfour")
+            .event("up\ndate")
+            .id("1\r\n2")
+            .retry(Duration::from_secs(3));
+        assert_eq!(
+            event.to_string(),
+            "event: update\ndata: one\ndata: two\ndata: three\ndata: four\nid: 12\nretry: 3000\n\n"
+        );
+
+        assert_eq!(Event::new("").to_string(), "data: \n\n");
+        assert_eq!(Event::new(" x\n").to_string(), "data:  x\ndata: \n\n");
+        assert_eq!(Event::comment("a\nb").to_string(), ": a\n: b\n\n");
+    }
+
+    #[test]
+    fn test_last_event_id() {
+        let mut req = Request::new(crate::Body::empty());
+        assert_eq!(last_event_id(&req), None);
+        req.headers_mut().insert("last-event-id", "7".parse().unwrap());
+        assert_eq!(last_event_id(&req), Some("7"));
+    }
+}
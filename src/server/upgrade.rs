@@ -0,0 +1,85 @@
+// Handing a connection over to another protocol after `101 Switching Protocols`.
+
+use futures_lite::{AsyncRead, AsyncWrite};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::response_writer::ResponseWritten;
+
+/// A connection which has switched protocols, from `ResponseWriter::upgrade`.
+///
+/// Reading returns any bytes the client sent right after its upgrade request (which were already
+/// read from the connection), and then continues reading from the connection itself. Writes go
+/// straight to the connection.
+///
+/// Once the connection is upgraded, `accept` stops handling requests on it. The endpoint can
+/// either run the new protocol itself before returning, or hand the `Upgraded` off to another
+/// task; either way, it returns `response_written()`.
+pub struct Upgraded<W> {
+    io: W,
+    buffered: Vec<u8>,
+    pos: usize,
+    bytes_written: u64,
+}
+
+impl<W> Upgraded<W> {
+    pub(crate) fn new(io: W, buffered: Vec<u8>, bytes_written: u64) -> Self {
+        Self {
+            io,
+            buffered,
+            pos: 0,
+            bytes_written,
+        }
+    }
+
+    /// The raw connection, and the bytes which were already read from it but not yet read from
+    /// this `Upgraded`.
+    pub fn into_parts(mut self) -> (W, Vec<u8>) {
+        let buffered = self.buffered.split_off(self.pos);
+        (self.io, buffered)
+    }
+
+    /// The `101 Switching Protocols` response, to return from the endpoint.
+    pub fn response_written(&self) -> ResponseWritten {
+        ResponseWritten {
+            bytes_written: self.bytes_written,
+            keep_alive: false,
+        }
+    }
+}
+
+impl<W: AsyncRead + Unpin> AsyncRead for Upgraded<W> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.pos < this.buffered.len() {
+            let n = std::cmp::min(this.buffered.len() - this.pos, buf.len());
+            buf[..n].copy_from_slice(&this.buffered[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Upgraded<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_close(cx)
+    }
+}
@@ -0,0 +1,672 @@
+//! WebSocket module
+//!
+//! Upgrades a request to a WebSocket connection (RFC 6455), from inside an endpoint. Works the
+//! same from an `accept` closure or a `Router` endpoint:
+//!
+//! ```rust,no_run
+//! # use futures_util::io::{AsyncRead, AsyncWrite};
+//! # use tophat::{Request, server::{glitch::Result, ResponseWriter, ResponseWritten}};
+//! use tophat::server::websocket::{self, Message};
+//!
+//! async fn echo<W>(req: Request, resp_wtr: ResponseWriter<W>) -> Result<ResponseWritten>
+//!     where W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+//! {
+//!     let mut ws = websocket::upgrade(&req, resp_wtr).await?;
+//!
+//!     while let Some(msg) = ws.recv().await {
+//!         match msg? {
+//!             Message::Text(text) => ws.send(Message::Text(text)).await?,
+//!             Message::Binary(bytes) => ws.send(Message::Binary(bytes)).await?,
+//!             _ => (),
+//!         }
+//!     }
+//!
+//!     Ok(ws.response_written())
+//! }
+//! ```
+//!
+//! The handshake checks for a `GET` with `Upgrade: websocket`, `Sec-WebSocket-Version: 13` and a
+//! `Sec-WebSocket-Key`, and otherwise returns a `Glitch` (400, or 426 for a different version), so
+//! a failed handshake is just a normal http response.
+//!
+//! After the handshake, `WebSocket::recv` reads messages:
+//! - fragmented messages are reassembled.
+//! - client frames must be masked, and reserved bits and opcodes unused.
+//! - text messages must be utf-8.
+//! - messages larger than `WebSocketConfig::max_message_size` are refused.
+//!
+//! On any of these protocol errors, a close frame with the matching status code is sent and
+//! `recv` returns the error; after that, it returns `None`.
+//!
+//! Pings are answered with a pong automatically (the ping is still returned from `recv`), and a
+//! close from the client is answered with a close.
+//!
+//! To send from other tasks while reading, get a `Sender` with `WebSocket::sender`. Frames from
+//! different `Sender`s never interleave. For a `Stream` and `Sink` of messages, see
+//! `WebSocket::into_stream` and `Sender::into_sink`.
+
+use futures_lite::io::BufReader;
+use futures_lite::{AsyncReadExt, AsyncWriteExt, Stream};
+use futures_util::io::{AsyncRead, AsyncWrite};
+use futures_util::lock::Mutex;
+use futures_util::Sink;
+use http::{
+    header::{self, HeaderMap, HeaderValue},
+    Method, StatusCode,
+};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::request::Request;
+use crate::server::glitch::Glitch;
+use crate::server::{ResponseWriter, ResponseWritten, Upgraded};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Control frames (close, ping, pong) can't carry more than this.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Settings for a WebSocket connection.
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    /// Largest message accepted from the client, after reassembling fragments. Larger messages
+    /// close the connection with status 1009.
+    ///
+    /// Default 16 MiB.
+    pub max_message_size: usize,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// A WebSocket message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A text message
+    Text(String),
+    /// A binary message
+    Binary(Vec<u8>),
+    /// A ping. Received pings are answered automatically.
+    Ping(Vec<u8>),
+    /// A pong
+    Pong(Vec<u8>),
+    /// A close, with an optional status code and reason.
+    Close(Option<CloseFrame>),
+}
+
+/// Status code and reason of a close message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    /// Status code, e.g. 1000 for a normal closure.
+    pub code: u16,
+    /// Reason, may be empty.
+    pub reason: String,
+}
+
+/// Errors on a WebSocket connection.
+#[derive(Debug)]
+pub enum WebSocketError {
+    /// Reading or writing the connection failed.
+    Io(io::Error),
+    /// The client broke the protocol. Closes with status 1002.
+    Protocol(&'static str),
+    /// A text message or close reason was not utf-8. Closes with status 1007.
+    InvalidUtf8,
+    /// A message was larger than the max message size. Closes with status 1009.
+    MessageTooBig,
+    /// A control message to send was larger than 125 bytes.
+    ControlTooBig,
+    /// A close was already sent, no more messages can be sent.
+    AlreadyClosed,
+}
+
+impl WebSocketError {
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::Protocol(_) => Some(1002),
+            WebSocketError::InvalidUtf8 => Some(1007),
+            WebSocketError::MessageTooBig => Some(1009),
+            _ => None,
+        }
+    }
+}
+
+impl std::error::Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebSocketError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketError::Io(err) => write!(f, "WebSocket io error: {}", err),
+            WebSocketError::Protocol(msg) => write!(f, "WebSocket protocol error: {}", msg),
+            WebSocketError::InvalidUtf8 => write!(f, "WebSocket message is not valid utf-8"),
+            WebSocketError::MessageTooBig => write!(f, "WebSocket message too big"),
+            WebSocketError::ControlTooBig => {
+                write!(f, "WebSocket control message larger than 125 bytes")
+            }
+            WebSocketError::AlreadyClosed => write!(f, "WebSocket already closed"),
+        }
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(err: io::Error) -> Self {
+        WebSocketError::Io(err)
+    }
+}
+
+/// Complete the WebSocket handshake and upgrade the connection, with the default config.
+pub async fn upgrade<W>(req: &Request, resp_wtr: ResponseWriter<W>) -> Result<WebSocket<W>, Glitch>
+where
+    W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    upgrade_with_config(req, resp_wtr, WebSocketConfig::default()).await
+}
+
+/// Complete the WebSocket handshake and upgrade the connection.
+///
+/// Headers already set on the `ResponseWriter` (e.g. `Sec-WebSocket-Protocol`) are sent with the
+/// `101 Switching Protocols`.
+pub async fn upgrade_with_config<W>(
+    req: &Request,
+    mut resp_wtr: ResponseWriter<W>,
+    config: WebSocketConfig,
+) -> Result<WebSocket<W>, Glitch>
+where
+    W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    if req.method() != Method::GET {
+        return Err(Glitch::new_with_status_context(
+            StatusCode::BAD_REQUEST,
+            "WebSocket handshake must be a GET request",
+        ));
+    }
+
+    let version = req.headers().get(header::SEC_WEBSOCKET_VERSION);
+    if version.map(|v| v.as_bytes()) != Some(b"13") {
+        let mut glitch = Glitch::new_with_status_context(
+            StatusCode::UPGRADE_REQUIRED,
+            "Unsupported Sec-WebSocket-Version",
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(header::SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+        glitch.headers = Some(headers);
+        return Err(glitch);
+    }
+
+    let key = req
+        .headers()
+        .get(header::SEC_WEBSOCKET_KEY)
+        .filter(|key| {
+            base64::decode(key.as_bytes())
+                .map(|key| key.len() == 16)
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| {
+            Glitch::new_with_status_context(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid Sec-WebSocket-Key",
+            )
+        })?;
+
+    let accept = accept_key(key.as_bytes()).parse()?;
+    resp_wtr.insert_header(header::SEC_WEBSOCKET_ACCEPT, accept);
+
+    let upgraded = resp_wtr.upgrade("websocket").await?;
+
+    Ok(WebSocket::new(upgraded, config))
+}
+
+/// The `Sec-WebSocket-Accept` for a `Sec-WebSocket-Key`.
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key);
+    sha1.update(ACCEPT_GUID.as_bytes());
+    base64::encode(sha1.digest().bytes())
+}
+
+/// A WebSocket connection, from `upgrade`.
+///
+/// `recv` is not cancel-safe: if its future is dropped in the middle of a frame, the connection
+/// is left in an unknown state. To select over incoming messages, use `into_stream`.
+pub struct WebSocket<W> {
+    reader: BufReader<Upgraded<W>>,
+    sender: Sender<W>,
+    config: WebSocketConfig,
+    // of the 101 response
+    bytes_written: u64,
+    // opcode and payload of a fragmented message so far
+    fragments: Option<(OpCode, Vec<u8>)>,
+    closed: bool,
+}
+
+impl<W> WebSocket<W>
+where
+    W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    fn new(upgraded: Upgraded<W>, config: WebSocketConfig) -> Self {
+        let bytes_written = upgraded.response_written().bytes_written;
+        let (io, buffered) = upgraded.into_parts();
+        let sender = Sender::new(io.clone());
+        let reader = BufReader::new(Upgraded::new(io, buffered, bytes_written));
+
+        Self {
+            reader,
+            sender,
+            config,
+            bytes_written,
+            fragments: None,
+            closed: false,
+        }
+    }
+
+    /// The next message from the client.
+    ///
+    /// `None` once the connection is closed, either after a close message or when the client
+    /// disconnects.
+    pub async fn recv(&mut self) -> Option<Result<Message, WebSocketError>> {
+        if self.closed {
+            return None;
+        }
+
+        match self.recv_inner().await {
+            Ok(Some(msg)) => {
+                if let Message::Close(_) = msg {
+                    self.closed = true;
+                }
+                Some(Ok(msg))
+            }
+            Ok(None) => {
+                self.closed = true;
+                None
+            }
+            Err(err) => {
+                self.closed = true;
+                if let Some(code) = err.close_code() {
+                    // best effort, the protocol error is what gets reported.
+                    let _ = self.sender.close_with(code, "").await;
+                }
+                Some(Err(err))
+            }
+        }
+    }
+
+    /// Send a message. See `Sender::send`.
+    pub async fn send(&self, msg: Message) -> Result<(), WebSocketError> {
+        self.sender.send(msg).await
+    }
+
+    /// A handle for sending messages, e.g. from another task.
+    pub fn sender(&self) -> Sender<W> {
+        self.sender.clone()
+    }
+
+    /// The `101 Switching Protocols` response, to return from the endpoint.
+    pub fn response_written(&self) -> ResponseWritten {
+        ResponseWritten {
+            bytes_written: self.bytes_written,
+            keep_alive: false,
+        }
+    }
+
+    /// Incoming messages as a `Stream`, ending when the connection is closed.
+    ///
+    /// Get the `sender` and `response_written` first if they're needed.
+    pub fn into_stream(self) -> impl Stream<Item = Result<Message, WebSocketError>> {
+        futures_lite::stream::unfold(self, |mut ws| async move {
+            let msg = ws.recv().await?;
+            Some((msg, ws))
+        })
+    }
+
+    async fn recv_inner(&mut self) -> Result<Option<Message>, WebSocketError> {
+        loop {
+            let frame = match self.read_frame().await? {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+
+            match frame.opcode {
+                OpCode::Ping => {
+                    // a failed pong will show up on the next read or send anyways.
+                    if !self.sender.is_closed() {
+                        let _ = self.sender.send_frame(OpCode::Pong, &frame.payload).await;
+                    }
+                    return Ok(Some(Message::Ping(frame.payload)));
+                }
+                OpCode::Pong => return Ok(Some(Message::Pong(frame.payload))),
+                OpCode::Close => {
+                    let close = parse_close(&frame.payload)?;
+                    if !self.sender.is_closed() {
+                        let code = close.as_ref().map(|close| close.code);
+                        self.sender.send_close(code, "").await?;
+                    }
+                    return Ok(Some(Message::Close(close)));
+                }
+                OpCode::Text | OpCode::Binary => {
+                    if self.fragments.is_some() {
+                        return Err(WebSocketError::Protocol("expected a continuation frame"));
+                    }
+                    if frame.fin {
+                        return into_message(frame.opcode, frame.payload).map(Some);
+                    }
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+                OpCode::Continuation => {
+                    let (opcode, mut payload) = self
+                        .fragments
+                        .take()
+                        .ok_or(WebSocketError::Protocol("unexpected continuation frame"))?;
+                    payload.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        return into_message(opcode, payload).map(Some);
+                    }
+                    self.fragments = Some((opcode, payload));
+                }
+            }
+        }
+    }
+
+    /// `None` if the client disconnected between frames.
+    async fn read_frame(&mut self) -> Result<Option<Frame>, WebSocketError> {
+        let mut head = [0u8; 2];
+        if self.reader.read(&mut head[..1]).await? == 0 {
+            return Ok(None);
+        }
+        self.reader.read_exact(&mut head[1..]).await?;
+
+        let fin = head[0] & 0x80 != 0;
+        if head[0] & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits set"));
+        }
+        let opcode = OpCode::from_u8(head[0] & 0x0f)?;
+        if head[1] & 0x80 == 0 {
+            return Err(WebSocketError::Protocol("client frame not masked"));
+        }
+
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0u8; 2];
+                self.reader.read_exact(&mut len).await?;
+                u64::from(u16::from_be_bytes(len))
+            }
+            127 => {
+                let mut len = [0u8; 8];
+                self.reader.read_exact(&mut len).await?;
+                let len = u64::from_be_bytes(len);
+                if len & (1 << 63) != 0 {
+                    return Err(WebSocketError::Protocol("invalid payload length"));
+                }
+                len
+            }
+            len => u64::from(len),
+        };
+
+        if opcode.is_control() {
+            if !fin {
+                return Err(WebSocketError::Protocol("fragmented control frame"));
+            }
+            if len > MAX_CONTROL_PAYLOAD as u64 {
+                return Err(WebSocketError::Protocol("control frame too big"));
+            }
+        } else {
+            let so_far = self.fragments.as_ref().map(|(_, p)| p.len()).unwrap_or(0);
+            if len > self.config.max_message_size.saturating_sub(so_far) as u64 {
+                return Err(WebSocketError::MessageTooBig);
+            }
+        }
+
+        let mut mask = [0u8; 4];
+        self.reader.read_exact(&mut mask).await?;
+
+        let mut payload = vec![0u8; len as usize];
+        self.reader.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+}
+
+/// Sends messages on a WebSocket connection. Cheap to clone.
+pub struct Sender<W> {
+    inner: Arc<SenderInner<W>>,
+}
+
+struct SenderInner<W> {
+    io: Mutex<W>,
+    close_sent: AtomicBool,
+}
+
+impl<W> Clone for Sender<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<W> Sender<W>
+where
+    W: AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    fn new(io: W) -> Self {
+        Self {
+            inner: Arc::new(SenderInner {
+                io: Mutex::new(io),
+                close_sent: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Send a message, as a single frame.
+    ///
+    /// Sending `Message::Close` starts the closing handshake; `recv` then returns the client's
+    /// close. After a close, sending returns `WebSocketError::AlreadyClosed`.
+    pub async fn send(&self, msg: Message) -> Result<(), WebSocketError> {
+        match msg {
+            Message::Text(text) => self.send_frame(OpCode::Text, text.as_bytes()).await,
+            Message::Binary(bytes) => self.send_frame(OpCode::Binary, &bytes).await,
+            Message::Ping(bytes) => self.send_frame(OpCode::Ping, &bytes).await,
+            Message::Pong(bytes) => self.send_frame(OpCode::Pong, &bytes).await,
+            Message::Close(Some(close)) => self.send_close(Some(close.code), &close.reason).await,
+            Message::Close(None) => self.send_close(None, "").await,
+        }
+    }
+
+    /// Start the closing handshake, with a status code and reason.
+    pub async fn close_with(&self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        self.send_close(Some(code), reason).await
+    }
+
+    /// Whether a close was sent.
+    pub fn is_closed(&self) -> bool {
+        self.inner.close_sent.load(Ordering::SeqCst)
+    }
+
+    /// Messages to send as a `Sink`.
+    pub fn into_sink(self) -> impl Sink<Message, Error = WebSocketError> {
+        futures_util::sink::unfold(self, |sender, msg| async move {
+            sender.send(msg).await?;
+            Ok(sender)
+        })
+    }
+
+    async fn send_close(&self, code: Option<u16>, reason: &str) -> Result<(), WebSocketError> {
+        let mut payload = Vec::new();
+        if let Some(code) = code {
+            payload.extend_from_slice(&code.to_be_bytes());
+            payload.extend_from_slice(reason.as_bytes());
+        }
+        self.send_frame(OpCode::Close, &payload).await?;
+        self.inner.close_sent.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn send_frame(&self, opcode: OpCode, payload: &[u8]) -> Result<(), WebSocketError> {
+        if opcode.is_control() && payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(WebSocketError::ControlTooBig);
+        }
+
+        // hold the lock across the check, so nothing is sent after a close.
+        let mut io = self.inner.io.lock().await;
+        if self.is_closed() {
+            return Err(WebSocketError::AlreadyClosed);
+        }
+        io.write_all(&encode_frame(opcode, payload)).await?;
+        io.flush().await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl OpCode {
+    fn from_u8(byte: u8) -> Result<Self, WebSocketError> {
+        match byte {
+            0x0 => Ok(OpCode::Continuation),
+            0x1 => Ok(OpCode::Text),
+            0x2 => Ok(OpCode::Binary),
+            0x8 => Ok(OpCode::Close),
+            0x9 => Ok(OpCode::Ping),
+            0xa => Ok(OpCode::Pong),
+            _ => Err(WebSocketError::Protocol("unknown opcode")),
+        }
+    }
+
+    fn as_u8(self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xa,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        self.as_u8() & 0x8 != 0
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: OpCode,
+    // unmasked
+    payload: Vec<u8>,
+}
+
+fn into_message(opcode: OpCode, payload: Vec<u8>) -> Result<Message, WebSocketError> {
+    match opcode {
+        OpCode::Text => String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| WebSocketError::InvalidUtf8),
+        _ => Ok(Message::Binary(payload)),
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, WebSocketError> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => Err(WebSocketError::Protocol("invalid close payload")),
+        _ => {
+            let code = u16::from_be_bytes([payload[0], payload[1]]);
+            // codes which may not be sent, RFC 6455 7.4
+            match code {
+                1000..=1003 | 1007..=1011 | 3000..=4999 => (),
+                _ => return Err(WebSocketError::Protocol("invalid close code")),
+            }
+            let reason = std::str::from_utf8(&payload[2..])
+                .map_err(|_| WebSocketError::InvalidUtf8)?
+                .to_owned();
+            Ok(Some(CloseFrame { code, reason }))
+        }
+    }
+}
+
+/// An unmasked frame, as sent by a server.
+fn encode_frame(opcode: OpCode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode.as_u8());
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // from RFC 6455 1.3
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        assert_eq!(encode_frame(OpCode::Text, b"Hello"), b"\x81\x05Hello");
+
+        let frame = encode_frame(OpCode::Binary, &[0; 256]);
+        assert_eq!(&frame[..4], &[0x82, 126, 0x01, 0x00]);
+        assert_eq!(frame.len(), 4 + 256);
+
+        let frame = encode_frame(OpCode::Binary, &[0; 65536]);
+        assert_eq!(&frame[..10], &[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_parse_close() {
+        assert_eq!(parse_close(b"").unwrap(), None);
+        assert_eq!(
+            parse_close(b"\x03\xe8bye").unwrap(),
+            Some(CloseFrame {
+                code: 1000,
+                reason: "bye".to_owned()
+            })
+        );
+        assert!(parse_close(b"\x03").is_err());
+        // 1005 is reserved, may not be sent
+        assert!(parse_close(b"\x03\xed").is_err());
+    }
+}
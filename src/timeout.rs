@@ -1,15 +1,19 @@
 // From async-std future::timeout, except that futures_timer is swapped in.
+//
+// Also io wrappers which time out when the inner io stalls.
 
+use futures_lite::{AsyncBufRead, AsyncRead, AsyncWrite};
 use std::error::Error;
 use std::fmt;
 use std::future::Future;
+use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use std::time::Duration;
 
 use futures_timer::Delay;
 use pin_project_lite::pin_project;
-
+use tracing::debug;
 
 pub(crate) async fn timeout<F, T>(dur: Duration, f: F) -> Result<T, TimeoutError>
 where
@@ -70,3 +74,126 @@ impl fmt::Display for TimeoutError {
         "future has timed out".fmt(f)
     }
 }
+
+pin_project! {
+    /// Fails a read with `io::ErrorKind::TimedOut` if the inner reader stalls for `dur`.
+    ///
+    /// The timer only runs while a read is pending, and restarts after each read which completes.
+    /// `None` never times out.
+    pub(crate) struct TimeoutReader<R> {
+        #[pin]
+        inner: R,
+        dur: Option<Duration>,
+        delay: Option<Delay>,
+        // logged on expiry
+        reason: &'static str,
+    }
+}
+
+impl<R> TimeoutReader<R> {
+    pub(crate) fn new(inner: R, dur: Option<Duration>, reason: &'static str) -> Self {
+        Self {
+            inner,
+            dur,
+            delay: None,
+            reason,
+        }
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for TimeoutReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let poll = this.inner.poll_read(cx, buf);
+        poll_io_timeout(poll, *this.dur, this.delay, this.reason, cx)
+    }
+}
+
+impl<R: AsyncBufRead> AsyncBufRead for TimeoutReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.project();
+        let poll = this.inner.poll_fill_buf(cx);
+        poll_io_timeout(poll, *this.dur, this.delay, this.reason, cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.project().inner.consume(amt)
+    }
+}
+
+pin_project! {
+    /// Fails a write with `io::ErrorKind::TimedOut` if the inner writer stalls for `dur`.
+    ///
+    /// The timer only runs while a write is pending, and restarts after each write which
+    /// completes. `None` never times out.
+    pub(crate) struct TimeoutWriter<W> {
+        #[pin]
+        inner: W,
+        dur: Option<Duration>,
+        delay: Option<Delay>,
+        // logged on expiry
+        reason: &'static str,
+    }
+}
+
+impl<W> TimeoutWriter<W> {
+    pub(crate) fn new(inner: W, dur: Option<Duration>, reason: &'static str) -> Self {
+        Self {
+            inner,
+            dur,
+            delay: None,
+            reason,
+        }
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for TimeoutWriter<W> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        let poll = this.inner.poll_write(cx, buf);
+        poll_io_timeout(poll, *this.dur, this.delay, this.reason, cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let poll = this.inner.poll_flush(cx);
+        poll_io_timeout(poll, *this.dur, this.delay, this.reason, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let poll = this.inner.poll_close(cx);
+        poll_io_timeout(poll, *this.dur, this.delay, this.reason, cx)
+    }
+}
+
+// Starts the timer when the inner io is pending, and clears it once the io is ready.
+fn poll_io_timeout<T>(
+    poll: Poll<io::Result<T>>,
+    dur: Option<Duration>,
+    delay: &mut Option<Delay>,
+    reason: &'static str,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<T>> {
+    match (poll, dur) {
+        (Poll::Ready(res), _) => {
+            *delay = None;
+            Poll::Ready(res)
+        }
+        (Poll::Pending, None) => Poll::Pending,
+        (Poll::Pending, Some(dur)) => {
+            let delay = delay.get_or_insert_with(|| Delay::new(dur));
+            match Pin::new(delay).poll(cx) {
+                Poll::Ready(()) => {
+                    debug!("{}", reason);
+                    Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, reason)))
+                }
+                Poll::Pending => Poll::Pending,
+            }
+        }
+    }
+}
@@ -2,7 +2,45 @@
 //!
 //! Trailers are headers sent at the end of a chunked message.
 //!
-//! Currently tophat can only receive, not send them.
+//! ## Receiving
+//! A chunked request body receives its trailers (if any) once it's been read to the end. To read
+//! the body as a stream and then get the trailers, use `Body::trailers`:
+//!
+//! ```rust
+//! # use futures_util::io::{AsyncRead, AsyncWrite};
+//! # use tophat::{Request, server::{glitch::Result, ResponseWriter, ResponseWritten}};
+//! use futures_lite::io;
+//! use tophat::trailers::ReceivedTrailers;
+//!
+//! async fn upload<W>(mut req: Request, resp_wtr: ResponseWriter<W>) -> Result<ResponseWritten>
+//!     where W: AsyncRead + AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+//! {
+//!     io::copy(req.body_mut(), io::sink()).await?;
+//!
+//!     match req.body_mut().trailers().await? {
+//!         ReceivedTrailers::Trailers(trailers) => println!("{:?}", trailers.get("checksum")),
+//!         ReceivedTrailers::NoneSent => println!("chunked, but no trailers"),
+//!         ReceivedTrailers::NotChunked => println!("not chunked, can't have trailers"),
+//!     }
+//!
+//!     resp_wtr.send().await
+//! }
+//! ```
+//!
+//! Or to buffer the whole body, `Body::into_bytes_with_trailer`.
+//!
+//! ## Sending
+//! For a response with a chunked body (a body of unknown length), get a `TrailersSender` with
+//! `Body::send_trailers` before setting the body on the `ResponseWriter`. After the last chunk of
+//! the body, the server waits for the trailers from the sender, up to
+//! `ServerOpts::trailers_timeout`; dropping the sender without sending ends the response without
+//! trailers.
+//!
+//! Trailer names should be declared up front in a `Trailer` header. `Body::declare_trailers`
+//! makes the server add it.
+//!
+//! Trailers are not sent for a fixed-length body, or to HTTP/1.0 clients. Trailer fields which
+//! affect framing (`content-length`, `transfer-encoding`, `trailer`) are never sent.
 
 use async_channel::Sender;
 use http::HeaderMap;
@@ -54,6 +92,17 @@ impl DerefMut for Trailers {
     }
 }
 
+/// Trailers received after a body, from `Body::trailers`.
+#[derive(Debug)]
+pub enum ReceivedTrailers {
+    /// The body was chunked, and ended with trailers.
+    Trailers(Trailers),
+    /// The body was chunked, but no trailers were sent.
+    NoneSent,
+    /// The body wasn't chunked, so it can't have trailers.
+    NotChunked,
+}
+
 /// The sending half of a channel to send trailers.
 ///
 /// Unlike `async_std::sync::channel` the `send` method on this type can only be
@@ -71,10 +120,15 @@ impl TrailersSender {
         Self { sender }
     }
 
-    /// Send a `Trailer`.
+    /// Send trailers, to be written after the last chunk of the body.
     ///
     /// The channel will be consumed after having sent trailers.
-    pub(crate) async fn send(self, trailers: Result<Trailers, BodyError>) {
+    pub async fn send(self, trailers: Trailers) {
+        self.send_result(Ok(trailers)).await
+    }
+
+    /// Send trailers, or the error from failing to decode them.
+    pub(crate) async fn send_result(self, trailers: Result<Trailers, BodyError>) {
         // TODO should this return an error?
         let _ = self.sender.send(trailers).await;
     }
@@ -1,4 +1,6 @@
-use futures_lite::{AsyncBufRead, AsyncRead, AsyncWrite};
+use futures_lite::io::BufReader;
+use futures_lite::{ready, AsyncBufRead, AsyncRead, AsyncWrite, Future};
+use http::header::{AsHeaderName, HeaderMap};
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
@@ -103,3 +105,102 @@ impl AsyncWrite for Cursor<Vec<u8>> {
         Poll::Ready(std::io::Write::flush(&mut self.inner))
     }
 }
+
+/// Check whether a header holding a comma-separated list of tokens (e.g. `Connection`) contains
+/// `token`. Comparison is case-insensitive, and all header lines with that name are checked.
+pub(crate) fn header_has_token(headers: &HeaderMap, name: impl AsHeaderName, token: &str) -> bool {
+    headers.get_all(name).iter().any(|value| {
+        value
+            .to_str()
+            .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+            .unwrap_or(false)
+    })
+}
+
+/// Decode `%XX` escapes. `None` if an escape is malformed.
+#[cfg(any(feature = "form", feature = "fs", feature = "multipart"))]
+pub(crate) fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Like `futures_lite::io::copy`, but also flushes the writer whenever the reader has nothing
+/// ready, so that a streamed body (e.g. SSE or NDJSON) reaches the client as it's produced,
+/// instead of waiting in a buffered writer.
+pub(crate) async fn copy_flushing<R, W>(reader: R, writer: W) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    pin_project_lite::pin_project! {
+        struct CopyFuture<R, W> {
+            #[pin]
+            reader: R,
+            #[pin]
+            writer: W,
+            amt: u64,
+            // written since the last flush
+            unflushed: bool,
+        }
+    }
+
+    impl<R, W> Future for CopyFuture<R, W>
+    where
+        R: AsyncBufRead,
+        W: AsyncWrite + Unpin,
+    {
+        type Output = io::Result<u64>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            let mut this = self.project();
+            loop {
+                let buffer = match this.reader.as_mut().poll_fill_buf(cx) {
+                    Poll::Ready(buffer) => buffer?,
+                    Poll::Pending => {
+                        if *this.unflushed {
+                            ready!(this.writer.as_mut().poll_flush(cx))?;
+                            *this.unflushed = false;
+                        }
+                        return Poll::Pending;
+                    }
+                };
+                if buffer.is_empty() {
+                    ready!(this.writer.as_mut().poll_flush(cx))?;
+                    return Poll::Ready(Ok(*this.amt));
+                }
+
+                let i = ready!(this.writer.as_mut().poll_write(cx, buffer))?;
+                if i == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                *this.amt += i as u64;
+                *this.unflushed = true;
+                this.reader.as_mut().consume(i);
+            }
+        }
+    }
+
+    CopyFuture {
+        reader: BufReader::new(reader),
+        writer,
+        amt: 0,
+        unflushed: false,
+    }
+    .await
+}
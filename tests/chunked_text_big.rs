@@ -57,7 +57,7 @@ pub const TEXT: &str = concat![
     "Nemo nemo iste qui voluptas itaque. Quae quis qui qui cum quod natus itaque est. Dolores voluptate sapiente ipsa eveniet doloremque laboriosam velit sunt. Optio voluptatum doloremque tenetur voluptate.",
 ];
 
-pub const RESPONSE: &'static str = concat![
+pub const RESPONSE: &str = concat![
     "HTTP/1.1 200 OK\r\n",
     "transfer-encoding: chunked\r\n",
     "content-type: application/octet-stream\r\n",
@@ -0,0 +1,380 @@
+mod mock;
+
+use async_compression::futures::bufread::{
+    BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZlibDecoder, ZlibEncoder,
+};
+use futures_lite::{AsyncReadExt, Future};
+use http::{header, StatusCode};
+use tophat::{
+    glitch,
+    server::{
+        accept_with_opts,
+        compression::{Compression, Encoding},
+        glitch::{Glitch, Result},
+        ResponseWriter, ResponseWritten, ServerError, ServerOpts,
+    },
+    Body, BodyError, Request,
+};
+
+use mock::{Client, Cursor};
+
+const TEXT: &str = "tophat compresses responses. tophat compresses responses. ";
+
+fn big_text() -> String {
+    TEXT.repeat(100)
+}
+
+/// Send the request, and return the response's lowercased head and decoded (unchunked) body.
+async fn respond<F, Fut>(req: &str, opts: ServerOpts, endpoint: F) -> (String, Vec<u8>)
+where
+    F: Fn(Request, ResponseWriter<Client>) -> Fut,
+    Fut: Future<Output = Result<ResponseWritten>>,
+{
+    let testclient = Client::new_with_writes(req, "", 1000);
+    accept_with_opts(testclient.clone(), opts, endpoint).await.unwrap();
+
+    let resp = testclient.response();
+    let split = resp.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let head = String::from_utf8(resp[..split].to_vec()).unwrap().to_lowercase();
+    let mut body = resp[split..].to_vec();
+
+    if head.contains("transfer-encoding: chunked\r\n") {
+        let mut unchunked = Vec::new();
+        let mut rest = &body[..];
+        loop {
+            let eol = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+            let len = usize::from_str_radix(std::str::from_utf8(&rest[..eol]).unwrap(), 16).unwrap();
+            if len == 0 {
+                break;
+            }
+            unchunked.extend_from_slice(&rest[eol + 2..eol + 2 + len]);
+            rest = &rest[eol + 2 + len + 2..];
+        }
+        body = unchunked;
+    }
+
+    (head, body)
+}
+
+fn compression_opts() -> ServerOpts {
+    ServerOpts {
+        compression: Some(Compression::default()),
+        ..ServerOpts::default()
+    }
+}
+
+async fn text_endpoint(_req: Request, mut resp_wtr: ResponseWriter<Client>) -> Result<ResponseWritten> {
+    resp_wtr.set_text(big_text());
+    resp_wtr.send().await
+}
+
+#[test]
+fn test_compress_gzip() {
+    smol::block_on(async {
+        let (head, body) = respond(
+            "GET / HTTP/1.1\r\nHost: example.org\r\nAccept-Encoding: gzip\r\n\r\n",
+            compression_opts(),
+            text_endpoint,
+        )
+        .await;
+
+        assert!(head.contains("transfer-encoding: chunked\r\n"));
+        assert!(head.contains("content-encoding: gzip\r\n"));
+        assert!(head.contains("vary: accept-encoding\r\n"));
+        assert!(!head.contains("content-length"));
+        assert!(body.len() < big_text().len());
+
+        let mut decoded = String::new();
+        GzipDecoder::new(&body[..]).read_to_string(&mut decoded).await.unwrap();
+        assert_eq!(decoded, big_text());
+    });
+}
+
+#[test]
+fn test_compress_q_values() {
+    smol::block_on(async {
+        let (head, body) = respond(
+            "GET / HTTP/1.1\r\nHost: example.org\r\nAccept-Encoding: gzip;q=0.5, br;q=0.9, deflate;q=0.1\r\n\r\n",
+            compression_opts(),
+            text_endpoint,
+        )
+        .await;
+
+        assert!(head.contains("content-encoding: br\r\n"));
+        let mut decoded = String::new();
+        BrotliDecoder::new(&body[..]).read_to_string(&mut decoded).await.unwrap();
+        assert_eq!(decoded, big_text());
+
+        let (head, body) = respond(
+            "GET / HTTP/1.1\r\nHost: example.org\r\nAccept-Encoding: br;q=0, gzip;q=0, *;q=0.2\r\n\r\n",
+            compression_opts(),
+            text_endpoint,
+        )
+        .await;
+
+        assert!(head.contains("content-encoding: deflate\r\n"));
+        let mut decoded = String::new();
+        ZlibDecoder::new(&body[..]).read_to_string(&mut decoded).await.unwrap();
+        assert_eq!(decoded, big_text());
+    });
+}
+
+#[test]
+fn test_compress_not_accepted() {
+    // not compressed, but the response still varies by accept-encoding
+    smol::block_on(async {
+        let (head, body) = respond(
+            "GET / HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            compression_opts(),
+            text_endpoint,
+        )
+        .await;
+
+        assert!(!head.contains("content-encoding"));
+        assert!(head.contains("vary: accept-encoding\r\n"));
+        assert!(head.contains(&format!("content-length: {}\r\n", big_text().len())));
+        assert_eq!(body, big_text().as_bytes());
+    });
+}
+
+#[test]
+fn test_compress_skipped() {
+    smol::block_on(async {
+        // small body
+        let (head, body) = respond(
+            "GET / HTTP/1.1\r\nHost: example.org\r\nAccept-Encoding: gzip\r\n\r\n",
+            compression_opts(),
+            |_req, mut resp_wtr| async move {
+                resp_wtr.set_text(TEXT.to_owned());
+                resp_wtr.send().await
+            },
+        )
+        .await;
+        assert!(!head.contains("content-encoding"));
+        assert!(!head.contains("vary"));
+        assert_eq!(body, TEXT.as_bytes());
+
+        // already compressed content type
+        let (head, _) = respond(
+            "GET / HTTP/1.1\r\nHost: example.org\r\nAccept-Encoding: gzip\r\n\r\n",
+            compression_opts(),
+            |_req, mut resp_wtr| async move {
+                resp_wtr.set_body(Body::from_bytes(vec![0; 4096]));
+                resp_wtr.insert_header(header::CONTENT_TYPE, "image/png".parse().unwrap());
+                resp_wtr.send().await
+            },
+        )
+        .await;
+        assert!(!head.contains("content-encoding"));
+        assert!(head.contains("content-length: 4096\r\n"));
+
+        // opted out
+        let (head, _) = respond(
+            "GET / HTTP/1.1\r\nHost: example.org\r\nAccept-Encoding: gzip\r\n\r\n",
+            compression_opts(),
+            |_req, mut resp_wtr| async move {
+                resp_wtr.set_text(big_text());
+                resp_wtr.insert_header(header::CONTENT_ENCODING, "identity".parse().unwrap());
+                resp_wtr.send().await
+            },
+        )
+        .await;
+        assert!(head.contains("content-encoding: identity\r\n"));
+        assert!(head.contains(&format!("content-length: {}\r\n", big_text().len())));
+    });
+}
+
+#[test]
+fn test_compress_stream_and_etag() {
+    // a body of unknown length is always compressed; a strong etag becomes weak
+    smol::block_on(async {
+        let (head, body) = respond(
+            "GET / HTTP/1.1\r\nHost: example.org\r\nAccept-Encoding: gzip\r\n\r\n",
+            compression_opts(),
+            |_req, mut resp_wtr| async move {
+                resp_wtr.set_body(Body::from_reader(Cursor::new(TEXT), None));
+                resp_wtr.insert_header(header::ETAG, "\"abc\"".parse().unwrap());
+                resp_wtr.send().await
+            },
+        )
+        .await;
+
+        assert!(head.contains("content-encoding: gzip\r\n"));
+        assert!(head.contains("etag: w/\"abc\"\r\n"));
+        let mut decoded = String::new();
+        GzipDecoder::new(&body[..]).read_to_string(&mut decoded).await.unwrap();
+        assert_eq!(decoded, TEXT);
+    });
+}
+
+#[test]
+fn test_compress_per_response() {
+    smol::block_on(async {
+        let compression = Compression {
+            encodings: vec![Encoding::Gzip],
+            ..Compression::default()
+        };
+
+        let (head, body) = respond(
+            "GET / HTTP/1.1\r\nHost: example.org\r\nAccept-Encoding: br, gzip\r\n\r\n",
+            ServerOpts::default(),
+            |_req, mut resp_wtr| {
+                let compression = compression.clone();
+                async move {
+                    resp_wtr.set_text(big_text());
+                    resp_wtr.compress(&compression);
+                    resp_wtr.send().await
+                }
+            },
+        )
+        .await;
+
+        assert!(head.contains("content-encoding: gzip\r\n"));
+        let mut decoded = String::new();
+        GzipDecoder::new(&body[..]).read_to_string(&mut decoded).await.unwrap();
+        assert_eq!(decoded, big_text());
+    });
+}
+
+async fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    GzipEncoder::new(data).read_to_end(&mut compressed).await.unwrap();
+    compressed
+}
+
+fn decompress_opts() -> ServerOpts {
+    ServerOpts {
+        decompress_requests: true,
+        ..ServerOpts::default()
+    }
+}
+
+/// Responds with the request body, after checking that its encoding headers were removed.
+async fn echo_endpoint(req: Request, mut resp_wtr: ResponseWriter<Client>) -> Result<ResponseWritten> {
+    assert!(req.headers().get(header::CONTENT_ENCODING).is_none());
+    assert!(req.headers().get(header::CONTENT_LENGTH).is_none());
+    let body = req.into_body().into_string().await?;
+    resp_wtr.set_text(body);
+    resp_wtr.send().await
+}
+
+#[test]
+fn test_decompress_request_gzip() {
+    smol::block_on(async {
+        let compressed = gzip(big_text().as_bytes()).await;
+        let mut req = format!(
+            "POST / HTTP/1.1\r\nHost: example.org\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            compressed.len()
+        )
+        .into_bytes();
+        req.extend_from_slice(&compressed);
+
+        let expected = format!(
+            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\ncontent-type: text/plain\r\n\r\n{}",
+            big_text().len(),
+            big_text()
+        );
+        let testclient = Client::from_bytes(req, &expected);
+        accept_with_opts(testclient.clone(), decompress_opts(), echo_endpoint)
+            .await
+            .unwrap();
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_decompress_request_chunked_layered() {
+    // deflate applied first, then br; sent chunked
+    smol::block_on(async {
+        let mut deflated = Vec::new();
+        ZlibEncoder::new(TEXT.as_bytes()).read_to_end(&mut deflated).await.unwrap();
+        let mut compressed = Vec::new();
+        BrotliEncoder::new(&deflated[..]).read_to_end(&mut compressed).await.unwrap();
+
+        let mut req = b"POST / HTTP/1.1\r\nHost: example.org\r\nContent-Encoding: deflate, br\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        req.extend_from_slice(format!("{:X}\r\n", compressed.len()).as_bytes());
+        req.extend_from_slice(&compressed);
+        req.extend_from_slice(b"\r\n0\r\n\r\n");
+
+        let expected = format!(
+            "HTTP/1.1 200 OK\r\ncontent-length: {}\r\ncontent-type: text/plain\r\n\r\n{}",
+            TEXT.len(),
+            TEXT
+        );
+        let testclient = Client::from_bytes(req, &expected);
+        accept_with_opts(testclient.clone(), decompress_opts(), echo_endpoint)
+            .await
+            .unwrap();
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_decompress_request_unsupported() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "POST / HTTP/1.1\r\nHost: example.org\r\nContent-Encoding: zstd\r\nContent-Length: 4\r\n\r\nabcd",
+            "HTTP/1.1 415 Unsupported Media Type\r\ncontent-length: 0\r\nconnection: close\r\naccept-encoding: gzip, deflate, br\r\n\r\n",
+        );
+        let res = accept_with_opts(testclient.clone(), decompress_opts(), |_req, _resp_wtr| async {
+            panic!("request should be rejected")
+        })
+        .await;
+
+        assert!(matches!(res, Err(ServerError::ConnectionClosedUnsupportedContentEncoding)));
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_decompress_request_off() {
+    // by default, the body is passed through as is
+    smol::block_on(async {
+        let testclient = Client::new(
+            "POST / HTTP/1.1\r\nHost: example.org\r\nContent-Encoding: zstd\r\nContent-Length: 4\r\n\r\nabcd",
+            "HTTP/1.1 200 OK\r\ncontent-length: 4\r\ncontent-type: text/plain\r\n\r\nabcd",
+        );
+        accept_with_opts(testclient.clone(), ServerOpts::default(), |req, mut resp_wtr| async move {
+            assert_eq!(req.headers()[header::CONTENT_ENCODING], "zstd");
+            resp_wtr.set_text(req.into_body().into_string().await?);
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_decompress_request_too_large() {
+    // the limit applies to the decompressed body, not what was sent
+    smol::block_on(async {
+        let compressed = gzip(&vec![0; 1024 * 1024]).await;
+        assert!(compressed.len() < 4096);
+        let mut req = format!(
+            "POST / HTTP/1.1\r\nHost: example.org\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            compressed.len()
+        )
+        .into_bytes();
+        req.extend_from_slice(&compressed);
+
+        let opts = ServerOpts {
+            max_body_size: Some(4096),
+            ..decompress_opts()
+        };
+        let testclient = Client::from_bytes(
+            req,
+            "HTTP/1.1 413 Payload Too Large\r\ncontent-length: 0\r\n\r\n",
+        );
+        accept_with_opts(testclient.clone(), opts, |req, resp_wtr| async move {
+            match req.into_body().into_bytes().await {
+                Err(BodyError::TooLarge(4096)) => Err(glitch!(StatusCode::PAYLOAD_TOO_LARGE)),
+                _ => panic!("body should be over limit"),
+            }?;
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+        testclient.assert();
+    });
+}
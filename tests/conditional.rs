@@ -0,0 +1,181 @@
+mod mock;
+
+use http::{header, StatusCode};
+use tophat::server::{accept, conditional};
+
+use mock::Client;
+
+const LAST_MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+/// Respond with a body, an etag, and last-modified, to `method` with extra `headers`.
+fn assert_response(method: &str, headers: &str, expected: &str) {
+    smol::block_on(async {
+        let req = format!("{} /doc HTTP/1.1\r\nHost: example.org\r\n{}\r\n", method, headers);
+        let testclient = Client::new(&req, expected);
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.set_text("hello".to_owned());
+            resp_wtr.insert_header(header::ETAG, "\"v1\"".parse().unwrap());
+            resp_wtr.insert_header(header::LAST_MODIFIED, LAST_MODIFIED.parse().unwrap());
+            resp_wtr.insert_header(header::CACHE_CONTROL, "max-age=60".parse().unwrap());
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+const OK: &str = "HTTP/1.1 200 OK\r\ncontent-length: 5\r\ncontent-type: text/plain\r\netag: \"v1\"\r\nlast-modified: Sun, 06 Nov 1994 08:49:37 GMT\r\ncache-control: max-age=60\r\n\r\nhello";
+const NOT_MODIFIED: &str = "HTTP/1.1 304 Not Modified\r\ncache-control: max-age=60\r\netag: \"v1\"\r\n\r\n";
+const PRECONDITION_FAILED: &str = "HTTP/1.1 412 Precondition Failed\r\ncontent-length: 0\r\ncache-control: max-age=60\r\netag: \"v1\"\r\nlast-modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n";
+
+#[test]
+fn test_if_none_match() {
+    assert_response("GET", "If-None-Match: \"v1\"\r\n", NOT_MODIFIED);
+    assert_response("HEAD", "If-None-Match: \"v0\", W/\"v1\"\r\n", NOT_MODIFIED);
+    assert_response("GET", "If-None-Match: *\r\n", NOT_MODIFIED);
+    assert_response("GET", "If-None-Match: \"v0\"\r\n", OK);
+}
+
+#[test]
+fn test_if_modified_since() {
+    assert_response("GET", &format!("If-Modified-Since: {}\r\n", LAST_MODIFIED), NOT_MODIFIED);
+    assert_response("GET", "If-Modified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n", OK);
+    assert_response("GET", "If-Modified-Since: not a date\r\n", OK);
+    // ignored when there's an If-None-Match
+    assert_response(
+        "GET",
+        &format!("If-None-Match: \"v0\"\r\nIf-Modified-Since: {}\r\n", LAST_MODIFIED),
+        OK,
+    );
+}
+
+#[test]
+fn test_if_match() {
+    assert_response("GET", "If-Match: \"v1\"\r\n", OK);
+    assert_response("GET", "If-Match: \"v0\"\r\n", PRECONDITION_FAILED);
+    assert_response("GET", "If-Match: W/\"v1\"\r\n", PRECONDITION_FAILED);
+    assert_response(
+        "GET",
+        "If-Unmodified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n",
+        PRECONDITION_FAILED,
+    );
+    assert_response("GET", &format!("If-Unmodified-Since: {}\r\n", LAST_MODIFIED), OK);
+}
+
+#[test]
+fn test_unsafe_method_not_checked_after() {
+    // the response describes the state after the change, so it's not checked
+    assert_response("PUT", "If-Match: \"v0\"\r\n", OK);
+    assert_response("POST", "If-None-Match: \"v1\"\r\n", OK);
+}
+
+/// PUT `headers`, to a resource whose etag is `current` ("v1", or `None` if it doesn't exist yet)
+/// before the change and "v2" after.
+fn put(current: Option<&'static str>, headers: &str) -> String {
+    smol::block_on(async {
+        let req = format!("PUT /doc HTTP/1.1\r\nHost: example.org\r\n{}\r\n", headers);
+        let testclient = Client::new_with_writes(&req, "", 1);
+        accept(testclient.clone(), move |req, mut resp_wtr| async move {
+            let etag = current.map(|etag| etag.parse().unwrap());
+            if let Some(status) = conditional::check(&req, etag.is_some(), etag.as_ref(), None) {
+                resp_wtr.set_status(status);
+                return resp_wtr.send().await;
+            }
+
+            resp_wtr.set_status(StatusCode::NO_CONTENT);
+            resp_wtr.insert_header(header::ETAG, "\"v2\"".parse().unwrap());
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        String::from_utf8(testclient.response()).unwrap()
+    })
+}
+
+#[test]
+fn test_check_before_put() {
+    let resp = put(Some("\"v1\""), "If-Match: \"v1\"\r\n");
+    assert!(resp.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(resp.contains("etag: \"v2\"\r\n"));
+
+    let resp = put(Some("\"v1\""), "If-Match: \"v0\"\r\n");
+    assert!(resp.starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+    let resp = put(Some("\"v1\""), "If-None-Match: *\r\n");
+    assert!(resp.starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+
+    let resp = put(Some("\"v1\""), "");
+    assert!(resp.starts_with("HTTP/1.1 204 No Content\r\n"));
+}
+
+#[test]
+fn test_check_before_put_missing() {
+    // create only if it doesn't exist yet
+    let resp = put(None, "If-None-Match: *\r\n");
+    assert!(resp.starts_with("HTTP/1.1 204 No Content\r\n"));
+
+    // update only if it exists
+    let resp = put(None, "If-Match: *\r\n");
+    assert!(resp.starts_with("HTTP/1.1 412 Precondition Failed\r\n"));
+    let resp = put(Some("\"v1\""), "If-Match: *\r\n");
+    assert!(resp.starts_with("HTTP/1.1 204 No Content\r\n"));
+}
+
+#[test]
+fn test_if_unmodified_since_without_last_modified() {
+    // nothing to compare the date to, so it's ignored
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /doc HTTP/1.1\r\nHost: example.org\r\nIf-Unmodified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\ncontent-type: text/plain\r\netag: \"v1\"\r\n\r\nhello",
+        );
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.set_text("hello".to_owned());
+            resp_wtr.insert_header(header::ETAG, "\"v1\"".parse().unwrap());
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_conditional_without_validators() {
+    // nothing to compare to, so the response is sent as is
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /doc HTTP/1.1\r\nHost: example.org\r\nIf-None-Match: *\r\nIf-Match: \"v0\"\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 5\r\ncontent-type: text/plain\r\n\r\nhello",
+        );
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.set_text("hello".to_owned());
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_set_body_with_etag() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /doc HTTP/1.1\r\nHost: example.org\r\nIf-None-Match: \"5-a430d84680aabd0b\"\r\n\r\n",
+            "HTTP/1.1 304 Not Modified\r\netag: \"5-a430d84680aabd0b\"\r\n\r\n",
+        );
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.set_body_with_etag(b"hello".to_vec());
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
@@ -0,0 +1,81 @@
+mod mock;
+
+use serde::Deserialize;
+use tophat::server::{accept, form::FormRequestExt};
+
+use mock::Client;
+
+#[derive(Deserialize)]
+struct Login {
+    user: String,
+    remember: Option<bool>,
+    scope: Vec<String>,
+}
+
+/// Send `req`, and return the response. The endpoint deserializes a `Login` from the query string
+/// for a GET, or from the body otherwise, and responds with it.
+fn respond(req: &str) -> String {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(req, "", 100);
+        accept(testclient.clone(), |req, mut resp_wtr| async move {
+            let login: Login = if req.method() == http::Method::GET {
+                req.query()?
+            } else {
+                req.into_body().into_form_with_limit(64).await?
+            };
+
+            let body = format!("{} {:?} {}", login.user, login.remember, login.scope.join(","));
+            resp_wtr.set_body(body.into());
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        String::from_utf8(testclient.response()).unwrap()
+    })
+}
+
+fn post(body: &str) -> String {
+    respond(&format!(
+        "POST /login HTTP/1.1\r\nHost: example.org\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    ))
+}
+
+fn body(resp: &str) -> &str {
+    &resp[resp.find("\r\n\r\n").unwrap() + 4..]
+}
+
+#[test]
+fn test_query() {
+    let resp = respond("GET /login?user=j%C3%BCrgen&scope=read&scope=write HTTP/1.1\r\nHost: example.org\r\n\r\n");
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert_eq!(body(&resp), "jürgen None read,write");
+
+    let resp = respond("GET /login?remember=maybe&user=a HTTP/1.1\r\nHost: example.org\r\n\r\n");
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert_eq!(
+        body(&resp),
+        "Invalid form field `remember`: provided string was not `true` or `false`"
+    );
+
+    // no query string at all
+    let resp = respond("GET /login HTTP/1.1\r\nHost: example.org\r\n\r\n");
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert_eq!(body(&resp), "Invalid form field `user`: missing");
+}
+
+#[test]
+fn test_form() {
+    let resp = post("user=a+b&remember=true&scope=read");
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert_eq!(body(&resp), "a b Some(true) read");
+
+    let resp = post("user=a&scope=%ZZ");
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert_eq!(body(&resp), "Invalid form field `scope`: invalid percent-encoding");
+
+    let resp = post(&format!("user={}", "a".repeat(100)));
+    assert!(resp.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+}
@@ -0,0 +1,253 @@
+mod mock;
+
+use futures_lite::StreamExt;
+use std::io;
+use std::path::{Path, PathBuf};
+use tophat::server::{
+    accept,
+    fs::{BoxFuture, DirEntry, FileSystem, Metadata, ServeDir},
+};
+
+use mock::Client;
+
+struct SmolFs;
+
+impl FileSystem for SmolFs {
+    type File = smol::fs::File;
+
+    fn open<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Self::File>> {
+        Box::pin(smol::fs::File::open(path))
+    }
+
+    fn metadata<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Metadata>> {
+        Box::pin(async move { smol::fs::metadata(path).await.map(Metadata::from) })
+    }
+
+    fn read_dir<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, io::Result<Vec<DirEntry>>> {
+        Box::pin(async move {
+            let mut entries = Vec::new();
+            let mut dir = smol::fs::read_dir(path).await?;
+            while let Some(entry) = dir.try_next().await? {
+                entries.push(DirEntry {
+                    name: entry.file_name().to_string_lossy().into_owned(),
+                    is_dir: entry.file_type().await?.is_dir(),
+                });
+            }
+            Ok(entries)
+        })
+    }
+}
+
+/// A fresh directory for each test:
+///
+/// ```text
+/// hello.txt
+/// site/index.html
+/// docs/a&b.md
+/// docs/sub/
+/// ```
+fn setup(test: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("tophat-fs-{}-{}", std::process::id(), test));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(root.join("site")).unwrap();
+    std::fs::create_dir_all(root.join("docs/sub")).unwrap();
+    std::fs::write(root.join("hello.txt"), "Hello, world!").unwrap();
+    std::fs::write(root.join("site/index.html"), "<h1>Hi</h1>").unwrap();
+    std::fs::write(root.join("docs/a&b.md"), "# a and b").unwrap();
+    root
+}
+
+/// Serve `path` (from `uri`) and return the response, lowercased
+fn serve(serve_dir: &ServeDir<SmolFs>, method: &str, uri: &str, path: &str) -> String {
+    serve_with_headers(serve_dir, method, uri, path, "")
+}
+
+fn serve_with_headers(
+    serve_dir: &ServeDir<SmolFs>,
+    method: &str,
+    uri: &str,
+    path: &str,
+    headers: &str,
+) -> String {
+    smol::block_on(async {
+        let req = format!("{} {} HTTP/1.1\r\nHost: example.org\r\n{}\r\n", method, uri, headers);
+        let testclient = Client::new_with_writes(&req, "", 100);
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            serve_dir.serve(path, &req, resp_wtr).await
+        })
+        .await
+        .unwrap();
+
+        String::from_utf8(testclient.response()).unwrap().to_lowercase()
+    })
+}
+
+#[test]
+fn test_serve_file() {
+    let root = setup("file");
+    let serve_dir = ServeDir::new(&root, SmolFs);
+
+    let modified = std::fs::metadata(root.join("hello.txt")).unwrap().modified().unwrap();
+    let modified = httpdate::fmt_http_date(modified);
+
+    let resp = serve(&serve_dir, "GET", "/hello.txt", "hello.txt");
+    assert!(resp.starts_with("http/1.1 200 ok\r\n"));
+    assert!(resp.contains("content-length: 13\r\n"));
+    assert!(resp.contains("content-type: text/plain; charset=utf-8\r\n"));
+    assert!(resp.contains("accept-ranges: bytes\r\n"));
+    assert!(resp.contains(&format!("last-modified: {}\r\n", modified.to_lowercase())));
+    assert!(resp.ends_with("\r\n\r\nhello, world!"));
+
+    // head has the same headers, without the body
+    let resp = serve(&serve_dir, "HEAD", "/hello.txt", "hello.txt");
+    assert!(resp.contains("content-length: 13\r\n"));
+    assert!(resp.ends_with("\r\n\r\n"));
+
+    let resp = serve(&serve_dir, "GET", "/docs/a%26b.md", "docs/a%26b.md");
+    assert!(resp.contains("content-type: text/markdown; charset=utf-8\r\n"));
+    assert!(resp.ends_with("\r\n\r\n# a and b"));
+
+    let range = "Range: bytes=7-\r\n";
+    let resp = serve_with_headers(&serve_dir, "GET", "/hello.txt", "hello.txt", range);
+    assert!(resp.starts_with("http/1.1 206 partial content\r\n"));
+    assert!(resp.contains("content-range: bytes 7-12/13\r\n"));
+    assert!(resp.ends_with("\r\n\r\nworld!"));
+
+    let if_modified_since = format!("If-Modified-Since: {}\r\n", modified);
+    let resp = serve_with_headers(&serve_dir, "GET", "/hello.txt", "hello.txt", &if_modified_since);
+    assert!(resp.starts_with("http/1.1 304 not modified\r\n"));
+    assert!(resp.contains("etag: \""));
+    assert!(resp.ends_with("\r\n\r\n"));
+
+    let resp = serve(&serve_dir, "POST", "/hello.txt", "hello.txt");
+    assert!(resp.starts_with("http/1.1 405 method not allowed\r\n"));
+    assert!(resp.contains("allow: get, head\r\n"));
+}
+
+#[test]
+fn test_serve_not_found() {
+    let root = setup("not-found");
+    // `secret.txt` is outside of the served root
+    std::fs::write(root.join("secret.txt"), "secret").unwrap();
+    let serve_dir = ServeDir::new(root.join("docs"), SmolFs);
+
+    for path in &[
+        "missing.txt",
+        "../secret.txt",
+        "sub/../../secret.txt",
+        "%2e%2e/secret.txt",
+        "..%2fsecret.txt",
+        "a&b.md/more",
+    ] {
+        let resp = serve(&serve_dir, "GET", "/whatever", path);
+        assert!(resp.starts_with("http/1.1 404 not found\r\n"), "{}: {}", path, resp);
+    }
+}
+
+#[test]
+fn test_serve_directory() {
+    let root = setup("directory");
+    let serve_dir = ServeDir::new(&root, SmolFs);
+
+    // redirect to add the trailing slash, keeping the query
+    let resp = serve(&serve_dir, "GET", "/static/site?v=1", "site");
+    assert!(resp.starts_with("http/1.1 301 moved permanently\r\n"));
+    assert!(resp.contains("location: ./site/?v=1\r\n"));
+
+    // never to another host
+    let resp = serve(&serve_dir, "GET", "//evil.example/site", "site");
+    assert!(resp.starts_with("http/1.1 301 moved permanently\r\n"));
+    assert!(resp.contains("location: ./site/\r\n"));
+
+    let resp = serve(&serve_dir, "GET", "/static/site/", "site/");
+    assert!(resp.contains("content-type: text/html; charset=utf-8\r\n"));
+    assert!(resp.ends_with("\r\n\r\n<h1>hi</h1>"));
+
+    // no index file, and no listing
+    let resp = serve(&serve_dir, "GET", "/static/docs/", "docs/");
+    assert!(resp.starts_with("http/1.1 404 not found\r\n"));
+
+    let serve_dir = ServeDir::new(&root, SmolFs).index_file(None);
+    let resp = serve(&serve_dir, "GET", "/static/site/", "site/");
+    assert!(resp.starts_with("http/1.1 404 not found\r\n"));
+}
+
+#[test]
+fn test_serve_directory_listing() {
+    let root = setup("listing");
+    let serve_dir = ServeDir::new(&root, SmolFs).list_directories(true);
+
+    let resp = serve(&serve_dir, "GET", "/static/docs/", "docs/");
+    assert!(resp.starts_with("http/1.1 200 ok\r\n"));
+    assert!(resp.contains("content-type: text/html; charset=utf-8\r\n"));
+    assert!(resp.contains("<title>index of /static/docs/</title>"));
+    // parent, then directories first; names are escaped
+    let parent = resp.find("<li><a href=\"../\">../</a></li>").unwrap();
+    let sub = resp.find("<li><a href=\"sub/\">sub/</a></li>").unwrap();
+    let file = resp.find("<li><a href=\"a%26b.md\">a&amp;b.md</a></li>").unwrap();
+    assert!(parent < sub && sub < file);
+
+    // no parent link at the root
+    let resp = serve(&serve_dir, "GET", "/static/", "");
+    assert!(!resp.contains("href=\"../\""));
+    assert!(resp.contains("<li><a href=\"hello.txt\">hello.txt</a></li>"));
+
+    // index file still comes first
+    let resp = serve(&serve_dir, "GET", "/static/site/", "site/");
+    assert!(resp.ends_with("\r\n\r\n<h1>hi</h1>"));
+}
+
+#[test]
+fn test_serve_hidden() {
+    let root = setup("hidden");
+    std::fs::create_dir_all(root.join(".git")).unwrap();
+    std::fs::write(root.join(".git/config"), "[core]").unwrap();
+    std::fs::write(root.join(".env"), "SECRET=1").unwrap();
+
+    // not found, and not listed
+    let serve_dir = ServeDir::new(&root, SmolFs).list_directories(true);
+    for path in &[".env", ".git/config", "%2egit/config", ".git/", "docs/../.env"] {
+        let resp = serve(&serve_dir, "GET", "/whatever", path);
+        assert!(resp.starts_with("http/1.1 404 not found\r\n"), "{}: {}", path, resp);
+    }
+    let resp = serve(&serve_dir, "GET", "/static/", "");
+    assert!(resp.contains("hello.txt"));
+    assert!(!resp.contains(".env") && !resp.contains(".git"));
+
+    let serve_dir = ServeDir::new(&root, SmolFs).list_directories(true).serve_hidden(true);
+    let resp = serve(&serve_dir, "GET", "/.env", ".env");
+    assert!(resp.ends_with("\r\n\r\nsecret=1"));
+    let resp = serve(&serve_dir, "GET", "/static/", "");
+    assert!(resp.contains("<li><a href=\".git/\">.git/</a></li>"));
+    assert!(resp.contains("<li><a href=\".env\">.env</a></li>"));
+}
+
+#[cfg(feature = "router")]
+#[test]
+fn test_serve_dir_router() {
+    use http::Method;
+    use tophat::server::router::Router;
+
+    let root = setup("router");
+
+    smol::block_on(async {
+        let router = Router::build()
+            .at(Method::GET, "/static/*file", ServeDir::new(&root, SmolFs).param("file"))
+            .finish();
+
+        let testclient = Client::new_with_writes(
+            "GET /static/docs/a%26b.md HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "",
+            100,
+        );
+        accept(testclient.clone(), |req, resp_wtr| async {
+            router.route(req, resp_wtr).await
+        })
+        .await
+        .unwrap();
+
+        let resp = String::from_utf8(testclient.response()).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.ends_with("\r\n\r\n# a and b"));
+    });
+}
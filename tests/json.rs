@@ -0,0 +1,207 @@
+mod mock;
+
+use futures_lite::{AsyncRead, AsyncWrite, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tophat::server::{accept, json};
+
+use mock::Client;
+
+#[derive(Deserialize, Serialize)]
+struct Todo {
+    title: String,
+    done: bool,
+}
+
+fn post(content_type: &str, body: &str) -> String {
+    smol::block_on(async {
+        let req = format!(
+            "POST /todos HTTP/1.1\r\nHost: example.org\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        );
+        let testclient = Client::new_with_writes(&req, "", 100);
+        accept(testclient.clone(), |req, mut resp_wtr| async move {
+            let mut todo: Todo = json::from_request_with_limit(req, 64).await?;
+            todo.done = true;
+            resp_wtr.set_json(&todo)?;
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        String::from_utf8(testclient.response()).unwrap()
+    })
+}
+
+fn body(resp: &str) -> &str {
+    &resp[resp.find("\r\n\r\n").unwrap() + 4..]
+}
+
+#[test]
+fn test_json() {
+    let resp = post("application/json", r#"{"title": "buy a hat", "done": false}"#);
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.contains("content-type: application/json\r\n"));
+    assert!(resp.contains("content-length: 33\r\n"));
+    assert_eq!(body(&resp), r#"{"title":"buy a hat","done":true}"#);
+}
+
+#[test]
+fn test_json_errors() {
+    let resp = post("text/plain", r#"{"title": "buy a hat", "done": false}"#);
+    assert!(resp.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+
+    let resp = post("application/json", "{\n  \"title\": \"buy a hat\",\n  \"done\": nope\n}");
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert_eq!(body(&resp), "Invalid JSON: expected ident at line 3 column 12");
+
+    let resp = post("application/json", r#"{"title": "buy a hat"}"#);
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert_eq!(body(&resp), "Invalid JSON: missing field `done` at line 1 column 22");
+
+    let resp = post("application/json", &format!(r#"{{"title": "{}"}}"#, "a".repeat(64)));
+    assert!(resp.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+}
+
+#[test]
+fn test_set_json_error() {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes("GET / HTTP/1.1\r\nHost: example.org\r\n\r\n", "", 100);
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            // json object keys must be strings
+            let mut map = HashMap::new();
+            map.insert(vec![1], 1);
+            resp_wtr.set_json(&map)?;
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        let resp = String::from_utf8(testclient.response()).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    });
+}
+
+#[test]
+fn test_json_stream() {
+    smol::block_on(async {
+        let ndjson = "{\"title\": \"a\", \"done\": true}\n{\"title\": \"b\", \"done\": false}\n";
+        let req = format!(
+            "POST /todos HTTP/1.1\r\nHost: example.org\r\nContent-Type: application/x-ndjson\r\nContent-Length: {}\r\n\r\n{}",
+            ndjson.len(),
+            ndjson
+        );
+        let testclient = Client::new_with_writes(&req, "", 100);
+        accept(testclient.clone(), |req, mut resp_wtr| async move {
+            let mut todos = Box::pin(req.into_body().into_json_stream::<Todo>());
+            let mut titles = Vec::new();
+            while let Some(todo) = todos.next().await {
+                titles.push(todo?.title);
+            }
+            resp_wtr.set_text(titles.join(","));
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        let resp = String::from_utf8(testclient.response()).unwrap();
+        assert_eq!(body(&resp), "a,b");
+    });
+}
+
+/// Yields each item only after a `Pending`, like rows arriving from a database.
+struct Slow {
+    items: std::vec::IntoIter<Todo>,
+    ready: bool,
+}
+
+impl Stream for Slow {
+    type Item = Todo;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Todo>> {
+        if !self.ready {
+            self.ready = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.ready = false;
+        Poll::Ready(self.items.next())
+    }
+}
+
+/// Records the response written so far at each flush.
+#[derive(Clone)]
+struct Flushes {
+    client: Client,
+    flushed: Arc<Mutex<Vec<String>>>,
+}
+
+impl AsyncRead for Flushes {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.client).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Flushes {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.client).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let resp = String::from_utf8(self.client.response()).unwrap();
+        self.flushed.lock().unwrap().push(resp);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[test]
+fn test_set_ndjson() {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes("GET /todos HTTP/1.1\r\nHost: example.org\r\n\r\n", "", 100);
+        let flushes = Flushes {
+            client: testclient.clone(),
+            flushed: Arc::new(Mutex::new(Vec::new())),
+        };
+        accept(flushes.clone(), |_req, mut resp_wtr| async move {
+            let todos = vec![
+                Todo { title: "a".into(), done: true },
+                Todo { title: "b".into(), done: false },
+            ];
+            resp_wtr.set_ndjson(Slow { items: todos.into_iter(), ready: false });
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        let resp = String::from_utf8(testclient.response()).unwrap();
+        assert!(resp.contains("content-type: application/x-ndjson\r\n"));
+        assert!(resp.contains("transfer-encoding: chunked\r\n"));
+        assert!(resp.ends_with(
+            "\r\n\r\n1A\r\n{\"title\":\"a\",\"done\":true}\n\r\n\
+            1B\r\n{\"title\":\"b\",\"done\":false}\n\r\n0\r\n\r\n"
+        ));
+
+        // flushed after each line, as the next one isn't ready yet
+        let flushed = flushes.flushed.lock().unwrap();
+        assert!(flushed.iter().any(|resp| resp.ends_with("true}\n\r\n")));
+        assert!(flushed.iter().any(|resp| resp.ends_with("false}\n\r\n")));
+    });
+}
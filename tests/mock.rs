@@ -11,9 +11,9 @@ use std::task::{Context, Poll};
 
 #[derive(Clone)]
 pub struct Client {
-    // bool is true if read/written, fallse if not yet read/written
+    // usize is the position read up to, or the number of writes
     // TODO make rdr and wtr structs so this is easier to understand.
-    read_buf: Arc<Mutex<(Vec<u8>, bool)>>,
+    read_buf: Arc<Mutex<(Vec<u8>, usize)>>,
     write_buf: Arc<Mutex<(Vec<u8>, usize)>>,
     expected: Vec<u8>,
     // sometimes writer needs to write more than once, like for chunks
@@ -23,7 +23,7 @@ pub struct Client {
 impl Client {
     pub fn new(req: &str, expected_resp: &str) -> Self {
         Self {
-            read_buf: Arc::new(Mutex::new((req.to_owned().into_bytes(), false))),
+            read_buf: Arc::new(Mutex::new((req.to_owned().into_bytes(), 0))),
             write_buf: Arc::new(Mutex::new((Vec::new(), 0))),
             expected: expected_resp.to_owned().into_bytes(),
             num_writes: 1,
@@ -32,13 +32,23 @@ impl Client {
 
     pub fn new_with_writes(req: &str, expected_resp: &str, writes: usize) -> Self {
         Self {
-            read_buf: Arc::new(Mutex::new((req.to_owned().into_bytes(), false))),
+            read_buf: Arc::new(Mutex::new((req.to_owned().into_bytes(), 0))),
             write_buf: Arc::new(Mutex::new((Vec::new(), 0))),
             expected: expected_resp.to_owned().into_bytes(),
             num_writes: writes,
         }
     }
 
+    /// For requests which aren't utf-8.
+    pub fn from_bytes(req: Vec<u8>, expected_resp: &str) -> Self {
+        Self {
+            read_buf: Arc::new(Mutex::new((req, 0))),
+            write_buf: Arc::new(Mutex::new((Vec::new(), 0))),
+            expected: expected_resp.to_owned().into_bytes(),
+            num_writes: 1,
+        }
+    }
+
     pub fn assert(self) {
         let write_buf = self.write_buf.lock().unwrap();
         let resp = remove_date(&write_buf.0);
@@ -48,6 +58,11 @@ impl Client {
         );
     }
 
+    /// The raw response written so far, for responses which aren't utf-8.
+    pub fn response(&self) -> Vec<u8> {
+        self.write_buf.lock().unwrap().0.clone()
+    }
+
     pub fn assert_with_resp_date(self, date: &str) {
         let write_buf = self.write_buf.lock().unwrap();
 
@@ -68,14 +83,12 @@ impl AsyncRead for Client {
         _cx: &mut Context,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
+        // reads continue from the last position, so a large request can take several reads
         let mut rdr = self.read_buf.lock().unwrap();
-        if !rdr.1 {
-            rdr.1 = true;
-            io::Read::read(&mut io::Cursor::new(&*rdr.0), buf).unwrap();
-            Poll::Ready(Ok(rdr.0.len()))
-        } else {
-            Poll::Ready(Ok(0))
-        }
+        let (req, pos) = &mut *rdr;
+        let n = io::Read::read(&mut &req[*pos..], buf).unwrap();
+        *pos += n;
+        Poll::Ready(Ok(n))
     }
 }
 
@@ -85,7 +98,7 @@ impl AsyncWrite for Client {
         if wtr.1 < self.num_writes {
             wtr.1 += 1;
             wtr.0.extend_from_slice(buf);
-            Poll::Ready(Ok(wtr.0.len()))
+            Poll::Ready(Ok(buf.len()))
         } else {
             Poll::Ready(Ok(0))
         }
@@ -197,16 +210,13 @@ impl AsyncWrite for Server {
 // just strip date from response
 fn remove_date(b: &[u8]) -> Vec<u8> {
     // just change to str and back is easier for now
-    let s = std::str::from_utf8(b).unwrap();
-    if let Some(i) = s.find("date: ") {
+    // there's a date for each response, when there's more than one
+    let mut s = std::str::from_utf8(b).unwrap().to_owned();
+    while let Some(i) = s.find("date: ") {
         let eol = s[i + 6..].find("\r\n").expect("missing date eol");
-        let mut res = Vec::new();
-        res.extend_from_slice(&b[..i]);
-        res.extend_from_slice(&b[i + 6 + eol + 2..]);
-        res
-    } else {
-        b.to_vec()
+        s.replace_range(i..i + 6 + eol + 2, "");
     }
+    s.into_bytes()
 }
 
 pub(crate) struct Cursor<T> {
@@ -0,0 +1,144 @@
+mod mock;
+
+use futures_lite::io::{copy, sink};
+use tophat::server::{
+    accept,
+    multipart::{Multipart, MultipartConfig, MultipartError},
+};
+
+use mock::Client;
+
+const BODY: &str = "--boundary\r\n\
+    Content-Disposition: form-data; name=\"title\"\r\n\
+    \r\n\
+    Holiday\r\n\
+    --boundary\r\n\
+    Content-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+    Content-Type: image/jpeg\r\n\
+    \r\n\
+    not really a jpeg\r\n\
+    --boundary--\r\n";
+
+/// Post a multipart `body`, and return the response. The endpoint responds with a line for each
+/// part: its name, filename and length.
+fn post(content_type: &str, body: &str, config: MultipartConfig) -> String {
+    smol::block_on(async {
+        let req = format!(
+            "POST /upload HTTP/1.1\r\nHost: example.org\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        );
+        let testclient = Client::new_with_writes(&req, "", 100);
+        accept(testclient.clone(), |req, mut resp_wtr| {
+            let config = config.clone();
+            async move {
+                let mut multipart = Multipart::from_request_with_config(req, config)
+                    .map_err(MultipartError::into_glitch)?;
+
+                let mut summary = String::new();
+                while let Some(part) = multipart.next_part().await {
+                    let mut part = part.map_err(MultipartError::into_glitch)?;
+                    let len = copy(&mut part, &mut sink())
+                        .await
+                        .map_err(|err| MultipartError::from(err).into_glitch())?;
+                    summary.push_str(&format!(
+                        "{} {} {}\n",
+                        part.name().unwrap_or("-"),
+                        part.filename().unwrap_or("-"),
+                        len
+                    ));
+                }
+
+                resp_wtr.set_body(summary.into());
+                resp_wtr.send().await
+            }
+        })
+        .await
+        .unwrap();
+
+        String::from_utf8(testclient.response()).unwrap()
+    })
+}
+
+fn body(resp: &str) -> &str {
+    &resp[resp.find("\r\n\r\n").unwrap() + 4..]
+}
+
+#[test]
+fn test_multipart() {
+    let content_type = "multipart/form-data; boundary=boundary";
+    let resp = post(content_type, BODY, MultipartConfig::default());
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert_eq!(body(&resp), "title - 7\nphoto beach.jpg 17\n");
+
+    // an empty part, with no headers
+    let empty = "--boundary\r\n\r\n\r\n--boundary--";
+    let resp = post(content_type, empty, MultipartConfig::default());
+    assert_eq!(body(&resp), "- - 0\n");
+}
+
+#[test]
+fn test_multipart_content_type() {
+    let resp = post("text/plain", BODY, MultipartConfig::default());
+    assert!(resp.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+
+    let resp = post("multipart/form-data", BODY, MultipartConfig::default());
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert_eq!(body(&resp), "Missing or invalid multipart boundary");
+}
+
+#[test]
+fn test_multipart_malformed() {
+    let content_type = "multipart/form-data; boundary=boundary";
+
+    // no closing boundary
+    let truncated = &BODY[..BODY.len() - 16];
+    let resp = post(content_type, truncated, MultipartConfig::default());
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert_eq!(body(&resp), "Multipart body ended before the closing boundary");
+
+    // no boundary at all
+    let resp = post(content_type, "just some text", MultipartConfig::default());
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    let bad_headers = "--boundary\r\nno colon here\r\n\r\nvalue\r\n--boundary--\r\n";
+    let resp = post(content_type, bad_headers, MultipartConfig::default());
+    assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert_eq!(body(&resp), "Malformed multipart body: invalid part headers");
+}
+
+#[test]
+fn test_multipart_limits() {
+    let content_type = "multipart/form-data; boundary=boundary";
+
+    let config = MultipartConfig {
+        max_part_size: Some(10),
+        ..MultipartConfig::default()
+    };
+    let resp = post(content_type, BODY, config);
+    assert!(resp.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    assert_eq!(body(&resp), "Multipart part larger than limit of 10 bytes");
+
+    let config = MultipartConfig {
+        max_total_size: Some(100),
+        ..MultipartConfig::default()
+    };
+    let resp = post(content_type, BODY, config);
+    assert!(resp.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+
+    let config = MultipartConfig {
+        max_parts: 1,
+        ..MultipartConfig::default()
+    };
+    let resp = post(content_type, BODY, config);
+    assert!(resp.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+    assert_eq!(body(&resp), "More than 1 multipart parts");
+
+    let config = MultipartConfig {
+        max_headers_size: 32,
+        ..MultipartConfig::default()
+    };
+    let resp = post(content_type, BODY, config);
+    assert!(resp.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+}
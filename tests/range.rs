@@ -0,0 +1,127 @@
+mod mock;
+
+use futures_lite::io::Cursor;
+use http::header;
+use tophat::server::accept;
+
+use mock::Client;
+
+const CONTENT: &str = "0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// Respond to a request with extra `headers` using `set_ranged_body`, and return the response.
+fn respond(method: &str, headers: &str) -> String {
+    smol::block_on(async {
+        let req = format!("{} /file HTTP/1.1\r\nHost: example.org\r\n{}\r\n", method, headers);
+        let testclient = Client::new_with_writes(&req, "", 100);
+        accept(testclient.clone(), |req, mut resp_wtr| async move {
+            resp_wtr.insert_header(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+            resp_wtr.insert_header(header::ETAG, "\"v1\"".parse().unwrap());
+            resp_wtr.insert_header(
+                header::LAST_MODIFIED,
+                "Sun, 06 Nov 1994 08:49:37 GMT".parse().unwrap(),
+            );
+            resp_wtr.set_ranged_body(&req, Cursor::new(CONTENT), CONTENT.len() as u64);
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        String::from_utf8(testclient.response()).unwrap()
+    })
+}
+
+fn body(resp: &str) -> &str {
+    &resp[resp.find("\r\n\r\n").unwrap() + 4..]
+}
+
+#[test]
+fn test_range_none() {
+    let resp = respond("GET", "");
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.contains("accept-ranges: bytes\r\n"));
+    assert!(resp.contains("content-length: 36\r\n"));
+    assert!(!resp.contains("content-range"));
+    assert_eq!(body(&resp), CONTENT);
+
+    // not a range which can be parsed, so ignored
+    for range in &["Range: bytes=5-2\r\n", "Range: items=0-1\r\n", "Range: bytes=x\r\n"] {
+        let resp = respond("GET", range);
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", range);
+        assert_eq!(body(&resp), CONTENT);
+    }
+
+    // only GET
+    let resp = respond("HEAD", "Range: bytes=0-4\r\n");
+    assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(resp.contains("content-length: 36\r\n"));
+}
+
+#[test]
+fn test_range_single() {
+    let resp = respond("GET", "Range: bytes=10-15\r\n");
+    assert!(resp.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert!(resp.contains("content-range: bytes 10-15/36\r\n"));
+    assert!(resp.contains("content-length: 6\r\n"));
+    assert!(resp.contains("content-type: text/plain\r\n"));
+    assert_eq!(body(&resp), "abcdef");
+
+    let resp = respond("GET", "Range: bytes=-3\r\n");
+    assert!(resp.contains("content-range: bytes 33-35/36\r\n"));
+    assert_eq!(body(&resp), "xyz");
+
+    // past the end is clamped
+    let resp = respond("GET", "Range: bytes=30-100\r\n");
+    assert!(resp.contains("content-range: bytes 30-35/36\r\n"));
+    assert_eq!(body(&resp), "uvwxyz");
+
+    // overlapping ranges are merged into one
+    let resp = respond("GET", "Range: bytes=0-4,3-9\r\n");
+    assert!(resp.contains("content-range: bytes 0-9/36\r\n"));
+    assert_eq!(body(&resp), "0123456789");
+}
+
+#[test]
+fn test_range_multiple() {
+    let resp = respond("GET", "Range: bytes=30-,0-1\r\n");
+    assert!(resp.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert!(!resp.contains("content-range"));
+
+    let content_type = "content-type: multipart/byteranges; boundary=";
+    let boundary = &resp[resp.find(content_type).unwrap() + content_type.len()..];
+    let boundary = &boundary[..boundary.find("\r\n").unwrap()];
+
+    let expected = format!(
+        "\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/36\r\n\r\n01\
+        \r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 30-35/36\r\n\r\nuvwxyz\
+        \r\n--{0}--\r\n",
+        boundary
+    );
+    assert_eq!(body(&resp), expected);
+    assert!(resp.contains(&format!("content-length: {}\r\n", expected.len())));
+}
+
+#[test]
+fn test_range_not_satisfiable() {
+    let resp = respond("GET", "Range: bytes=36-\r\n");
+    assert!(resp.starts_with("HTTP/1.1 416 Range Not Satisfiable\r\n"));
+    assert!(resp.contains("content-range: bytes */36\r\n"));
+    assert!(resp.contains("content-length: 0\r\n"));
+    assert_eq!(body(&resp), "");
+}
+
+#[test]
+fn test_range_if_range() {
+    let resp = respond("GET", "Range: bytes=0-1\r\nIf-Range: \"v1\"\r\n");
+    assert!(resp.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    assert_eq!(body(&resp), "01");
+
+    let resp = respond("GET", "Range: bytes=0-1\r\nIf-Range: Sun, 06 Nov 1994 08:49:37 GMT\r\n");
+    assert!(resp.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+
+    // changed since, so the whole body
+    for if_range in &["\"v0\"", "W/\"v1\"", "Sun, 06 Nov 1994 08:49:36 GMT"] {
+        let resp = respond("GET", &format!("Range: bytes=0-1\r\nIf-Range: {}\r\n", if_range));
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", if_range);
+        assert_eq!(body(&resp), CONTENT);
+    }
+}
@@ -1,17 +1,23 @@
 mod chunked_text_big;
 mod mock;
 
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
 use http::{
     header::{self, HeaderName, HeaderValue},
     method::Method,
     Uri, Version,
 };
-use tophat::{server::accept, Body};
+use tophat::{
+    server::{accept, accept_with_opts, glitch::Glitch, ServerError, ServerOpts, Shutdown},
+    trailers::{ReceivedTrailers, Trailers},
+    Body,
+};
 
 use mock::{Cursor, Client};
 
 const RESP_200: &str = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n";
-const RESP_400: &str = "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n";
+// Decode failures close the connection, as the rest of the request is unread.
+const RESP_400: &str = "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
 
 #[test]
 fn test_request_empty_body() {
@@ -179,11 +185,30 @@ fn test_request_version() {
         testclient.assert();
     });
 
-    // version 1.0 not supported
+    // version 1.0 is answered with 1.0
     smol::block_on(async {
         let testclient = Client::new(
             "GET /foo/bar HTTP/1.0\r\nHost: example.org\r\nContent-Length: 0\r\n\r\n",
-            "HTTP/1.1 505 HTTP Version Not Supported\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.0 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            assert_eq!(req.version(), Version::HTTP_10);
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_http10_no_host() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar HTTP/1.0\r\n\r\n",
+            "HTTP/1.0 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
         );
 
         accept(testclient.clone(), |_req, resp_wtr| async move {
@@ -196,13 +221,142 @@ fn test_request_version() {
     });
 }
 
+#[test]
+fn test_http10_keep_alive() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n",
+            "HTTP/1.0 200 OK\r\ncontent-length: 0\r\nconnection: keep-alive\r\n\r\n",
+        );
+
+        accept(testclient.clone(), |_req, resp_wtr| async move {
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_http10_stream_body_close_delimited() {
+    // no chunked encoding for 1.0, the body is ended by closing the connection, even when the
+    // client asked for keep-alive.
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /foo/bar HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+            "HTTP/1.0 200 OK\r\nconnection: close\r\ncontent-type: application/octet-stream\r\n\r\nHello tophat!",
+            2,
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            let body_str = Cursor::new("Hello tophat!");
+            resp_wtr.set_body(Body::from_reader(body_str, None));
+
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_head_fixed_body() {
+    // same headers as GET, including content-length, but no body
+    smol::block_on(async {
+        let testclient = Client::new(
+            "HEAD /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 12\r\ncontent-type: text/plain\r\n\r\n",
+        );
+
+        accept(testclient.clone(), |req, mut resp_wtr| async move {
+            assert_eq!(req.method(), Method::HEAD);
+            resp_wtr.set_text("Hello tophat".to_string());
+
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_head_stream_body() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "HEAD /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ncontent-type: application/octet-stream\r\n\r\n",
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            let body_str = Cursor::new("Hello tophat!");
+            resp_wtr.set_body(Body::from_reader(body_str, None));
+
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_no_content_no_framing() {
+    // a body set on a 204 is not sent
+    smol::block_on(async {
+        let testclient = Client::new(
+            "DELETE /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 204 No Content\r\n\r\n",
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.set_body(Body::from_reader(Cursor::new("Hello tophat!"), None));
+            resp_wtr.set_code(204);
+
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_not_modified_no_framing() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 304 Not Modified\r\netag: \"tophat\"\r\n\r\n",
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.set_body(Body::from("Hello tophat".to_string()));
+            resp_wtr.insert_header(header::ETAG, "\"tophat\"".parse().unwrap());
+            resp_wtr.set_code(304);
+
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
 // sends message _ands_ closes connection
 #[test]
 fn test_transfer_encoding_unsupported() {
     smol::block_on(async {
         let testclient = Client::new(
-            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 0\r\nTransfer-Encoding: gzip\r\n\r\n",
-            "HTTP/1.1 501 Not Implemented\r\ncontent-length: 0\r\n\r\n",
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: foo, chunked\r\n\r\n",
+            "HTTP/1.1 501 Not Implemented\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
         );
 
         let res = accept(testclient.clone(), |_req, resp_wtr| async move {
@@ -223,16 +377,35 @@ fn test_transfer_encoding_unsupported() {
 }
 
 #[test]
-// TODO handle transfer-encoding chunked and content-length clash
-#[ignore] // temporary
-fn test_transfer_encoding_content_length() {
+fn test_connection_close_from_client() {
     smol::block_on(async {
         let testclient = Client::new(
-            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 0\r\nTransfer-Encoding: chunked\r\n\r\n",
-            RESP_400,
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\nConnection: Close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
         );
 
         accept(testclient.clone(), |_req, resp_wtr| async move {
+            let done = resp_wtr.send().await?;
+            Ok(done)
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_connection_close_from_handler() {
+    // header is not doubled, even though the server also adds it when closing
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.insert_header(header::CONNECTION, "close".parse().unwrap());
             resp_wtr.send().await
         })
         .await
@@ -288,7 +461,7 @@ fn test_response_date() {
     // also make sure that the date header was passed through
     smol::block_on(async {
         let testclient = Client::new(
-            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 0\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
             RESP_200,
         );
 
@@ -402,6 +575,155 @@ fn test_decode_transfer_encoding_chunked() {
     });
 }
 
+const REQ_CHUNKED_TRAILER: &str = "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: chunked\r\n\r\n\
+    7\r\n\
+    Mozilla\r\n\
+    9\r\n\
+    Developer\r\n\
+    0\r\n\
+    Expires: Wed, 21 Oct 2015 07:28:00 GMT\r\n\
+    \r\n";
+
+#[test]
+fn test_request_trailers_after_reading() {
+    smol::block_on(async {
+        let testclient = Client::new(REQ_CHUNKED_TRAILER, RESP_200);
+
+        accept(testclient.clone(), |mut req, resp_wtr| async move {
+            let mut body = String::new();
+            req.body_mut().read_to_string(&mut body).await.unwrap();
+            assert_eq!(body, "MozillaDeveloper");
+
+            match req.body_mut().trailers().await.unwrap() {
+                ReceivedTrailers::Trailers(trailers) => {
+                    assert_eq!(trailers.get("expires").unwrap(), "Wed, 21 Oct 2015 07:28:00 GMT");
+                }
+                other => panic!("expected trailers, got {:?}", other),
+            }
+
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_request_trailers_body_unread() {
+    // the rest of the body is skipped to get to the trailers
+    smol::block_on(async {
+        let testclient = Client::new(REQ_CHUNKED_TRAILER, RESP_200);
+
+        accept(testclient.clone(), |mut req, resp_wtr| async move {
+            let trailers = req.body_mut().trailers().await.unwrap();
+            assert!(matches!(trailers, ReceivedTrailers::Trailers(_)));
+
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_request_trailers_none_sent() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: chunked\r\n\r\n\
+                7\r\n\
+                Mozilla\r\n\
+                0\r\n\
+                \r\n",
+            RESP_200,
+        );
+
+        accept(testclient.clone(), |mut req, resp_wtr| async move {
+            let mut body = String::new();
+            req.body_mut().read_to_string(&mut body).await.unwrap();
+            assert_eq!(body, "Mozilla");
+            assert!(matches!(
+                req.body_mut().trailers().await.unwrap(),
+                ReceivedTrailers::NoneSent
+            ));
+
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_request_trailers_not_chunked() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 7\r\n\r\nMozilla",
+            RESP_200,
+        );
+
+        accept(testclient.clone(), |mut req, resp_wtr| async move {
+            assert!(matches!(
+                req.body_mut().trailers().await.unwrap(),
+                ReceivedTrailers::NotChunked
+            ));
+            // and the buffering version doesn't wait for trailers either
+            let (body, trailers) = req.into_body().into_string_with_trailer().await.unwrap();
+            assert_eq!(body, "Mozilla");
+            assert!(trailers.is_none());
+
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[cfg(feature = "router")]
+#[test]
+fn test_request_trailers_router() {
+    use tophat::server::{glitch::Result, router::Router, ResponseWriter, ResponseWritten};
+
+    async fn upload<W>(
+        mut req: tophat::Request,
+        resp_wtr: ResponseWriter<W>,
+    ) -> Result<ResponseWritten>
+    where
+        W: futures_lite::AsyncRead + futures_lite::AsyncWrite + Clone + Send + Sync + Unpin,
+        W: 'static,
+    {
+        let mut body = String::new();
+        req.body_mut().read_to_string(&mut body).await?;
+        assert_eq!(body, "MozillaDeveloper");
+        assert!(matches!(
+            req.body_mut().trailers().await?,
+            ReceivedTrailers::Trailers(_)
+        ));
+
+        resp_wtr.send().await
+    }
+
+    smol::block_on(async {
+        let router = Router::build().at(Method::GET, "/foo/bar", upload).finish();
+        let testclient = Client::new(REQ_CHUNKED_TRAILER, RESP_200);
+
+        accept(testclient.clone(), |req, resp_wtr| async {
+            router.route(req, resp_wtr).await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
 #[test]
 fn test_encode_transfer_encoding_chunked() {
     smol::block_on(async {
@@ -447,3 +769,837 @@ fn test_encode_transfer_encoding_chunked_big() {
         testclient.assert();
     });
 }
+
+#[test]
+fn test_request_many_headers() {
+    // more than the old fixed limit of 16 headers
+    let mut req = "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n".to_owned();
+    for i in 0..20 {
+        req.push_str(&format!("x-header-{}: {}\r\n", i, i));
+    }
+    req.push_str("\r\n");
+
+    smol::block_on(async {
+        let testclient = Client::new(&req, RESP_200);
+
+        accept(testclient.clone(), |_req, resp_wtr| async move {
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+
+    smol::block_on(async {
+        let testclient = Client::new(
+            &req,
+            "HTTP/1.1 431 Request Header Fields Too Large\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+        let opts = ServerOpts {
+            max_headers: 16,
+            ..ServerOpts::default()
+        };
+
+        let res = accept_with_opts(testclient.clone(), opts, |_req, resp_wtr| async move {
+            resp_wtr.send().await
+        })
+        .await;
+
+        assert!(matches!(res, Err(ServerError::ConnectionClosedHeadTooLarge)));
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_request_head_too_large() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\nx-big: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n\r\n",
+            "HTTP/1.1 431 Request Header Fields Too Large\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+        let opts = ServerOpts {
+            max_head_size: 64,
+            ..ServerOpts::default()
+        };
+
+        let res = accept_with_opts(testclient.clone(), opts, |_req, resp_wtr| async move {
+            resp_wtr.send().await
+        })
+        .await;
+
+        assert!(matches!(res, Err(ServerError::ConnectionClosedHeadTooLarge)));
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_request_uri_too_long() {
+    // request line over the head size limit
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/barrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrrr HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 414 URI Too Long\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+        let opts = ServerOpts {
+            max_head_size: 64,
+            ..ServerOpts::default()
+        };
+
+        let res = accept_with_opts(testclient.clone(), opts, |_req, resp_wtr| async move {
+            resp_wtr.send().await
+        })
+        .await;
+
+        assert!(matches!(res, Err(ServerError::ConnectionClosedHeadTooLarge)));
+        testclient.assert();
+    });
+
+    // request-target over the uri limit
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar/baz HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 414 URI Too Long\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+        let opts = ServerOpts {
+            max_uri_length: 8,
+            ..ServerOpts::default()
+        };
+
+        let res = accept_with_opts(testclient.clone(), opts, |_req, resp_wtr| async move {
+            resp_wtr.send().await
+        })
+        .await;
+
+        assert!(matches!(res, Err(ServerError::ConnectionClosedHeadTooLarge)));
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_100_continue_after_validation() {
+    // A request which is rejected gets no `100 Continue`, so the client doesn't send its body.
+    smol::block_on(async {
+        let testclient = Client::new(
+            "POST /foo/bar/baz HTTP/1.1\r\nHost: example.org\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\n",
+            "HTTP/1.1 414 URI Too Long\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+        let opts = ServerOpts {
+            max_uri_length: 8,
+            ..ServerOpts::default()
+        };
+
+        let res = accept_with_opts(testclient.clone(), opts, |_req, resp_wtr| async move {
+            resp_wtr.send().await
+        })
+        .await;
+
+        assert!(matches!(res, Err(ServerError::ConnectionClosedHeadTooLarge)));
+        testclient.assert();
+    });
+
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello",
+            &format!("HTTP/1.1 100 Continue\r\n\r\n{}", RESP_200),
+            2,
+        );
+
+        accept(testclient.clone(), |_req, resp_wtr| async move {
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_100_continue_not_for_http10() {
+    // an HTTP/1.0 client doesn't know 1xx responses, and sends the body anyway
+    smol::block_on(async {
+        let testclient = Client::new(
+            "POST /foo/bar HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello",
+            "HTTP/1.0 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            let body = req.into_body().into_string().await.unwrap();
+            assert_eq!(body, "hello");
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_request_content_length_too_large() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 20\r\n\r\naaaaaaaaaaaaaaaaaaaa",
+            "HTTP/1.1 413 Payload Too Large\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+        let opts = ServerOpts {
+            max_body_size: Some(10),
+            ..ServerOpts::default()
+        };
+
+        let res = accept_with_opts(testclient.clone(), opts, |_req, _resp_wtr| async move {
+            panic!("handler should not be called");
+        })
+        .await;
+
+        assert!(matches!(res, Err(ServerError::ConnectionClosedPayloadTooLarge)));
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_shutdown_during_request() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+        let shutdown = Shutdown::new();
+        let opts = ServerOpts {
+            shutdown: Some(shutdown.clone()),
+            ..ServerOpts::default()
+        };
+
+        accept_with_opts(testclient.clone(), opts, |_req, resp_wtr| {
+            let shutdown = shutdown.clone();
+            async move {
+                assert_eq!(shutdown.connection_count(), 1);
+                shutdown.signal();
+                resp_wtr.send().await
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(shutdown.connection_count(), 0);
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_shutdown_idle_connection() {
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    smol::block_on(async {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        let shutdown = Shutdown::new();
+
+        let server = smol::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let opts = ServerOpts {
+                    shutdown: Some(shutdown),
+                    ..ServerOpts::default()
+                };
+                accept_with_opts(async_dup::Arc::new(stream), opts, |_req, resp_wtr| async move {
+                    resp_wtr.send().await
+                })
+                .await
+            }
+        });
+
+        let mut client = Async::<TcpStream>::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = vec![0; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        assert!(buf[..n].starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        // the connection is kept alive, waiting for the next request
+        assert_eq!(shutdown.connection_count(), 1);
+
+        shutdown.signal();
+        assert!(shutdown.drain(Duration::from_secs(5)).await);
+        server.await.unwrap();
+
+        // server closed the connection
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    });
+}
+
+#[test]
+fn test_idle_timeout() {
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    smol::block_on(async {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+
+        let server = smol::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let opts = ServerOpts {
+                idle_timeout: Some(Duration::from_millis(50)),
+                ..ServerOpts::default()
+            };
+            accept_with_opts(async_dup::Arc::new(stream), opts, |_req, resp_wtr| async move {
+                resp_wtr.send().await
+            })
+            .await
+        });
+
+        // connect, but never send a request
+        let _client = Async::<TcpStream>::connect(addr).await.unwrap();
+        server.await.unwrap();
+    });
+}
+
+#[test]
+fn test_header_read_timeout() {
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    smol::block_on(async {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+
+        let server = smol::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let opts = ServerOpts {
+                header_read_timeout: Some(Duration::from_millis(50)),
+                ..ServerOpts::default()
+            };
+            accept_with_opts(async_dup::Arc::new(stream), opts, |_req, resp_wtr| async move {
+                resp_wtr.send().await
+            })
+            .await
+        });
+
+        // the head never finishes
+        let mut client = Async::<TcpStream>::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /foo/bar HTTP/1.1\r\nHost: exa")
+            .await
+            .unwrap();
+
+        let mut resp = Vec::new();
+        client.read_to_end(&mut resp).await.unwrap();
+        let resp = String::from_utf8(resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(resp.contains("connection: close\r\n"));
+
+        server.await.unwrap();
+    });
+}
+
+#[test]
+fn test_body_read_timeout() {
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    smol::block_on(async {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+
+        let server = smol::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let opts = ServerOpts {
+                body_read_timeout: Some(Duration::from_millis(50)),
+                ..ServerOpts::default()
+            };
+            accept_with_opts(async_dup::Arc::new(stream), opts, |req, mut resp_wtr| async move {
+                let err = req.into_body().into_bytes().await.unwrap_err();
+                assert!(err.to_string().contains("timeout"));
+
+                resp_wtr.set_code(408);
+                resp_wtr.insert_header(tophat::http::header::CONNECTION, "close".parse().unwrap());
+                resp_wtr.send().await
+            })
+            .await
+        });
+
+        // only half of the body is sent
+        let mut client = Async::<TcpStream>::connect(addr).await.unwrap();
+        client
+            .write_all(b"POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 10\r\n\r\nhello")
+            .await
+            .unwrap();
+
+        let mut resp = Vec::new();
+        client.read_to_end(&mut resp).await.unwrap();
+        assert!(resp.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+
+        server.await.unwrap();
+    });
+}
+
+// Pipelined requests all arrive in one read, each response echoes the request path.
+async fn echo_path<W>(
+    req: tophat::Request,
+    mut resp_wtr: tophat::server::ResponseWriter<W>,
+) -> tophat::server::glitch::Result<tophat::server::ResponseWritten>
+where
+    W: futures_lite::AsyncWrite + Clone + Send + Sync + Unpin + 'static,
+{
+    resp_wtr.set_text(req.uri().path().to_string());
+    resp_wtr.send().await
+}
+
+const RESP_ONE: &str = "HTTP/1.1 200 OK\r\ncontent-length: 4\r\ncontent-type: text/plain\r\n\r\n/one";
+const RESP_TWO: &str = "HTTP/1.1 200 OK\r\ncontent-length: 4\r\ncontent-type: text/plain\r\n\r\n/two";
+
+#[test]
+fn test_pipelined_requests() {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /one HTTP/1.1\r\nHost: example.org\r\n\r\nGET /two HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            &[RESP_ONE, RESP_TWO].concat(),
+            2,
+        );
+
+        accept(testclient.clone(), echo_path).await.unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_pipelined_fixed_body_read() {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "POST /one HTTP/1.1\r\nHost: example.org\r\nContent-Length: 6\r\n\r\ntophat\
+            GET /two HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            &[RESP_ONE, RESP_TWO].concat(),
+            2,
+        );
+
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            let (parts, body) = req.into_parts();
+            if parts.uri.path() == "/one" {
+                assert_eq!(body.into_string().await.unwrap(), "tophat");
+            }
+            echo_path(tophat::Request::from_parts(parts, Body::empty()), resp_wtr).await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_pipelined_fixed_body_unread() {
+    // the unread body is drained, and not parsed as the next request
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "POST /one HTTP/1.1\r\nHost: example.org\r\nContent-Length: 6\r\n\r\ntophat\
+            GET /two HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            &[RESP_ONE, RESP_TWO].concat(),
+            2,
+        );
+
+        accept(testclient.clone(), echo_path).await.unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_pipelined_chunked_body() {
+    let req = "POST /one HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: chunked\r\n\r\n\
+        7\r\n\
+        Mozilla\r\n\
+        9\r\n\
+        Developer\r\n\
+        0\r\n\
+        Expires: Wed, 21 Oct 2015 07:28:00 GMT\r\n\
+        \r\n\
+        GET /two HTTP/1.1\r\nHost: example.org\r\n\r\n";
+
+    // read
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(req, &[RESP_ONE, RESP_TWO].concat(), 2);
+
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            let (parts, body) = req.into_parts();
+            if parts.uri.path() == "/one" {
+                assert_eq!(body.into_string().await.unwrap(), "MozillaDeveloper");
+            }
+            echo_path(tophat::Request::from_parts(parts, Body::empty()), resp_wtr).await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+
+    // unread
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(req, &[RESP_ONE, RESP_TWO].concat(), 2);
+
+        accept(testclient.clone(), echo_path).await.unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_unread_body_too_large_to_drain() {
+    // only the first request is answered, and the close is announced
+    let req = format!(
+        "POST /one HTTP/1.1\r\nHost: example.org\r\nContent-Length: {len}\r\n\r\n{body}\
+        GET /two HTTP/1.1\r\nHost: example.org\r\n\r\n",
+        len = 1024 * 1024,
+        body = "a".repeat(1024 * 1024),
+    );
+    let resp = "HTTP/1.1 200 OK\r\ncontent-length: 4\r\nconnection: close\r\ncontent-type: text/plain\r\n\r\n/one";
+
+    // still held by the handler
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(&req, resp, 2);
+
+        accept(testclient.clone(), echo_path).await.unwrap();
+
+        testclient.assert();
+    });
+
+    // partly read, and dropped
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(&req, resp, 2);
+
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            let (parts, mut body) = req.into_parts();
+            let mut buf = [0; 1024];
+            futures_lite::AsyncReadExt::read_exact(&mut body, &mut buf).await.unwrap();
+            drop(body);
+            echo_path(tophat::Request::from_parts(parts, Body::empty()), resp_wtr).await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+
+    // chunked, and dropped
+    let req = format!(
+        "POST /one HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: chunked\r\n\r\n{len:X}\r\n{body}\r\n0\r\n\r\n\
+        GET /two HTTP/1.1\r\nHost: example.org\r\n\r\n",
+        len = 1024 * 1024,
+        body = "a".repeat(1024 * 1024),
+    );
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(&req, resp, 2);
+
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            let (parts, _) = req.into_parts();
+            echo_path(tophat::Request::from_parts(parts, Body::empty()), resp_wtr).await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_upgrade() {
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+    // bytes sent right after the upgrade request belong to the new protocol
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /echo HTTP/1.1\r\nHost: example.org\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\nhello",
+            "HTTP/1.1 101 Switching Protocols\r\nx-echo: yes\r\nupgrade: echo\r\nconnection: upgrade\r\n\r\nhello",
+            2,
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.insert_header(HeaderName::from_static("x-echo"), "yes".parse().unwrap());
+            let mut upgraded = resp_wtr.upgrade("echo").await?;
+
+            let mut buf = Vec::new();
+            upgraded.read_to_end(&mut buf).await?;
+            upgraded.write_all(&buf).await?;
+
+            Ok(upgraded.response_written())
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_upgrade_not_requested() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /echo HTTP/1.1\r\nHost: example.org\r\nUpgrade: echo\r\n\r\n",
+            "HTTP/1.1 400 Bad Request\r\ncontent-length: 33\r\ncontent-type: text/plain\r\n\r\nUpgrade to echo was not requested",
+        );
+
+        accept(testclient.clone(), |_req, resp_wtr| async move {
+            let upgraded = resp_wtr.upgrade("echo").await?;
+            Ok(upgraded.response_written())
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+const RESP_STREAM_HEAD: &str = "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ncontent-type: text/plain\r\n\r\n";
+
+// `date: <IMF-fixdate>\r\n`, which `assert` strips
+const DATE_LEN: u64 = 37;
+
+#[test]
+fn test_start_stream() {
+    smol::block_on(async {
+        let expected = format!(
+            "{}5\r\nHello\r\n8\r\n tophat!\r\n0\r\n\r\n",
+            RESP_STREAM_HEAD
+        );
+        let testclient = Client::new_with_writes(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            &expected,
+            10,
+        );
+
+        let (tx, rx) = async_channel::bounded(1);
+        accept(testclient.clone(), |_req, mut resp_wtr| {
+            let tx = tx.clone();
+            async move {
+                resp_wtr.insert_header(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+                let mut body_wtr = resp_wtr.start_stream().await?;
+                body_wtr.write_all(b"Hello").await?;
+                body_wtr.flush().await?;
+                body_wtr.write_all(b" tophat!").await?;
+                let done = body_wtr.finish().await?;
+                tx.send(done.bytes_written()).await.unwrap();
+                Ok(done)
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(rx.recv().await.unwrap(), expected.len() as u64 + DATE_LEN);
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_start_stream_trailers() {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ntrailer: x-checksum\r\ncontent-type: text/plain\r\n\r\n5\r\nHello\r\n0\r\nx-checksum: abc\r\n\r\n",
+            10,
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.insert_header(header::TRAILER, "x-checksum".parse().unwrap());
+            resp_wtr.insert_header(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+            let mut body_wtr = resp_wtr.start_stream().await?;
+            body_wtr.write_all(b"Hello").await?;
+
+            let mut trailers = Trailers::new();
+            trailers.insert("x-checksum", "abc".parse().unwrap());
+            body_wtr.finish_with_trailers(trailers).await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_start_stream_head() {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "HEAD /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            RESP_STREAM_HEAD,
+            10,
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.insert_header(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+            let mut body_wtr = resp_wtr.start_stream().await?;
+            body_wtr.write_all(b"Hello").await?;
+            body_wtr.finish().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_start_stream_http10() {
+    // no chunked encoding, the body ends when the connection closes
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /foo/bar HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /foo/bar HTTP/1.0\r\n\r\n",
+            "HTTP/1.0 200 OK\r\nconnection: close\r\ncontent-type: text/plain\r\n\r\nHello tophat!",
+            10,
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.insert_header(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+            let mut body_wtr = resp_wtr.start_stream().await?;
+            body_wtr.write_all(b"Hello").await?;
+            body_wtr.write_all(b" tophat!").await?;
+            body_wtr.finish().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_start_stream_unfinished() {
+    // After part of the response is sent, a glitch can't be sent. The connection is closed
+    // instead, so the pipelined request isn't answered either.
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\nGET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            &format!("{}5\r\nHello\r\n", RESP_STREAM_HEAD),
+            10,
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            resp_wtr.insert_header(header::CONTENT_TYPE, "text/plain".parse().unwrap());
+            let mut body_wtr = resp_wtr.start_stream().await?;
+            body_wtr.write_all(b"Hello").await?;
+            body_wtr.flush().await?;
+            Err(Glitch::bad_request())
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_response_trailers() {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ncontent-type: application/octet-stream\r\ntrailer: x-checksum\r\n\r\nD\r\nHello tophat!\r\n0\r\nx-checksum: abc\r\n\r\n",
+            10,
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            let mut body = Body::from_reader(Cursor::new("Hello tophat!"), None);
+            body.declare_trailers(vec![HeaderName::from_static("x-checksum")]);
+            let trailers_sender = body.send_trailers();
+            resp_wtr.set_body(body);
+
+            // the trailers are sent while the response is being written
+            let send_trailers = async move {
+                let mut trailers = Trailers::new();
+                trailers.insert("x-checksum", "abc".parse().unwrap());
+                // framing fields are never sent as trailers
+                trailers.insert(header::CONTENT_LENGTH, "13".parse().unwrap());
+                trailers_sender.send(trailers).await;
+            };
+            let (res, _) = futures_lite::future::zip(resp_wtr.send(), send_trailers).await;
+            res
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_response_trailers_sender_dropped() {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ncontent-type: application/octet-stream\r\n\r\nD\r\nHello tophat!\r\n0\r\n\r\n",
+            10,
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            let mut body = Body::from_reader(Cursor::new("Hello tophat!"), None);
+            drop(body.send_trailers());
+            resp_wtr.set_body(body);
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_response_trailers_timeout() {
+    // the sender is held, but never sends
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ncontent-type: application/octet-stream\r\n\r\nD\r\nHello tophat!\r\n0\r\n\r\n",
+            10,
+        );
+        let opts = ServerOpts {
+            trailers_timeout: Some(std::time::Duration::from_millis(20)),
+            ..ServerOpts::default()
+        };
+
+        accept_with_opts(testclient.clone(), opts, |_req, mut resp_wtr| async move {
+            let mut body = Body::from_reader(Cursor::new("Hello tophat!"), None);
+            let _trailers_sender = body.send_trailers();
+            resp_wtr.set_body(body);
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_response_trailers_fixed_body() {
+    // trailers are only sent with a chunked body
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /foo/bar HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 13\r\ncontent-type: application/octet-stream\r\n\r\nHello tophat!",
+        );
+
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            let mut body = Body::from_reader(Cursor::new("Hello tophat!"), Some(13));
+            body.declare_trailers(vec![HeaderName::from_static("x-checksum")]);
+            let _trailers_sender = body.send_trailers();
+            resp_wtr.set_body(body);
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
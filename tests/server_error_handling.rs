@@ -4,14 +4,18 @@ use tophat::{
     glitch, glitch_code,
     http::StatusCode,
     server::{
-        accept,
+        accept, accept_with_opts,
         glitch::{Glitch, GlitchExt},
+        ServerError, ServerOpts,
     },
+    BodyError,
 };
 
 use mock::Client;
 
 const RESP_400: &str = "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n";
+const RESP_400_CLOSE: &str =
+    "HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
 const RESP_500: &str = "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n";
 const S_400: StatusCode = StatusCode::BAD_REQUEST;
 const S_500: StatusCode = StatusCode::INTERNAL_SERVER_ERROR;
@@ -246,3 +250,261 @@ fn test_request_glitch_code_macro_panic() {
         testclient.assert();
     });
 }
+
+#[test]
+fn test_request_chunked_body_too_large() {
+    let req = "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: chunked\r\n\r\n\
+        7\r\n\
+        Mozilla\r\n\
+        9\r\n\
+        Developer\r\n\
+        0\r\n\
+        \r\n";
+    let opts = ServerOpts {
+        max_body_size: Some(10),
+        ..ServerOpts::default()
+    };
+
+    // the rest of the body can't be read past the limit, so the connection closes
+    smol::block_on(async {
+        let testclient = Client::new(
+            req,
+            "HTTP/1.1 413 Payload Too Large\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+        );
+
+        accept_with_opts(testclient.clone(), opts.clone(), |req, resp_wtr| async move {
+            match req.into_body().into_bytes().await {
+                Err(BodyError::TooLarge(10)) => Err(glitch!(StatusCode::PAYLOAD_TOO_LARGE)),
+                _ => panic!("body should be over limit"),
+            }?;
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+
+    // body exactly at the limit
+    let opts = ServerOpts {
+        max_body_size: Some(16),
+        ..opts
+    };
+
+    smol::block_on(async {
+        let testclient = Client::new(req, "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+
+        accept_with_opts(testclient.clone(), opts, |req, resp_wtr| async move {
+            let body = req.into_body().into_string().await.unwrap();
+            assert_eq!(body, "MozillaDeveloper");
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+// Request smuggling: the server and a proxy in front of it must never disagree about where a
+// request ends, so any ambiguous body length responds with 400 and closes the connection.
+fn assert_invalid_body_length(req: &str) {
+    smol::block_on(async {
+        let testclient = Client::new(req, RESP_400_CLOSE);
+
+        let res = accept(testclient.clone(), |_req, resp_wtr| async move {
+            resp_wtr.send().await
+        })
+        .await;
+
+        match res {
+            Err(ServerError::ConnectionClosedInvalidBodyLength) => (),
+            _ => panic!("expected ConnectionClosedInvalidBodyLength"),
+        }
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_smuggling_cl_te() {
+    // a front end using content-length would pass `SMUGGLED` on as the start of the next request
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 13\r\nTransfer-Encoding: chunked\r\n\r\n\
+        0\r\n\
+        \r\n\
+        SMUGGLED",
+    );
+}
+
+#[test]
+fn test_smuggling_te_cl() {
+    // a front end using transfer-encoding would pass the whole chunked body on
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n\
+        8\r\n\
+        SMUGGLED\r\n\
+        0\r\n\
+        \r\n",
+    );
+}
+
+#[test]
+fn test_smuggling_chunked_not_final() {
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: chunked, identity\r\n\r\n0\r\n\r\n",
+    );
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+    );
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: identity\r\n\r\n",
+    );
+}
+
+#[test]
+fn test_smuggling_transfer_encoding_http10() {
+    // a front end might not know transfer-encoding in HTTP/1.0, and use content-length
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+    );
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.0\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+    );
+}
+
+#[test]
+fn test_smuggling_content_length() {
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+    );
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 5, 6\r\n\r\nhello!",
+    );
+    assert_invalid_body_length(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: +5\r\n\r\nhello",
+    );
+}
+
+#[test]
+fn test_duplicate_content_length_same_value() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nContent-Length: 5\r\nContent-Length: 5, 5\r\n\r\nhello",
+            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n",
+        );
+
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            let body = req.into_body().into_string().await.unwrap();
+            assert_eq!(body, "hello");
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_transfer_encoding_not_decoded() {
+    // only chunked is decoded, so other codings would reach the handler still applied
+    for coding in &["gzip, chunked", "deflate, chunked", "x-gzip, chunked", "gzip"] {
+        let req = format!(
+            "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nTransfer-Encoding: {}\r\n\r\n0\r\n\r\n",
+            coding
+        );
+        smol::block_on(async {
+            let testclient = Client::new(
+                &req,
+                "HTTP/1.1 501 Not Implemented\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            );
+
+            let res = accept(testclient.clone(), |_req, resp_wtr| async move {
+                resp_wtr.send().await
+            })
+            .await;
+
+            match res {
+                Err(ServerError::ConnectionClosedUnsupportedTransferEncoding) => (),
+                _ => panic!("expected ConnectionClosedUnsupportedTransferEncoding"),
+            }
+
+            testclient.assert();
+        });
+    }
+}
+
+// A request which fails to decode after its head is read still has its body on the wire. The
+// connection must close, or the body would be parsed as the next request.
+fn assert_not_smuggled(head: &str, opts: ServerOpts, resp: &str) -> Result<(), ServerError> {
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: example.org\r\n\r\n";
+    let req = format!("{}Content-Length: {}\r\n\r\n{}", head, smuggled.len(), smuggled);
+
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(&req, resp, 2);
+
+        let res = accept_with_opts(testclient.clone(), opts, |req, resp_wtr| async move {
+            assert_ne!(req.uri(), "/smuggled", "smuggled request was handled");
+            resp_wtr.send().await
+        })
+        .await;
+
+        testclient.assert();
+        res
+    })
+}
+
+#[test]
+fn test_pipelined_too_many_headers() {
+    let opts = ServerOpts {
+        max_headers: 2,
+        ..ServerOpts::default()
+    };
+    let res = assert_not_smuggled(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nx-a: a\r\n",
+        opts,
+        "HTTP/1.1 431 Request Header Fields Too Large\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+    );
+    assert!(matches!(res, Err(ServerError::ConnectionClosedHeadTooLarge)));
+}
+
+#[test]
+fn test_pipelined_no_host() {
+    let res = assert_not_smuggled(
+        "POST /foo/bar HTTP/1.1\r\n",
+        ServerOpts::default(),
+        RESP_400_CLOSE,
+    );
+    assert!(res.is_ok());
+}
+
+#[test]
+fn test_pipelined_bad_header_name() {
+    let res = assert_not_smuggled(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nx\x01bad: a\r\n",
+        ServerOpts::default(),
+        RESP_400_CLOSE,
+    );
+    assert!(res.is_ok());
+}
+
+#[test]
+fn test_pipelined_bad_header_value() {
+    let res = assert_not_smuggled(
+        "POST /foo/bar HTTP/1.1\r\nHost: example.org\r\nx-bad: a\x7fb\r\n",
+        ServerOpts::default(),
+        RESP_400_CLOSE,
+    );
+    assert!(res.is_ok());
+}
+
+#[test]
+fn test_pipelined_bad_path() {
+    let res = assert_not_smuggled(
+        "POST http://[example.org/foo HTTP/1.1\r\nHost: example.org\r\n",
+        ServerOpts::default(),
+        RESP_400_CLOSE,
+    );
+    assert!(res.is_ok());
+}
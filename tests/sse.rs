@@ -0,0 +1,133 @@
+mod mock;
+
+use std::time::Duration;
+use tophat::server::{
+    accept,
+    broadcast::Hub,
+    sse::{self, Event, SseConfig},
+};
+
+use mock::Client;
+
+fn body(resp: &str) -> &str {
+    &resp[resp.find("\r\n\r\n").unwrap() + 4..]
+}
+
+#[test]
+fn test_sse() {
+    smol::block_on(async {
+        let testclient = Client::new_with_writes(
+            "GET /events HTTP/1.1\r\nHost: example.org\r\nLast-Event-ID: 41\r\n\r\n",
+            "",
+            100,
+        );
+        accept(testclient.clone(), |req, mut resp_wtr| async move {
+            let last: u64 = sse::last_event_id(&req).unwrap().parse().unwrap();
+
+            let (sender, events) = sse::channel();
+            smol::spawn(async move {
+                let event = Event::new("a\nb").id((last + 1).to_string()).event("update");
+                sender.send(event).await.unwrap();
+                sender.send(Event::new("c").retry(Duration::from_secs(5))).await.unwrap();
+            })
+            .detach();
+
+            resp_wtr.set_sse(events);
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        let resp = String::from_utf8(testclient.response()).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(resp.contains("content-type: text/event-stream\r\n"));
+        assert_eq!(
+            body(&resp),
+            "26\r\nevent: update\ndata: a\ndata: b\nid: 42\n\n\r\n\
+            15\r\ndata: c\nretry: 5000\n\n\r\n\
+            0\r\n\r\n"
+        );
+    });
+}
+
+#[test]
+fn test_sse_ping() {
+    smol::block_on(async {
+        let testclient =
+            Client::new_with_writes("GET /events HTTP/1.1\r\nHost: example.org\r\n\r\n", "", 100);
+        accept(testclient.clone(), |_req, mut resp_wtr| async move {
+            let config = SseConfig {
+                ping_interval: Some(Duration::from_millis(10)),
+                ..SseConfig::default()
+            };
+            let (sender, events) = sse::channel_with_config(config);
+            smol::spawn(async move {
+                smol::Timer::after(Duration::from_millis(35)).await;
+                sender.send(Event::new("done")).await.unwrap();
+            })
+            .detach();
+
+            resp_wtr.set_sse(events);
+            resp_wtr.send().await
+        })
+        .await
+        .unwrap();
+
+        let resp = String::from_utf8(testclient.response()).unwrap();
+        let body = body(&resp);
+        assert!(body.starts_with("8\r\n: ping\n\n\r\n"), "{:?}", body);
+        assert!(body.ends_with("C\r\ndata: done\n\n\r\n0\r\n\r\n"), "{:?}", body);
+    });
+}
+
+#[test]
+fn test_sse_disconnected() {
+    smol::block_on(async {
+        let (sender, events) = sse::channel();
+        assert!(!sender.is_disconnected());
+        drop(events);
+        assert!(sender.is_disconnected());
+        assert!(sender.send(Event::new("a")).await.is_err());
+    });
+}
+
+#[test]
+fn test_sse_hub() {
+    smol::block_on(async {
+        let hub = Hub::new();
+        for data in &["a", "b", "c"] {
+            hub.publish("updates", Event::new(*data)).await;
+        }
+
+        let testclient = Client::new_with_writes(
+            "GET /events HTTP/1.1\r\nHost: example.org\r\nLast-Event-ID: 1\r\n\r\n",
+            "",
+            100,
+        );
+        accept(testclient.clone(), |req, mut resp_wtr| {
+            resp_wtr.set_sse(sse::subscribe(&hub, "updates", &req));
+            assert_eq!(hub.subscriber_count("updates"), 1);
+
+            let hub = hub.clone();
+            smol::spawn(async move {
+                hub.publish("updates", Event::new("d")).await;
+                hub.remove_topic("updates");
+            })
+            .detach();
+
+            async move { resp_wtr.send().await }
+        })
+        .await
+        .unwrap();
+
+        let resp = String::from_utf8(testclient.response()).unwrap();
+        assert_eq!(
+            body(&resp),
+            "F\r\ndata: b\nid: 2\n\n\r\n\
+            F\r\ndata: c\nid: 3\n\n\r\n\
+            F\r\ndata: d\nid: 4\n\n\r\n\
+            0\r\n\r\n"
+        );
+        assert_eq!(hub.subscriber_count("updates"), 0);
+    });
+}
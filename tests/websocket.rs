@@ -0,0 +1,224 @@
+mod mock;
+
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use smol::Async;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use tophat::server::{
+    accept,
+    websocket::{self, CloseFrame, Message, WebSocketConfig},
+};
+
+use mock::Client;
+
+const HANDSHAKE: &str = "GET /chat HTTP/1.1\r\nHost: example.org\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+/// Echo server for one connection, which also reports every message it receives.
+fn echo_server(config: WebSocketConfig) -> (SocketAddr, async_channel::Receiver<Message>) {
+    let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+    let addr = listener.get_ref().local_addr().unwrap();
+    let (tx, rx) = async_channel::unbounded();
+
+    smol::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        accept(async_dup::Arc::new(stream), |req, resp_wtr| {
+            let config = config.clone();
+            let tx = tx.clone();
+            async move {
+                let mut ws = websocket::upgrade_with_config(&req, resp_wtr, config).await?;
+                while let Some(msg) = ws.recv().await {
+                    let msg = msg?;
+                    tx.send(msg.clone()).await.unwrap();
+                    match msg {
+                        Message::Text(_) | Message::Binary(_) => ws.send(msg).await?,
+                        _ => (),
+                    }
+                }
+                Ok(ws.response_written())
+            }
+        })
+        .await
+        .unwrap();
+    })
+    .detach();
+
+    (addr, rx)
+}
+
+async fn connect(addr: SocketAddr) -> Async<TcpStream> {
+    let mut client = Async::<TcpStream>::connect(addr).await.unwrap();
+    client.write_all(HANDSHAKE.as_bytes()).await.unwrap();
+
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        client.read_exact(&mut byte).await.unwrap();
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8(head).unwrap().to_lowercase();
+    assert!(head.starts_with("http/1.1 101 switching protocols\r\n"));
+    assert!(head.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo=\r\n"));
+    assert!(head.contains("upgrade: websocket\r\n"));
+
+    client
+}
+
+fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&MASK);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ MASK[i % 4]));
+    frame
+}
+
+/// Opcode and payload of a frame from the server.
+async fn server_frame(client: &mut Async<TcpStream>) -> (u8, Vec<u8>) {
+    let mut head = [0u8; 2];
+    client.read_exact(&mut head).await.unwrap();
+    assert_eq!(head[0] & 0x80, 0x80, "server frames are not fragmented");
+    assert_eq!(head[1] & 0x80, 0, "server frames are not masked");
+    let len = match head[1] {
+        126 => {
+            let mut len = [0u8; 2];
+            client.read_exact(&mut len).await.unwrap();
+            u16::from_be_bytes(len) as usize
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    client.read_exact(&mut payload).await.unwrap();
+    (head[0] & 0x0f, payload)
+}
+
+#[test]
+fn test_websocket_echo_and_close() {
+    smol::block_on(async {
+        let (addr, received) = echo_server(WebSocketConfig::default());
+        let mut client = connect(addr).await;
+
+        client.write_all(&client_frame(true, 0x1, b"Hello")).await.unwrap();
+        assert_eq!(server_frame(&mut client).await, (0x1, b"Hello".to_vec()));
+
+        client.write_all(&client_frame(true, 0x2, &[1; 300])).await.unwrap();
+        assert_eq!(server_frame(&mut client).await, (0x2, vec![1; 300]));
+
+        client.write_all(&client_frame(true, 0x8, b"\x03\xe8bye")).await.unwrap();
+        assert_eq!(server_frame(&mut client).await, (0x8, b"\x03\xe8".to_vec()));
+
+        assert_eq!(received.recv().await.unwrap(), Message::Text("Hello".into()));
+        assert_eq!(received.recv().await.unwrap(), Message::Binary(vec![1; 300]));
+        assert_eq!(
+            received.recv().await.unwrap(),
+            Message::Close(Some(CloseFrame {
+                code: 1000,
+                reason: "bye".into()
+            }))
+        );
+
+        // the server is done with the connection
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    });
+}
+
+#[test]
+fn test_websocket_fragmented_with_ping() {
+    smol::block_on(async {
+        let (addr, received) = echo_server(WebSocketConfig::default());
+        let mut client = connect(addr).await;
+
+        client.write_all(&client_frame(false, 0x1, b"Hel")).await.unwrap();
+        client.write_all(&client_frame(true, 0x9, b"ping")).await.unwrap();
+        client.write_all(&client_frame(true, 0x0, b"lo")).await.unwrap();
+
+        // the pong comes first, the message only once it's complete
+        assert_eq!(server_frame(&mut client).await, (0xa, b"ping".to_vec()));
+        assert_eq!(server_frame(&mut client).await, (0x1, b"Hello".to_vec()));
+
+        assert_eq!(received.recv().await.unwrap(), Message::Ping(b"ping".to_vec()));
+        assert_eq!(received.recv().await.unwrap(), Message::Text("Hello".into()));
+    });
+}
+
+#[test]
+fn test_websocket_unmasked_frame() {
+    smol::block_on(async {
+        let (addr, _received) = echo_server(WebSocketConfig::default());
+        let mut client = connect(addr).await;
+
+        client.write_all(b"\x81\x05Hello").await.unwrap();
+        assert_eq!(server_frame(&mut client).await, (0x8, 1002u16.to_be_bytes().to_vec()));
+    });
+}
+
+#[test]
+fn test_websocket_invalid_utf8() {
+    smol::block_on(async {
+        let (addr, _received) = echo_server(WebSocketConfig::default());
+        let mut client = connect(addr).await;
+
+        client.write_all(&client_frame(true, 0x1, &[0xff, 0xfe])).await.unwrap();
+        assert_eq!(server_frame(&mut client).await, (0x8, 1007u16.to_be_bytes().to_vec()));
+    });
+}
+
+#[test]
+fn test_websocket_max_message_size() {
+    smol::block_on(async {
+        let config = WebSocketConfig {
+            max_message_size: 4,
+        };
+        let (addr, _received) = echo_server(config);
+        let mut client = connect(addr).await;
+
+        // each fragment fits, but not the whole message
+        client.write_all(&client_frame(false, 0x2, b"123")).await.unwrap();
+        client.write_all(&client_frame(true, 0x0, b"45")).await.unwrap();
+        assert_eq!(server_frame(&mut client).await, (0x8, 1009u16.to_be_bytes().to_vec()));
+    });
+}
+
+#[test]
+fn test_websocket_handshake_missing_key() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /chat HTTP/1.1\r\nHost: example.org\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            "HTTP/1.1 400 Bad Request\r\ncontent-length: 36\r\ncontent-type: text/plain\r\n\r\nMissing or invalid Sec-WebSocket-Key",
+        );
+
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            let ws = websocket::upgrade(&req, resp_wtr).await?;
+            Ok(ws.response_written())
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}
+
+#[test]
+fn test_websocket_handshake_wrong_version() {
+    smol::block_on(async {
+        let testclient = Client::new(
+            "GET /chat HTTP/1.1\r\nHost: example.org\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n",
+            "HTTP/1.1 426 Upgrade Required\r\ncontent-length: 33\r\nsec-websocket-version: 13\r\ncontent-type: text/plain\r\n\r\nUnsupported Sec-WebSocket-Version",
+        );
+
+        accept(testclient.clone(), |req, resp_wtr| async move {
+            let ws = websocket::upgrade(&req, resp_wtr).await?;
+            Ok(ws.response_written())
+        })
+        .await
+        .unwrap();
+
+        testclient.assert();
+    });
+}